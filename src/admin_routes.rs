@@ -0,0 +1,256 @@
+// admin_routes.rs
+use crate::api_keys::{self, AuthenticatedKey, KeyLimits, KeyRegistry, RegistryError, Scope, StoredKey};
+use crate::audit_log::AuditLog;
+use crate::config::TenantQuotas;
+use crate::personas::PersonaRegistry;
+use crate::tenants::{Tenant, TenantRegistry};
+
+use actix_web::{web, HttpResponse, Responder};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::sync::Arc;
+
+#[derive(Deserialize)]
+struct CreateKeyRequest {
+    name: String,
+    scopes: Vec<Scope>,
+    expires_at: Option<u64>,
+    #[serde(default)]
+    limits: KeyLimits,
+    persona: Option<String>,
+    // Operators may issue keys for any tenant; everyone else issues for their own
+    tenant: Option<String>,
+}
+
+// A key as shown to administrators; the hash never leaves the server.
+#[derive(Serialize)]
+struct KeyView {
+    id: String,
+    name: String,
+    scopes: Vec<Scope>,
+    enabled: bool,
+    expires_at: Option<u64>,
+    created_at: u64,
+    last_used_at: Option<u64>,
+    limits: KeyLimits,
+    persona: Option<String>,
+    tenant: String,
+}
+
+impl From<StoredKey> for KeyView {
+    fn from(key: StoredKey) -> Self {
+        KeyView {
+            tenant: key.tenant().to_string(),
+            id: key.id,
+            name: key.name,
+            scopes: key.scopes,
+            enabled: key.enabled,
+            expires_at: key.expires_at,
+            created_at: key.created_at,
+            last_used_at: key.last_used_at,
+            limits: key.limits,
+            persona: key.persona,
+        }
+    }
+}
+
+// Returned on create and rotate only: the plaintext secret is not stored anywhere.
+#[derive(Serialize)]
+struct IssuedKey {
+    key: String,
+    #[serde(flatten)]
+    details: KeyView,
+}
+
+// Set Admin Routes (nested under /api)
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/admin/keys")
+            .route("", web::get().to(list_keys))
+            .route("", web::post().to(create_key))
+            .route("/{id}/rotate", web::post().to(rotate_key))
+            .route("/{id}/disable", web::post().to(disable_key))
+            .route("/{id}/enable", web::post().to(enable_key))
+            .route("/{id}", web::delete().to(delete_key))
+    );
+}
+
+fn forbidden_unless_admin(auth: &AuthenticatedKey) -> Option<HttpResponse> {
+    if auth.has_scope(Scope::Admin) {
+        None
+    } else {
+        info!("Key {} ({}) attempted an admin action without the admin scope", auth.id, auth.name);
+        Some(error_response(HttpResponse::Forbidden(), "insufficient_scope", "This API key is not allowed to manage keys".to_string()))
+    }
+}
+
+// Keys of other tenants are reported as missing unless the caller is an operator.
+fn check_tenant(id: &str, registry: &KeyRegistry, auth: &AuthenticatedKey) -> Option<HttpResponse> {
+    match registry.get(id) {
+        Some(key) if auth.is_operator() || key.tenant() == auth.tenant => None,
+        _ => Some(registry_error(RegistryError::NotFound)),
+    }
+}
+
+fn error_response(mut builder: actix_web::HttpResponseBuilder, code: &str, message: String) -> HttpResponse {
+    builder.json(json!({ "error": { "code": code, "message": message } }))
+}
+
+fn registry_error(e: RegistryError) -> HttpResponse {
+    match e {
+        RegistryError::NotFound => error_response(HttpResponse::NotFound(), "key_not_found", e.to_string()),
+        RegistryError::Immutable => error_response(HttpResponse::Conflict(), "key_immutable", e.to_string()),
+        RegistryError::Io(_) => {
+            error!("{}", e);
+            error_response(HttpResponse::InternalServerError(), "storage_failed", e.to_string())
+        }
+    }
+}
+
+async fn list_keys(registry: web::Data<Arc<KeyRegistry>>, auth: web::ReqData<AuthenticatedKey>) -> impl Responder {
+    if let Some(response) = forbidden_unless_admin(&auth) {
+        return response;
+    }
+    let keys: Vec<KeyView> = registry
+        .list()
+        .into_iter()
+        .filter(|key| auth.is_operator() || key.tenant() == auth.tenant)
+        .map(KeyView::from)
+        .collect();
+    HttpResponse::Ok().json(json!({ "keys": keys }))
+}
+
+async fn create_key(
+    body: web::Json<CreateKeyRequest>,
+    registry: web::Data<Arc<KeyRegistry>>,
+    personas: web::Data<PersonaRegistry>,
+    tenants: web::Data<Arc<TenantRegistry>>,
+    audit_log: web::Data<AuditLog>,
+    auth: web::ReqData<AuthenticatedKey>,
+    caller_tenant: web::ReqData<Arc<Tenant>>,
+) -> impl Responder {
+    if let Some(response) = forbidden_unless_admin(&auth) {
+        return response;
+    }
+    let body = body.into_inner();
+    if body.name.trim().is_empty() || body.scopes.is_empty() {
+        return error_response(HttpResponse::BadRequest(), "invalid_parameter", "name and at least one scope are required".to_string());
+    }
+    if body.expires_at.is_some_and(|expires_at| expires_at <= api_keys::now()) {
+        return error_response(HttpResponse::BadRequest(), "invalid_parameter", "expires_at must be in the future".to_string());
+    }
+    let tenant = match body.tenant.as_deref() {
+        Some(id) if id != caller_tenant.id => {
+            if !auth.is_operator() {
+                return error_response(HttpResponse::Forbidden(), "insufficient_scope", "Only operators can issue keys for other tenants".to_string());
+            }
+            match tenants.get(id) {
+                Some(tenant) => tenant,
+                None => return error_response(HttpResponse::BadRequest(), "unknown_tenant", format!("Unknown tenant '{}'", id)),
+            }
+        }
+        _ => caller_tenant.into_inner(),
+    };
+    if let Some(persona) = body
+        .persona
+        .as_deref()
+        .filter(|persona| personas.get(persona).is_none() || !tenant.allows_persona(persona))
+    {
+        return error_response(HttpResponse::BadRequest(), "unknown_persona", format!("Unknown persona '{}'", persona));
+    }
+    if !auth.is_operator() {
+        if let Some(limit) = above_quota(&body.limits, &tenant.quotas) {
+            return error_response(HttpResponse::Forbidden(), "limit_above_quota", format!("{} is above the tenant's quota; only operators can raise it", limit));
+        }
+    }
+
+    match registry.create(body.name, body.scopes, body.expires_at, body.limits, body.persona, &tenant.id) {
+        Ok((key, secret)) => {
+            audit_log.record(&auth.id, "key.create", &key.id, json!({ "name": key.name, "scopes": key.scopes, "expires_at": key.expires_at, "limits": key.limits, "persona": key.persona, "tenant": key.tenant() }));
+            HttpResponse::Created().json(IssuedKey { key: secret, details: key.into() })
+        }
+        Err(e) => registry_error(e),
+    }
+}
+
+// The first of the key's limits that is higher than what the tenant as a whole may use.
+fn above_quota(limits: &KeyLimits, quotas: &TenantQuotas) -> Option<&'static str> {
+    [
+        ("daily_tokens", limits.daily_tokens, quotas.daily_tokens),
+        ("monthly_tokens", limits.monthly_tokens, quotas.monthly_tokens),
+        ("daily_images", limits.daily_images, quotas.daily_images),
+        ("monthly_images", limits.monthly_images, quotas.monthly_images),
+    ]
+    .into_iter()
+    .find(|(_, limit, quota)| matches!((limit, quota), (Some(limit), Some(quota)) if limit > quota))
+    .map(|(name, _, _)| name)
+}
+
+async fn rotate_key(
+    path: web::Path<String>,
+    registry: web::Data<Arc<KeyRegistry>>,
+    audit_log: web::Data<AuditLog>,
+    auth: web::ReqData<AuthenticatedKey>,
+) -> impl Responder {
+    if let Some(response) = forbidden_unless_admin(&auth).or_else(|| check_tenant(&path, &registry, &auth)) {
+        return response;
+    }
+    match registry.rotate(&path) {
+        Ok((key, secret)) => {
+            audit_log.record(&auth.id, "key.rotate", &key.id, json!({}));
+            HttpResponse::Ok().json(IssuedKey { key: secret, details: key.into() })
+        }
+        Err(e) => registry_error(e),
+    }
+}
+
+async fn disable_key(
+    path: web::Path<String>,
+    registry: web::Data<Arc<KeyRegistry>>,
+    audit_log: web::Data<AuditLog>,
+    auth: web::ReqData<AuthenticatedKey>,
+) -> impl Responder {
+    set_enabled(&path, false, &registry, &audit_log, &auth)
+}
+
+async fn enable_key(
+    path: web::Path<String>,
+    registry: web::Data<Arc<KeyRegistry>>,
+    audit_log: web::Data<AuditLog>,
+    auth: web::ReqData<AuthenticatedKey>,
+) -> impl Responder {
+    set_enabled(&path, true, &registry, &audit_log, &auth)
+}
+
+fn set_enabled(id: &str, enabled: bool, registry: &KeyRegistry, audit_log: &AuditLog, auth: &AuthenticatedKey) -> HttpResponse {
+    if let Some(response) = forbidden_unless_admin(auth).or_else(|| check_tenant(id, registry, auth)) {
+        return response;
+    }
+    match registry.set_enabled(id, enabled) {
+        Ok(key) => {
+            let action = if enabled { "key.enable" } else { "key.disable" };
+            audit_log.record(&auth.id, action, &key.id, json!({}));
+            HttpResponse::Ok().json(KeyView::from(key))
+        }
+        Err(e) => registry_error(e),
+    }
+}
+
+async fn delete_key(
+    path: web::Path<String>,
+    registry: web::Data<Arc<KeyRegistry>>,
+    audit_log: web::Data<AuditLog>,
+    auth: web::ReqData<AuthenticatedKey>,
+) -> impl Responder {
+    if let Some(response) = forbidden_unless_admin(&auth).or_else(|| check_tenant(&path, &registry, &auth)) {
+        return response;
+    }
+    match registry.delete(&path) {
+        Ok(key) => {
+            audit_log.record(&auth.id, "key.delete", &key.id, json!({ "name": key.name }));
+            HttpResponse::NoContent().finish()
+        }
+        Err(e) => registry_error(e),
+    }
+}
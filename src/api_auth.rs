@@ -1,16 +1,39 @@
-// api_routes.rs
+// api_auth.rs
+use crate::api_keys::{AuthFailure, KeyRegistry};
+use crate::jwt_auth::JwtVerifier;
+use crate::rate_limit::{RateLimited, RateLimiter};
+use crate::tenants::TenantRegistry;
+
 use std::future::{ready, Ready};
+use std::sync::Arc;
 use actix_web::{
     dev::{Service, ServiceRequest, ServiceResponse, Transform},
-    Error, HttpResponse, http::StatusCode,
+    Error, HttpMessage, HttpResponse,
     body::{BoxBody, EitherBody},
+    http::header::{self, HeaderName, HeaderValue},
 };
 use futures::future::LocalBoxFuture;
+use log::info;
+use serde_json::json;
 use std::task::{Context, Poll};
-use std::env;
-use dotenv::dotenv;
 
-pub struct ApiKey;
+pub struct ApiKey {
+    registry: Arc<KeyRegistry>,
+    jwt: Option<Arc<JwtVerifier>>,
+    tenants: Arc<TenantRegistry>,
+    limiter: Arc<RateLimiter>,
+}
+
+impl ApiKey {
+    pub fn new(
+        registry: Arc<KeyRegistry>,
+        jwt: Option<Arc<JwtVerifier>>,
+        tenants: Arc<TenantRegistry>,
+        limiter: Arc<RateLimiter>,
+    ) -> Self {
+        ApiKey { registry, jwt, tenants, limiter }
+    }
+}
 
 impl<S, B> Transform<S, ServiceRequest> for ApiKey
 where
@@ -24,12 +47,22 @@ where
     type Future = Ready<Result<Self::Transform, Self::InitError>>;
 
     fn new_transform(&self, service: S) -> Self::Future {
-        ready(Ok(ApiKeyMiddleware { service }))
+        ready(Ok(ApiKeyMiddleware {
+            service,
+            registry: self.registry.clone(),
+            jwt: self.jwt.clone(),
+            tenants: self.tenants.clone(),
+            limiter: self.limiter.clone(),
+        }))
     }
 }
 
 pub struct ApiKeyMiddleware<S> {
     service: S,
+    registry: Arc<KeyRegistry>,
+    jwt: Option<Arc<JwtVerifier>>,
+    tenants: Arc<TenantRegistry>,
+    limiter: Arc<RateLimiter>,
 }
 
 impl<S, B> Service<ServiceRequest> for ApiKeyMiddleware<S>
@@ -46,37 +79,112 @@ where
     }
 
     fn call(&self, req: ServiceRequest) -> Self::Future {
-        dotenv().ok();
-        let api_key = env::var("API_KEY").expect("API_KEY not set");
-
         let bearer_token = req
             .headers()
             .get("Authorization")
             .and_then(|header| header.to_str().ok())
             .and_then(|header| header.strip_prefix("Bearer "))
-            .map(String::from);
+            .map(|token| token.trim().to_string());
+
+        let result = match (bearer_token, &self.jwt) {
+            (Some(token), Some(jwt)) if JwtVerifier::looks_like_jwt(&token) => jwt.verify(&token),
+            (Some(token), _) => self.registry.authenticate(&token),
+            (None, _) => Err(AuthFailure::UnknownKey),
+        };
+        let result = result.and_then(|key| match self.tenants.get(&key.tenant) {
+            Some(tenant) => Ok((key, tenant)),
+            None => {
+                info!("Key {} belongs to unknown tenant '{}'", key.id, key.tenant);
+                Err(AuthFailure::UnknownTenant)
+            }
+        });
 
-        if let Some(token) = bearer_token {
-            if token == api_key {
+        match result {
+            Ok((mut key, tenant)) => {
+                key.limits = tenant.key_limits(&key.limits);
+                let session = match key.session {
+                    Some(session) => session.to_string(),
+                    None => session_identity(&req),
+                };
+                // Scrapes poll on a schedule and must not eat into the key's request budget
+                let limited = if req.path() == "/metrics" { None } else { Some(self.limiter.check(&key, &session)) };
+                let status = match limited {
+                    None => None,
+                    Some(Ok(status)) => Some(status),
+                    Some(Err(limited)) => {
+                        info!("Rate limited key {} ({} bucket) on {}", key.id, limited.scope.as_str(), req.path());
+                        return Box::pin(async move {
+                            let (http_req, _payload) = req.into_parts();
+                            Ok(ServiceResponse::new(http_req, too_many_requests(limited)).map_into_right_body())
+                        });
+                    }
+                };
+                // Downstream handlers read the caller's identity via web::ReqData<AuthenticatedKey>
+                // and their tenant via web::ReqData<Arc<Tenant>>
+                req.extensions_mut().insert(key);
+                req.extensions_mut().insert(tenant);
                 let fut = self.service.call(req);
                 Box::pin(async move {
-                    let res: ServiceResponse<B> = fut.await?;
+                    let mut res: ServiceResponse<B> = fut.await?;
+                    if let Some(status) = status {
+                        let headers = res.headers_mut();
+                        headers.insert(HeaderName::from_static("x-ratelimit-limit"), HeaderValue::from(status.limit));
+                        headers.insert(HeaderName::from_static("x-ratelimit-remaining"), HeaderValue::from(status.remaining));
+                    }
                     Ok(res.map_into_left_body())
                 })
-            } else {
+            }
+            Err(failure) => {
+                info!("Rejected request to {}: {:?}", req.path(), failure);
                 Box::pin(async move {
                     let (http_req, _payload) = req.into_parts();
-                    let res = HttpResponse::new(StatusCode::UNAUTHORIZED);
+                    let res = unauthorized(failure);
                     Ok(ServiceResponse::new(http_req, res).map_into_right_body())
                 })
             }
-        } else {
-            Box::pin(async move {
-                let (http_req, _payload) = req.into_parts();
-                let res = HttpResponse::new(StatusCode::UNAUTHORIZED);
-                Ok(ServiceResponse::new(http_req, res).map_into_right_body())
-            })
         }
     }
 }
 
+fn unauthorized(failure: AuthFailure) -> HttpResponse {
+    let (code, message) = match failure {
+        AuthFailure::UnknownKey => ("unauthorized", "Missing or invalid API key"),
+        AuthFailure::Disabled => ("key_disabled", "API key is disabled"),
+        AuthFailure::Expired => ("key_expired", "API key has expired"),
+        AuthFailure::InvalidToken => ("invalid_token", "Bearer token is invalid"),
+        AuthFailure::TokenExpired => ("token_expired", "Bearer token has expired"),
+        AuthFailure::UnknownTenant => ("unknown_tenant", "The credentials belong to a tenant that is not configured"),
+    };
+    HttpResponse::Unauthorized().json(json!({ "error": { "code": code, "message": message } }))
+}
+
+// The end user behind a request: an explicit X-Session-Id header or `session_id` query
+// parameter, falling back to the client address.
+fn session_identity(req: &ServiceRequest) -> String {
+    if let Some(session) = req.headers().get("X-Session-Id").and_then(|value| value.to_str().ok()) {
+        return session.trim().to_string();
+    }
+    let from_query = req
+        .query_string()
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(name, _)| *name == "session_id")
+        .map(|(_, value)| value.to_string());
+    from_query
+        .or_else(|| req.peer_addr().map(|addr| addr.ip().to_string()))
+        .unwrap_or_default()
+}
+
+fn too_many_requests(limited: RateLimited) -> HttpResponse {
+    HttpResponse::TooManyRequests()
+        .insert_header((header::RETRY_AFTER, (limited.retry_after.as_secs_f64().ceil() as u64).max(1).to_string()))
+        .insert_header(("X-RateLimit-Limit", limited.limit.to_string()))
+        .insert_header(("X-RateLimit-Remaining", "0"))
+        .insert_header(("X-RateLimit-Scope", limited.scope.as_str()))
+        .json(json!({
+            "error": {
+                "code": "rate_limited",
+                "message": format!("Too many requests for this {}; retry later", limited.scope.as_str()),
+            }
+        }))
+}
@@ -0,0 +1,458 @@
+// api_keys.rs
+use crate::config::Config;
+use crate::interaction::Route;
+use crate::tenants::DEFAULT_TENANT;
+
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use subtle::ConstantTimeEq;
+use uuid::Uuid;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    Chat,
+    Vision,
+    Generate,
+    Admin,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 4] = [Scope::Chat, Scope::Vision, Scope::Generate, Scope::Admin];
+
+    pub fn for_route(route: Route) -> Scope {
+        match route {
+            Route::Chat => Scope::Chat,
+            Route::Vision => Scope::Vision,
+            Route::Generate => Scope::Generate,
+        }
+    }
+}
+
+// One entry of the key registry file. Only the SHA-256 of the secret is stored;
+// generate one with `printf '%s' "$KEY" | sha256sum`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StoredKey {
+    pub id: String,
+    pub name: String,
+    pub key_hash: String,
+    pub scopes: Vec<Scope>,
+    // Unix timestamp (seconds); the key is rejected from then on
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub created_at: u64,
+    #[serde(default)]
+    pub last_used_at: Option<u64>,
+    #[serde(default)]
+    pub limits: KeyLimits,
+    // Persona for sessions started with this key, unless the request names one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub persona: Option<String>,
+    // Unset for keys of the default tenant
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+}
+
+impl StoredKey {
+    pub fn tenant(&self) -> &str {
+        self.tenant.as_deref().unwrap_or(DEFAULT_TENANT)
+    }
+}
+
+// Per-key overrides of the server-wide rate limits and quotas; unset fields use the defaults.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyLimits {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub requests_per_minute: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub burst: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub daily_tokens: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub monthly_tokens: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub daily_images: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub monthly_images: Option<u64>,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+// Identity of the caller (an API key or a JWT subject), attached to the request by the auth middleware.
+#[derive(Debug, Clone)]
+pub struct AuthenticatedKey {
+    pub id: String,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub limits: KeyLimits,
+    // End-user session named by a JWT; API keys leave it to the request
+    pub session: Option<Uuid>,
+    pub persona: Option<String>,
+    pub tenant: String,
+    // End user named by a JWT, whose memories the caller may use; API keys name users per request
+    pub subject: Option<String>,
+}
+
+impl AuthenticatedKey {
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope)
+    }
+
+    // Admins of the default tenant run the server and see every tenant;
+    // other admins only manage their own.
+    pub fn is_operator(&self) -> bool {
+        self.has_scope(Scope::Admin) && self.tenant == DEFAULT_TENANT
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AuthFailure {
+    UnknownKey,
+    Disabled,
+    Expired,
+    InvalidToken,
+    TokenExpired,
+    // The key or token names a tenant that is not configured
+    UnknownTenant,
+}
+
+#[derive(Debug)]
+pub enum RegistryError {
+    NotFound,
+    // The legacy API_KEY entry lives in the environment, not the registry file
+    Immutable,
+    Io(io::Error),
+}
+
+impl fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegistryError::NotFound => write!(f, "API key not found"),
+            RegistryError::Immutable => write!(f, "the legacy API_KEY cannot be managed through the API"),
+            RegistryError::Io(e) => write!(f, "failed to persist API keys: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RegistryError {}
+
+const LEGACY_KEY_ID: &str = "legacy";
+
+pub struct KeyRegistry {
+    path: PathBuf,
+    keys: RwLock<Vec<StoredKey>>,
+    // Updated on every request, so kept apart from the keys and written out by `flush`
+    last_used: Mutex<HashMap<String, u64>>,
+    // Whether a key was used since the registry was last written
+    used: AtomicBool,
+    // Held while changing and writing the keys, so saves land one at a time and in order
+    writer: Mutex<()>,
+}
+
+impl KeyRegistry {
+    // Load the registry file (api_keys.json in the data directory). A legacy auth.api_key
+    // (API_KEY) is still honoured as an unrestricted key so existing deployments keep working.
+    pub fn load(config: &Config) -> io::Result<Self> {
+        let path = config.data_file("api_keys.json");
+        let mut keys: Vec<StoredKey> = if path.exists() {
+            let contents = fs::read_to_string(&path)?;
+            serde_json::from_str(&contents)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))?
+        } else {
+            Vec::new()
+        };
+        info!("Loaded {} API key(s) from {}", keys.len(), path.display());
+
+        if let Some(legacy_key) = &config.auth.api_key {
+            warn!("A legacy API key is configured; accepting it as an unrestricted key");
+            keys.push(StoredKey {
+                id: LEGACY_KEY_ID.to_string(),
+                name: "Legacy API_KEY".to_string(),
+                key_hash: hash_key(legacy_key.trim()),
+                scopes: Scope::ALL.to_vec(),
+                expires_at: None,
+                enabled: true,
+                created_at: 0,
+                last_used_at: None,
+                limits: KeyLimits::default(),
+                persona: None,
+                tenant: None,
+            });
+        }
+        if keys.is_empty() {
+            warn!("No API keys configured; every request will be rejected");
+        }
+
+        Ok(KeyRegistry {
+            path,
+            keys: RwLock::new(keys),
+            last_used: Mutex::new(HashMap::new()),
+            used: AtomicBool::new(false),
+            writer: Mutex::new(()),
+        })
+    }
+
+    pub fn authenticate(&self, token: &str) -> Result<AuthenticatedKey, AuthFailure> {
+        let token_hash = hash_key(token);
+        let keys = self.keys.read().unwrap();
+
+        // Compare against every entry so timing does not reveal which (if any) matched
+        let mut matched = None;
+        for key in keys.iter() {
+            if bool::from(key.key_hash.as_bytes().ct_eq(token_hash.as_bytes())) {
+                matched = Some(key);
+            }
+        }
+
+        let key = matched.ok_or(AuthFailure::UnknownKey)?;
+        if !key.enabled {
+            return Err(AuthFailure::Disabled);
+        }
+        if key.expires_at.is_some_and(|expires_at| expires_at <= now()) {
+            return Err(AuthFailure::Expired);
+        }
+        self.last_used.lock().unwrap().insert(key.id.clone(), now());
+        // The legacy key is never written out
+        if key.id != LEGACY_KEY_ID {
+            self.used.store(true, Ordering::Relaxed);
+        }
+        Ok(AuthenticatedKey {
+            id: key.id.clone(),
+            name: key.name.clone(),
+            scopes: key.scopes.clone(),
+            limits: key.limits.clone(),
+            session: None,
+            persona: key.persona.clone(),
+            tenant: key.tenant().to_string(),
+            subject: None,
+        })
+    }
+
+    // All keys with their most recent use folded in; hashes are left to the caller to hide.
+    pub fn list(&self) -> Vec<StoredKey> {
+        self.with_last_use(&self.keys.read().unwrap())
+    }
+
+    fn with_last_use(&self, keys: &[StoredKey]) -> Vec<StoredKey> {
+        let last_used = self.last_used.lock().unwrap();
+        keys.iter()
+            .cloned()
+            .map(|mut key| {
+                if let Some(used) = last_used.get(&key.id) {
+                    key.last_used_at = Some(*used);
+                }
+                key
+            })
+            .collect()
+    }
+
+    // Issue a new key; the secret is returned once and only its hash is kept.
+    pub fn create(
+        &self,
+        name: String,
+        scopes: Vec<Scope>,
+        expires_at: Option<u64>,
+        limits: KeyLimits,
+        persona: Option<String>,
+        tenant: &str,
+    ) -> Result<(StoredKey, String), RegistryError> {
+        let secret = generate_secret();
+        let key = StoredKey {
+            id: Uuid::new_v4().to_string(),
+            name,
+            key_hash: hash_key(&secret),
+            scopes,
+            expires_at,
+            enabled: true,
+            created_at: now(),
+            last_used_at: None,
+            limits,
+            persona,
+            tenant: (tenant != DEFAULT_TENANT).then(|| tenant.to_string()),
+        };
+        self.modify(|keys| {
+            keys.push(key.clone());
+            Ok(())
+        })?;
+        Ok((key, secret))
+    }
+
+    pub fn get(&self, id: &str) -> Option<StoredKey> {
+        self.list().into_iter().find(|key| key.id == id)
+    }
+
+    // Replace a key's secret, keeping its id, name and scopes.
+    pub fn rotate(&self, id: &str) -> Result<(StoredKey, String), RegistryError> {
+        let secret = generate_secret();
+        let key = self.update(id, |key| key.key_hash = hash_key(&secret))?;
+        Ok((key, secret))
+    }
+
+    pub fn set_enabled(&self, id: &str, enabled: bool) -> Result<StoredKey, RegistryError> {
+        self.update(id, |key| key.enabled = enabled)
+    }
+
+    pub fn delete(&self, id: &str) -> Result<StoredKey, RegistryError> {
+        if id == LEGACY_KEY_ID {
+            return Err(RegistryError::Immutable);
+        }
+        let removed = self.modify(|keys| {
+            let index = keys.iter().position(|key| key.id == id).ok_or(RegistryError::NotFound)?;
+            Ok(keys.remove(index))
+        })?;
+        self.last_used.lock().unwrap().remove(id);
+        Ok(removed)
+    }
+
+    fn update(&self, id: &str, change: impl FnOnce(&mut StoredKey)) -> Result<StoredKey, RegistryError> {
+        if id == LEGACY_KEY_ID {
+            return Err(RegistryError::Immutable);
+        }
+        let updated = self.modify(|keys| {
+            let key = keys.iter_mut().find(|key| key.id == id).ok_or(RegistryError::NotFound)?;
+            change(key);
+            Ok(key.clone())
+        })?;
+        Ok(StoredKey {
+            last_used_at: self.last_used.lock().unwrap().get(id).copied().or(updated.last_used_at),
+            ..updated
+        })
+    }
+
+    // Apply a change to a copy of the keys and write it out; only once it is on disk does it
+    // replace the keys in use, so a failed write changes nothing.
+    fn modify<T>(&self, change: impl FnOnce(&mut Vec<StoredKey>) -> Result<T, RegistryError>) -> Result<T, RegistryError> {
+        let _writer = self.writer.lock().unwrap();
+        let mut keys = self.keys.read().unwrap().clone();
+        let result = change(&mut keys)?;
+        self.save(&keys)?;
+        *self.keys.write().unwrap() = keys;
+        Ok(result)
+    }
+
+    // Write out when keys were last used, if any were since the last write. Called on an
+    // interval and at shutdown rather than on every request.
+    pub fn flush(&self) {
+        if !self.used.load(Ordering::Relaxed) {
+            return;
+        }
+        let _writer = self.writer.lock().unwrap();
+        let keys = self.keys.read().unwrap().clone();
+        if let Err(e) = self.save(&keys) {
+            error!("Failed to record when API keys were last used: {}", e);
+        }
+    }
+
+    // Write the keys atomically (temp file + rename) with their last use folded in, leaving
+    // out the legacy key. Callers hold the writer, so saves land one at a time.
+    fn save(&self, keys: &[StoredKey]) -> Result<(), RegistryError> {
+        self.used.store(false, Ordering::Relaxed);
+        let keys: Vec<StoredKey> = self.with_last_use(keys).into_iter().filter(|key| key.id != LEGACY_KEY_ID).collect();
+        let json = serde_json::to_string_pretty(&keys).map_err(|e| RegistryError::Io(e.into()))?;
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).map_err(RegistryError::Io)?;
+        }
+        let tmp_path = self.path.with_extension(format!("json.{}.tmp", Uuid::new_v4()));
+        let written = fs::write(&tmp_path, json).and_then(|_| fs::rename(&tmp_path, &self.path));
+        if let Err(e) = written {
+            let _ = fs::remove_file(&tmp_path);
+            // Retried on the next flush
+            self.used.store(true, Ordering::Relaxed);
+            return Err(RegistryError::Io(e));
+        }
+        Ok(())
+    }
+}
+
+fn generate_secret() -> String {
+    let bytes: [u8; 24] = rand::random();
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("fana_{}", hex)
+}
+
+pub fn hash_key(key: &str) -> String {
+    Sha256::digest(key.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry(path: PathBuf) -> KeyRegistry {
+        KeyRegistry {
+            path,
+            keys: RwLock::new(Vec::new()),
+            last_used: Mutex::new(HashMap::new()),
+            used: AtomicBool::new(false),
+            writer: Mutex::new(()),
+        }
+    }
+
+    #[test]
+    fn changes_are_kept_once_written() {
+        let dir = std::env::temp_dir().join(format!("api_keys_{}", Uuid::new_v4()));
+        let registry = registry(dir.join("api_keys.json"));
+        let (key, secret) = registry.create("ci".to_string(), vec![Scope::Chat], None, KeyLimits::default(), None, DEFAULT_TENANT).unwrap();
+        assert_eq!(registry.authenticate(&secret).unwrap().id, key.id);
+
+        let (_, rotated) = registry.rotate(&key.id).unwrap();
+        assert!(matches!(registry.authenticate(&secret), Err(AuthFailure::UnknownKey)));
+        assert!(registry.authenticate(&rotated).is_ok());
+
+        let stored: Vec<StoredKey> = serde_json::from_str(&fs::read_to_string(dir.join("api_keys.json")).unwrap()).unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].key_hash, hash_key(&rotated));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn failed_writes_change_nothing() {
+        // The data "directory" is a file, so every write fails
+        let blocker = std::env::temp_dir().join(format!("api_keys_{}", Uuid::new_v4()));
+        fs::write(&blocker, "").unwrap();
+        let registry = registry(blocker.join("api_keys.json"));
+        let secret = generate_secret();
+        registry.keys.write().unwrap().push(StoredKey {
+            id: "k1".to_string(),
+            name: "ci".to_string(),
+            key_hash: hash_key(&secret),
+            scopes: vec![Scope::Chat],
+            expires_at: None,
+            enabled: true,
+            created_at: 0,
+            last_used_at: None,
+            limits: KeyLimits::default(),
+            persona: None,
+            tenant: None,
+        });
+
+        assert!(matches!(registry.rotate("k1"), Err(RegistryError::Io(_))));
+        assert!(matches!(registry.set_enabled("k1", false), Err(RegistryError::Io(_))));
+        assert!(matches!(registry.delete("k1"), Err(RegistryError::Io(_))));
+        assert!(registry.create("new".to_string(), vec![Scope::Chat], None, KeyLimits::default(), None, DEFAULT_TENANT).is_err());
+        assert_eq!(registry.list().len(), 1);
+        assert_eq!(registry.authenticate(&secret).unwrap().id, "k1");
+        fs::remove_file(blocker).unwrap();
+    }
+}
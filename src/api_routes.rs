@@ -1,64 +1,366 @@
 // api_routes.rs
-use crate::session_manager::SessionManager;
-use crate::input_process::process_user_input;
+use crate::input_process::{process_user_input, route_for};
+use crate::api_keys::{AuthenticatedKey, Scope};
 
-use actix_web::{web, HttpResponse, Responder};
-use actix_web::http::header::ContentType;
-use serde::Deserialize;
+use crate::interaction::{Interaction, Route, TokenUsage};
+use crate::knowledge_base::Citation;
+use crate::memory::{self, UserIdError};
+use crate::chat_error;
+use crate::generation_params::{GenerationDefaults, GenerationOverrides, GenerationParams};
+use crate::personas::{PersonaError, PersonaRegistry};
+use crate::prompt_template::accept_language;
+use crate::rate_limit::{QuotaExceeded, QuotaTracker};
+use crate::tenants::{SessionError, Tenant, TenantRegistry};
+use crate::tools::ToolCallRecord;
+use crate::usage_ledger::UsageLedger;
+
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use actix_web::http::header::{self, ContentType};
+use actix_web::http::StatusCode;
+use serde::{Deserialize, Serialize};
 use reqwest::Client;
 use log::{error, info};
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 use std::net::IpAddr;
+use std::time::Instant;
+use uuid::Uuid;
 
 #[derive(Deserialize)]
 struct InteractRequest {
     question: String,
+    // Continue a conversation; a new session is started when absent
+    session_id: Option<Uuid>,
+    // Only honoured when the session is created
+    persona: Option<String>,
+    // For the system prompt; the locale defaults to Accept-Language
+    user_name: Option<String>,
+    locale: Option<String>,
+    // Whose long-term memories to use; a token's subject always wins
+    user_id: Option<String>,
+    #[serde(flatten)]
+    generation: GenerationOverrides,
 }
 
+#[derive(Serialize)]
+pub(crate) struct InteractResponse {
+    session_id: Uuid,
+    persona: String,
+    route: Route,
+    provider: String,
+    model: String,
+    content: String,
+    usage: Option<TokenUsage>,
+    image_urls: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    sources: Vec<Citation>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    language: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tool_calls: Vec<ToolCallRecord>,
+    latency_ms: u128,
+}
 
+impl InteractResponse {
+    pub(crate) fn new(interaction: Interaction, persona: &str, started: Instant) -> Self {
+        InteractResponse {
+            session_id: interaction.session_id,
+            persona: persona.to_string(),
+            route: interaction.route,
+            provider: interaction.provider,
+            model: interaction.model,
+            content: interaction.content,
+            usage: interaction.usage,
+            image_urls: interaction.image_urls,
+            sources: interaction.sources,
+            language: interaction.language,
+            tool_calls: interaction.tool_calls,
+            latency_ms: started.elapsed().as_millis(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    error: ErrorBody,
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    code: &'static str,
+    message: String,
+    session_id: Option<Uuid>,
+    latency_ms: u128,
+}
+
+// JSON is the default; clients that rank `text/plain` above `application/json` get the bare content.
+fn wants_plaintext(req: &HttpRequest) -> bool {
+    req.headers()
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .map(prefers_plaintext)
+        .unwrap_or(false)
+}
+
+// Compares the q-values of both types; on a tie the one listed first wins, and wildcards leave JSON.
+fn prefers_plaintext(accept: &str) -> bool {
+    let (text_q, text_at) = accepted(accept, "text/plain");
+    let (json_q, json_at) = accepted(accept, "application/json");
+    text_q > 0.0 && (text_q > json_q || (text_q == json_q && text_at < json_at))
+}
+
+// The q-value and position of the most specific Accept entry covering `media`; absent types get q=0.
+fn accepted(accept: &str, media: &str) -> (f32, usize) {
+    let main_type = media.split('/').next().unwrap_or(media);
+    let mut best: Option<(u8, f32, usize)> = None;
+    for (position, entry) in accept.split(',').enumerate() {
+        let mut parts = entry.split(';');
+        let range = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+        let specificity = match range.split_once('/') {
+            _ if range == media => 2,
+            Some((kind, "*")) if kind == main_type => 1,
+            Some(("*", "*")) => 0,
+            _ => continue,
+        };
+        let quality = parts
+            .filter_map(|param| param.split_once('='))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("q"))
+            .and_then(|(_, value)| value.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if best.is_none_or(|(most_specific, _, _)| specificity > most_specific) {
+            best = Some((specificity, quality, position));
+        }
+    }
+    best.map(|(_, quality, position)| (quality, position)).unwrap_or((0.0, usize::MAX))
+}
+
+// 429 for an exhausted quota, with headers saying which one and when it resets.
+fn quota_exceeded(exceeded: &QuotaExceeded, latency_ms: u128) -> HttpResponse {
+    HttpResponse::TooManyRequests()
+        .insert_header((header::RETRY_AFTER, exceeded.retry_after().as_secs().to_string()))
+        .insert_header(("X-Quota-Scope", exceeded.scope.as_str()))
+        .insert_header(("X-Quota-Kind", exceeded.kind.as_str()))
+        .insert_header(("X-Quota-Period", exceeded.period.as_str()))
+        .insert_header(("X-Quota-Limit", exceeded.limit.to_string()))
+        .insert_header(("X-Quota-Remaining", "0"))
+        .insert_header(("X-Quota-Reset", exceeded.reset_at.to_string()))
+        .json(ErrorResponse {
+            error: ErrorBody {
+                code: "quota_exceeded",
+                message: exceeded.message(),
+                session_id: None,
+                latency_ms,
+            },
+        })
+}
+
+// 404 for another tenant's session, 403 for another end user's.
+pub(crate) fn session_error(e: &SessionError) -> (StatusCode, &'static str) {
+    match e {
+        SessionError::NotFound(_) => (StatusCode::NOT_FOUND, "session_not_found"),
+        SessionError::Forbidden(_) => (StatusCode::FORBIDDEN, "session_forbidden"),
+    }
+}
+
+// 400 for a persona that does not exist, 409 for switching an existing session's persona.
+pub(crate) fn persona_error(e: &PersonaError) -> (StatusCode, &'static str) {
+    match e {
+        PersonaError::Unknown(_) => (StatusCode::BAD_REQUEST, "unknown_persona"),
+        PersonaError::Conflict { .. } => (StatusCode::CONFLICT, "persona_conflict"),
+    }
+}
+
+// 403 for a token naming another user, 400 for an unusable user id. Shared with the memory routes.
+pub(crate) fn user_id_error(e: &UserIdError) -> (StatusCode, &'static str) {
+    match e {
+        UserIdError::Mismatch => (StatusCode::FORBIDDEN, "user_mismatch"),
+        UserIdError::Invalid => (StatusCode::BAD_REQUEST, "invalid_parameter"),
+    }
+}
 
 // Set API Routes
-pub fn configure(cfg: &mut web::ServiceConfig, groq_api_key: web::Data<String>) {
-    cfg.service(
-        web::scope("/api")
-            .app_data(web::Data::new(Client::new()))
-            .app_data(web::Data::new(groq_api_key.clone()))
-            .route("/interact", web::post().to(interact_route))
-    );
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    let features = &crate::config::get().features;
+    let mut scope = web::scope("/api")
+        .app_data(web::Data::new(crate::http_client::shared_client().clone()))
+        .route("/interact", web::post().to(interact_route))
+        .route("/personas", web::get().to(crate::personas::list_personas))
+        .configure(crate::admin_routes::configure);
+    if features.websocket {
+        scope = scope.route("/ws", web::get().to(crate::ws_chat::ws_route));
+    }
+    if features.usage_api {
+        scope = scope.configure(crate::usage_routes::configure);
+    }
+    if features.rag {
+        scope = scope.configure(crate::knowledge_routes::configure);
+    }
+    if features.memory {
+        scope = scope.configure(crate::memory_routes::configure);
+    }
+    cfg.service(scope);
 }
 
 // Set API Endpoint
+#[allow(clippy::too_many_arguments)]
 async fn interact_route(
+    req: HttpRequest,
     interact_req: web::Json<InteractRequest>,
     client: web::Data<Client>,
-    groq_api_key: web::Data<String>,
-    session_manager: web::Data<Arc<Mutex<SessionManager>>>,
+    generation_defaults: web::Data<GenerationDefaults>,
+    personas: web::Data<PersonaRegistry>,
+    tenants: web::Data<Arc<TenantRegistry>>,
+    quotas: web::Data<QuotaTracker>,
+    usage_ledger: web::Data<UsageLedger>,
+    auth: web::ReqData<AuthenticatedKey>,
+    tenant: web::ReqData<Arc<Tenant>>,
 ) -> impl Responder {
-    let session_manager_arc = session_manager.clone();
-    let mut session_manager_lock = session_manager_arc.lock().unwrap();
+    let started = Instant::now();
     let ip_addr = IpAddr::V4("95.94.61.253".parse().unwrap());
-    let session_id = session_manager_lock.create_session(ip_addr);
+    // A session named by the caller's token wins over the request body
+    let session_id = auth.session.or(interact_req.session_id).unwrap_or_else(Uuid::new_v4);
+    tracing::Span::current().record("session_id", tracing::field::display(session_id));
+    if let Err(e) = tenants.check_session(session_id, &tenant, auth.subject.as_deref()) {
+        let (status, code) = session_error(&e);
+        return HttpResponse::build(status).json(ErrorResponse {
+            error: ErrorBody {
+                code,
+                message: e.to_string(),
+                session_id: None,
+                latency_ms: started.elapsed().as_millis(),
+            },
+        });
+    }
+    let user_id = match memory::user_id(&auth, interact_req.user_id.as_deref()) {
+        Ok(user_id) => user_id,
+        Err(e) => {
+            info!("Rejected interact request: {}", e);
+            let (status, code) = user_id_error(&e);
+            return HttpResponse::build(status).json(ErrorResponse {
+                error: ErrorBody {
+                    code,
+                    message: e.to_string(),
+                    session_id: None,
+                    latency_ms: started.elapsed().as_millis(),
+                },
+            });
+        }
+    };
+    let persona = match personas.for_session(session_id, interact_req.persona.as_deref(), &auth, &tenant) {
+        Ok(persona) => persona,
+        Err(e) => {
+            info!("Rejected interact request: {}", e);
+            let (status, code) = persona_error(&e);
+            return HttpResponse::build(status).json(ErrorResponse {
+                error: ErrorBody {
+                    code,
+                    message: e.to_string(),
+                    session_id: Some(session_id),
+                    latency_ms: started.elapsed().as_millis(),
+                },
+            });
+        }
+    };
+
+    let routing = route_for(&interact_req.question, &persona, &tenant);
+    let route = routing.route;
+    tracing::Span::current().record("route", route.as_str());
+    if !auth.has_scope(Scope::for_route(route)) {
+        info!("Key {} ({}) lacks the {} scope", auth.id, auth.name, route.as_str());
+        return HttpResponse::Forbidden().json(ErrorResponse {
+            error: ErrorBody {
+                code: "insufficient_scope",
+                message: format!("This API key is not allowed to use the {} route", route.as_str()),
+                session_id: None,
+                latency_ms: started.elapsed().as_millis(),
+            },
+        });
+    }
+    if let Err(exceeded) = quotas.check(&auth, &tenant, route) {
+        return quota_exceeded(&exceeded, started.elapsed().as_millis());
+    }
+    let params = match GenerationParams::resolve(&interact_req.generation, &persona.generation_defaults(&generation_defaults)) {
+        Ok(params) => params,
+        Err(e) => {
+            info!("Rejected interact request: {}", e);
+            return HttpResponse::BadRequest().json(ErrorResponse {
+                error: ErrorBody {
+                    code: "invalid_parameter",
+                    message: e.to_string(),
+                    session_id: None,
+                    latency_ms: started.elapsed().as_millis(),
+                },
+            });
+        }
+    };
+    let locale = interact_req.locale.as_deref().or_else(|| {
+        accept_language(req.headers().get(header::ACCEPT_LANGUAGE).and_then(|value| value.to_str().ok()))
+    });
+    let mut prompt = persona.prompt_variables(&tenant, Some(&auth)).with_user(interact_req.user_name.as_deref(), locale);
+    // The chat model may generate images through tools, which needs image quota left
+    if quotas.check(&auth, &tenant, Route::Generate).is_err() {
+        prompt = prompt.without(Route::Generate);
+    }
+    if let Err(e) = tenants.claim_session(session_id, &tenant, auth.subject.as_deref()) {
+        let (status, code) = session_error(&e);
+        return HttpResponse::build(status).json(ErrorResponse {
+            error: ErrorBody {
+                code,
+                message: e.to_string(),
+                session_id: None,
+                latency_ms: started.elapsed().as_millis(),
+            },
+        });
+    }
     info!("Added assistant message to context for session {}", session_id);
     //let session = session_manager_lock.get_session(&session_id).unwrap().clone();
 
-    let groq_api_key = groq_api_key.get_ref().trim();
-
     match process_user_input(
         interact_req.question.clone(),
-        &mut session_manager_lock,
+        routing,
+        session_id,
         &client,
-        groq_api_key,
+        &tenant,
         ip_addr,
+        &persona,
+        &prompt,
+        &params,
+        user_id.as_deref(),
+        None,
     ).await {
-        Ok(response) => {
-            // Return the response as plain text
-            HttpResponse::Ok()
-     .content_type(ContentType::plaintext())
-     .body(response)
+        Ok(interaction) => {
+            quotas.record(&auth, &interaction);
+            usage_ledger.record(&auth, &interaction);
+            memory::remember_later(&auth, user_id.as_deref(), &interact_req.question, &interaction);
+            if wants_plaintext(&req) {
+                HttpResponse::Ok()
+                    .content_type(ContentType::plaintext())
+                    .body(interaction.content)
+            } else {
+                HttpResponse::Ok().json(InteractResponse::new(interaction, &persona.name, started))
+            }
         }
         Err(e) => {
             error!("Failed to process user input: {}", e);
-            HttpResponse::InternalServerError().body(format!("Failed to process user input: {}", e))
+            let mapping = chat_error::classify(e.as_ref());
+            let message = format!("Failed to process user input: {}", e);
+            let mut response = HttpResponse::build(mapping.status);
+            if let Some(retry_after) = mapping.retry_after {
+                response.insert_header((header::RETRY_AFTER, retry_after.as_secs().max(1).to_string()));
+            }
+            if wants_plaintext(&req) {
+                response
+                    .content_type(ContentType::plaintext())
+                    .body(message)
+            } else {
+                response.json(ErrorResponse {
+                    error: ErrorBody {
+                        code: mapping.code,
+                        message,
+                        session_id: Some(session_id),
+                        latency_ms: started.elapsed().as_millis(),
+                    },
+                })
+            }
         }
     }
 }
@@ -100,3 +402,23 @@ async fn interact_route(
 //             .route("/analyze", web::post().to(analyze_image_route))
 //     );
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plaintext_only_when_ranked_above_json() {
+        assert!(prefers_plaintext("text/plain"));
+        assert!(prefers_plaintext("text/plain, application/json"));
+        assert!(prefers_plaintext("application/json;q=0.5, text/plain"));
+        assert!(prefers_plaintext("text/html, text/plain;q=0.9"));
+        assert!(prefers_plaintext("text/*, application/json;q=0.2"));
+        assert!(!prefers_plaintext("application/json, text/plain"));
+        assert!(!prefers_plaintext("text/plain;q=0.4, application/json;q=0.8"));
+        assert!(!prefers_plaintext("text/plain;q=0"));
+        assert!(!prefers_plaintext("*/*"));
+        assert!(!prefers_plaintext("text/html"));
+        assert!(!prefers_plaintext(""));
+    }
+}
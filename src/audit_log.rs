@@ -0,0 +1,52 @@
+// audit_log.rs
+use crate::api_keys::now;
+use crate::config::Config;
+
+use log::error;
+use serde::Serialize;
+use serde_json::Value;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+// One administrative change, appended as a JSON line.
+#[derive(Serialize)]
+struct AuditEntry<'a> {
+    timestamp: u64,
+    actor: &'a str,
+    action: &'a str,
+    target: &'a str,
+    details: Value,
+}
+
+// Append-only record of administrative changes (audit_log.jsonl in the data directory).
+pub struct AuditLog {
+    path: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl AuditLog {
+    pub fn open(config: &Config) -> Self {
+        let path = config.data_file("audit_log.jsonl");
+        AuditLog { path, lock: Mutex::new(()) }
+    }
+
+    pub fn record(&self, actor: &str, action: &str, target: &str, details: Value) {
+        let entry = AuditEntry { timestamp: now(), actor, action, target, details };
+        let _guard = self.lock.lock().unwrap();
+        if let Err(e) = self.append(&entry) {
+            // The change itself has already been applied, so losing the entry is logged loudly
+            error!("Failed to write audit log entry {} {} by {}: {}", action, target, actor, e);
+        }
+    }
+
+    fn append(&self, entry: &AuditEntry) -> std::io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        let line = serde_json::to_string(entry)?;
+        writeln!(file, "{}", line)
+    }
+}
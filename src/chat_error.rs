@@ -0,0 +1,137 @@
+// chat_error.rs
+use crate::generation_params::ParamError;
+use crate::http_client::ProviderError;
+
+use actix_web::http::StatusCode;
+use serde::Deserialize;
+use std::fmt;
+use std::time::Duration;
+
+// What a provider said when it rejected a call, parsed from its error payload
+// (`{"error": {"message", "type", "code"}}` for OpenAI-compatible APIs).
+#[derive(Debug, Clone)]
+pub struct ErrorDetail {
+    pub provider: &'static str,
+    pub message: String,
+    pub kind: Option<String>,
+    pub code: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ErrorPayload {
+    error: ErrorPayloadBody,
+}
+
+#[derive(Deserialize)]
+struct ErrorPayloadBody {
+    message: Option<String>,
+    #[serde(rename = "type")]
+    kind: Option<String>,
+    code: Option<serde_json::Value>,
+}
+
+impl ErrorDetail {
+    pub fn parse(provider: &'static str, body: &str) -> Self {
+        match serde_json::from_str::<ErrorPayload>(body) {
+            Ok(payload) => ErrorDetail {
+                provider,
+                message: payload.error.message.unwrap_or_else(|| "no message".to_string()),
+                kind: payload.error.kind,
+                // Some providers send numeric codes
+                code: payload.error.code.and_then(|code| match code {
+                    serde_json::Value::String(code) => Some(code),
+                    serde_json::Value::Null => None,
+                    other => Some(other.to_string()),
+                }),
+            },
+            Err(_) => ErrorDetail {
+                provider,
+                message: if body.trim().is_empty() { "empty error body".to_string() } else { body.trim().to_string() },
+                kind: None,
+                code: None,
+            },
+        }
+    }
+
+    // The model itself is gone or unknown, rather than the request being malformed.
+    pub fn is_model_unavailable(&self) -> bool {
+        matches!(self.code.as_deref(), Some("model_not_found" | "model_decommissioned"))
+    }
+}
+
+impl fmt::Display for ErrorDetail {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.provider, self.message)?;
+        if let Some(code) = self.code.as_ref().or(self.kind.as_ref()) {
+            write!(f, " ({})", code)?;
+        }
+        Ok(())
+    }
+}
+
+// A provider answered successfully but the answer is unusable.
+#[derive(Debug)]
+pub enum ChatError {
+    EmptyResponse { provider: String },
+    InvalidResponse { provider: String, message: String },
+    // The provider has no API key in the configuration
+    NotConfigured { provider: String },
+}
+
+impl fmt::Display for ChatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChatError::EmptyResponse { provider } => write!(f, "{} returned an empty response", provider),
+            ChatError::InvalidResponse { provider, message } => write!(f, "{} returned an invalid response: {}", provider, message),
+            ChatError::NotConfigured { provider } => write!(f, "{} is not configured", provider),
+        }
+    }
+}
+
+impl std::error::Error for ChatError {}
+
+// How an error is reported to API clients.
+pub struct ErrorMapping {
+    pub status: StatusCode,
+    pub code: &'static str,
+    pub retry_after: Option<Duration>,
+}
+
+impl ErrorMapping {
+    fn new(status: StatusCode, code: &'static str) -> Self {
+        ErrorMapping { status, code, retry_after: None }
+    }
+}
+
+pub fn classify(error: &(dyn std::error::Error + 'static)) -> ErrorMapping {
+    if let Some(error) = error.downcast_ref::<ProviderError>() {
+        return match error {
+            ProviderError::RateLimited { retry_after, .. } => ErrorMapping {
+                retry_after: *retry_after,
+                ..ErrorMapping::new(StatusCode::TOO_MANY_REQUESTS, "provider_rate_limited")
+            },
+            ProviderError::Auth { .. } => ErrorMapping::new(StatusCode::BAD_GATEWAY, "provider_auth_failed"),
+            ProviderError::BadRequest { detail, .. } if detail.is_model_unavailable() => {
+                ErrorMapping::new(StatusCode::BAD_GATEWAY, "model_unavailable")
+            }
+            ProviderError::BadRequest { .. } => ErrorMapping::new(StatusCode::BAD_REQUEST, "provider_rejected_request"),
+            ProviderError::Server { .. } => ErrorMapping::new(StatusCode::BAD_GATEWAY, "provider_error"),
+            ProviderError::Timeout => ErrorMapping::new(StatusCode::GATEWAY_TIMEOUT, "provider_timeout"),
+            ProviderError::Connect(_) | ProviderError::Network(_) => {
+                ErrorMapping::new(StatusCode::BAD_GATEWAY, "provider_unreachable")
+            }
+            ProviderError::CircuitOpen { .. } => ErrorMapping::new(StatusCode::SERVICE_UNAVAILABLE, "provider_unavailable"),
+        };
+    }
+    if let Some(error) = error.downcast_ref::<ChatError>() {
+        return match error {
+            ChatError::EmptyResponse { .. } => ErrorMapping::new(StatusCode::BAD_GATEWAY, "empty_response"),
+            ChatError::InvalidResponse { .. } => ErrorMapping::new(StatusCode::BAD_GATEWAY, "invalid_provider_response"),
+            ChatError::NotConfigured { .. } => ErrorMapping::new(StatusCode::SERVICE_UNAVAILABLE, "provider_not_configured"),
+        };
+    }
+    if error.downcast_ref::<ParamError>().is_some() {
+        return ErrorMapping::new(StatusCode::BAD_REQUEST, "invalid_parameter");
+    }
+    ErrorMapping::new(StatusCode::INTERNAL_SERVER_ERROR, "processing_failed")
+}
@@ -0,0 +1,934 @@
+// config.rs
+use crate::api_keys::KeyLimits;
+use crate::generation_params::{builtin_model_limits, ModelLimits};
+use crate::redaction::ContentMode;
+
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::net::ToSocketAddrs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+// Server configuration: defaults, then the TOML file (CONFIG_FILE, default config.toml
+// if present), then environment variables. Loaded and validated once at startup.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub storage: StorageConfig,
+    pub providers: ProvidersConfig,
+    pub models: ModelsConfig,
+    // Chat models by name, besides (or in place of) the built-in ones
+    pub chat_models: BTreeMap<String, ModelLimits>,
+    pub personas: PersonasConfig,
+    pub prompt: PromptConfig,
+    pub generation: GenerationConfig,
+    pub http: HttpConfig,
+    pub circuit_breaker: CircuitBreakerConfig,
+    pub limits: LimitsConfig,
+    pub auth: AuthConfig,
+    pub logging: LoggingConfig,
+    pub features: FeaturesConfig,
+    pub rag: RagConfig,
+    pub embeddings: EmbeddingsConfig,
+    pub memory: MemoryConfig,
+    pub language: LanguageConfig,
+    pub tools: ToolsConfig,
+    pub tenants: BTreeMap<String, TenantConfig>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: String,
+    // Defaults to the number of CPUs
+    pub workers: Option<usize>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig { bind: "127.0.0.1:8080".to_string(), workers: None }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    // Sessions, API keys, quotas, usage and audit logs live here
+    pub data_dir: PathBuf,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig { data_dir: PathBuf::from("src/data") }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProvidersConfig {
+    pub groq: ProviderConfig,
+    pub openai: ProviderConfig,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProviderConfig {
+    pub api_key: Option<String>,
+    // Overrides the provider's public endpoint, e.g. for a proxy
+    pub chat_url: Option<String>,
+    pub images_url: Option<String>,
+    pub embeddings_url: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModelsConfig {
+    pub chat: String,
+    pub chat_fallbacks: Vec<String>,
+    pub vision: Vec<String>,
+    pub image: String,
+    // Used for knowledge base documents and the questions searched against them
+    pub embedding: String,
+}
+
+impl Default for ModelsConfig {
+    fn default() -> Self {
+        ModelsConfig {
+            chat: "mixtral-8x7b-32768".to_string(),
+            chat_fallbacks: Vec::new(),
+            vision: vec!["gpt-4o".to_string()],
+            image: "dall-e-3".to_string(),
+            embedding: "text-embedding-3-small".to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct PersonasConfig {
+    // One `<name>.toml` per persona
+    pub dir: PathBuf,
+    // Used when neither the request nor the API key picks one
+    pub default: String,
+}
+
+impl Default for PersonasConfig {
+    fn default() -> Self {
+        PersonasConfig { dir: PathBuf::from("personas"), default: "fana".to_string() }
+    }
+}
+
+// Values for the system prompt templates; callers may supply their own locale.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct PromptConfig {
+    pub tenant_name: String,
+    pub contact_email: String,
+    pub locale: String,
+}
+
+impl Default for PromptConfig {
+    fn default() -> Self {
+        PromptConfig {
+            tenant_name: "Fana AI".to_string(),
+            contact_email: "hello@fana.ai".to_string(),
+            locale: "en".to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct GenerationConfig {
+    pub temperature: f32,
+    pub max_tokens: u32,
+    pub top_p: f32,
+}
+
+impl Default for GenerationConfig {
+    fn default() -> Self {
+        GenerationConfig { temperature: 0.5, max_tokens: 4000, top_p: 1.0 }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    pub connect_timeout_secs: u64,
+    pub timeout_secs: u64,
+    pub stream_timeout_secs: u64,
+    pub max_retries: u32,
+    pub backoff_base_ms: u64,
+    pub backoff_max_ms: u64,
+    pub max_retry_after_secs: u64,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            connect_timeout_secs: 10,
+            timeout_secs: 60,
+            stream_timeout_secs: 300,
+            max_retries: 3,
+            backoff_base_ms: 500,
+            backoff_max_ms: 8000,
+            max_retry_after_secs: 30,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct CircuitBreakerConfig {
+    pub failure_threshold: u32,
+    pub cooldown_secs: u64,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        CircuitBreakerConfig { failure_threshold: 5, cooldown_secs: 30 }
+    }
+}
+
+// Defaults for every API key; keys can override them individually. Unset quotas are unlimited.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub key_requests_per_minute: u32,
+    pub key_burst: u32,
+    pub session_requests_per_minute: u32,
+    pub session_burst: u32,
+    pub daily_tokens: Option<u64>,
+    pub monthly_tokens: Option<u64>,
+    pub daily_images: Option<u64>,
+    pub monthly_images: Option<u64>,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            key_requests_per_minute: 60,
+            key_burst: 20,
+            session_requests_per_minute: 20,
+            session_burst: 5,
+            daily_tokens: None,
+            monthly_tokens: None,
+            daily_images: None,
+            monthly_images: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    // Unrestricted key kept for existing deployments; prefer the key registry
+    pub api_key: Option<String>,
+    pub jwt_hs256_secret: Option<String>,
+    pub jwt_jwks_file: Option<PathBuf>,
+    pub jwt_audience: Option<String>,
+    pub jwt_issuer: Option<String>,
+    pub jwt_scope_claim: String,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            api_key: None,
+            jwt_hs256_secret: None,
+            jwt_jwks_file: None,
+            jwt_audience: None,
+            jwt_issuer: None,
+            jwt_scope_claim: "scope".to_string(),
+        }
+    }
+}
+
+impl AuthConfig {
+    pub fn jwt_enabled(&self) -> bool {
+        self.jwt_hs256_secret.is_some() || self.jwt_jwks_file.is_some()
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    // tracing EnvFilter directives; RUST_LOG takes precedence
+    pub filter: String,
+    pub format: LogFormat,
+    pub content_mode: ContentMode,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig { filter: "info,fanallmrust=debug".to_string(), format: LogFormat::Text, content_mode: ContentMode::Full }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeaturesConfig {
+    pub interactive_console: bool,
+    pub websocket: bool,
+    pub metrics: bool,
+    pub usage_api: bool,
+    // When off, messages with URLs or trigger words are answered as plain chat
+    pub vision: bool,
+    pub image_generation: bool,
+    // Answer chat messages with excerpts from the tenant's knowledge base
+    pub rag: bool,
+    // Remember facts about identified users across sessions
+    pub memory: bool,
+    // Detect each message's language for triggers, replies and image prompts
+    pub language_detection: bool,
+    // Let chat models call tools; the chat models (and fallbacks) must support function calling.
+    // Image tools take over from trigger words and URL detection
+    pub tools: bool,
+    pub session_active_window_secs: u64,
+}
+
+impl Default for FeaturesConfig {
+    fn default() -> Self {
+        FeaturesConfig {
+            interactive_console: true,
+            websocket: true,
+            metrics: true,
+            usage_api: true,
+            vision: true,
+            image_generation: true,
+            rag: true,
+            memory: false,
+            language_detection: true,
+            tools: false,
+            session_active_window_secs: 300,
+        }
+    }
+}
+
+// Chunking of ingested documents and retrieval of excerpts for the prompt.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RagConfig {
+    // In characters; chunks overlap so a passage cut in two is still found whole
+    pub chunk_size: usize,
+    pub chunk_overlap: usize,
+    pub top_k: usize,
+    // Cosine similarity below which an excerpt is considered unrelated
+    pub min_score: f32,
+    pub max_document_bytes: usize,
+}
+
+impl Default for RagConfig {
+    fn default() -> Self {
+        RagConfig { chunk_size: 1200, chunk_overlap: 200, top_k: 4, min_score: 0.3, max_document_bytes: 10 * 1024 * 1024 }
+    }
+}
+
+// A brand hosted alongside the default tenant, which the rest of this file describes.
+// Callers belong to the tenant named by their API key (or JWT `tenant` claim); unset
+// fields fall back to the server-wide values.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct TenantConfig {
+    // Shown in system prompts; defaults to the tenant id
+    pub name: Option<String>,
+    pub contact_email: Option<String>,
+    // Persona for sessions whose request and key don't pick one
+    pub persona: Option<String>,
+    // Personas this tenant's callers may use; empty allows all
+    pub personas: Vec<String>,
+    // Prefer TENANT_<ID>_GROQ_API_KEY / TENANT_<ID>_OPENAI_API_KEY over writing keys here
+    pub groq_api_key: Option<String>,
+    pub openai_api_key: Option<String>,
+    // Defaults for this tenant's keys in place of [limits]
+    pub limits: KeyLimits,
+    // Shared by all of the tenant's keys
+    pub quotas: TenantQuotas,
+    // Words that start image generation; unset uses the built-in list
+    pub triggers: Option<Vec<String>>,
+    // Extra trigger words for messages detected in a language, by ISO 639-3 code
+    // (e.g. "por"); a language left out uses its built-in list
+    pub language_triggers: BTreeMap<String, Vec<String>>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct TenantQuotas {
+    pub daily_tokens: Option<u64>,
+    pub monthly_tokens: Option<u64>,
+    pub daily_images: Option<u64>,
+    pub monthly_images: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum EmbeddingBackend {
+    // providers.openai.embeddings_url with models.embedding; any OpenAI-compatible server works
+    #[default]
+    Openai,
+    // A sentence-transformer run on the CPU; needs the `local-embeddings` build feature
+    Local,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct EmbeddingsConfig {
+    pub backend: EmbeddingBackend,
+    // For OpenAI-compatible servers other than OpenAI; the tenant's OpenAI key is used otherwise
+    pub api_key: Option<String>,
+    // Holds config.json, tokenizer.json and model.safetensors of a BERT sentence-transformer
+    pub local_model_dir: PathBuf,
+    pub batch_size: usize,
+    // Vectors kept in memory, keyed by a hash of the model and text; 0 disables the cache
+    pub cache_size: usize,
+}
+
+impl Default for EmbeddingsConfig {
+    fn default() -> Self {
+        EmbeddingsConfig {
+            backend: EmbeddingBackend::Openai,
+            api_key: None,
+            local_model_dir: PathBuf::from("models/all-MiniLM-L6-v2"),
+            batch_size: 64,
+            cache_size: 10_000,
+        }
+    }
+}
+
+// Extraction of facts about a user after each chat exchange, and their recall into prompts.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct MemoryConfig {
+    // Chat model that extracts facts; defaults to models.chat
+    pub model: Option<String>,
+    pub top_k: usize,
+    // Cosine similarity below which a memory is considered unrelated to the message
+    pub min_score: f32,
+    // New facts this similar to a remembered one are dropped as repeats
+    pub duplicate_score: f32,
+    // The oldest memories are forgotten beyond this
+    pub max_per_user: usize,
+}
+
+impl Default for MemoryConfig {
+    fn default() -> Self {
+        MemoryConfig { model: None, top_k: 5, min_score: 0.3, duplicate_score: 0.9, max_per_user: 200 }
+    }
+}
+
+impl MemoryConfig {
+    pub fn model<'a>(&'a self, models: &'a ModelsConfig) -> &'a str {
+        self.model.as_deref().unwrap_or(&models.chat)
+    }
+}
+
+// How detected languages are used.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LanguageConfig {
+    // 0 to 1; less certain detections leave a message's language unknown. Short
+    // messages score low, so this is best kept around 0.1
+    pub min_confidence: f64,
+    // ISO 639-3 codes detection chooses from, e.g. ["eng", "por"]; empty allows every
+    // language. Narrowing it avoids confusing close languages in short messages
+    pub languages: Vec<String>,
+    // Rewrite image requests in other languages into English before generating
+    pub translate_image_prompts: bool,
+    // Chat model that translates; defaults to models.chat
+    pub model: Option<String>,
+}
+
+impl Default for LanguageConfig {
+    fn default() -> Self {
+        LanguageConfig { min_confidence: 0.1, languages: Vec::new(), translate_image_prompts: true, model: None }
+    }
+}
+
+impl LanguageConfig {
+    pub fn model<'a>(&'a self, models: &'a ModelsConfig) -> &'a str {
+        self.model.as_deref().unwrap_or(&models.chat)
+    }
+}
+
+// Function calling in chat.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ToolsConfig {
+    // Tool names offered to the model; empty offers every tool
+    pub enabled: Vec<String>,
+    // Rounds of tool calls per message before the model must answer
+    pub max_iterations: usize,
+    pub timeout_secs: u64,
+    // Longer results are cut before they are handed back to the model
+    pub max_output_chars: usize,
+    // Model Context Protocol servers by name; their tools are offered as <name>__<tool>
+    pub mcp: BTreeMap<String, McpServerConfig>,
+}
+
+impl Default for ToolsConfig {
+    fn default() -> Self {
+        ToolsConfig { enabled: Vec::new(), max_iterations: 5, timeout_secs: 30, max_output_chars: 8000, mcp: BTreeMap::new() }
+    }
+}
+
+// An MCP server, started as a subprocess speaking over stdio (`command`) or reached over
+// streamable HTTP (`url`).
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct McpServerConfig {
+    pub command: Option<String>,
+    pub args: Vec<String>,
+    // Added to the subprocess's environment
+    pub env: BTreeMap<String, String>,
+    pub url: Option<String>,
+    pub headers: BTreeMap<String, String>,
+    // Sent as a bearer token to HTTP servers (or set MCP_<NAME>_TOKEN)
+    pub token: Option<String>,
+    // Tenants whose chats are offered the server's tools; empty offers them to every tenant
+    pub tenants: Vec<String>,
+}
+
+// Everything wrong with the configuration, reported together.
+#[derive(Debug)]
+pub struct ConfigError {
+    pub errors: Vec<String>,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Invalid configuration:")?;
+        for error in &self.errors {
+            writeln!(f, "  - {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+// Load, override and validate the configuration, then make it available through `get()`.
+pub fn init() -> Result<&'static Config, ConfigError> {
+    let config = Config::load()?;
+    Ok(CONFIG.get_or_init(|| config))
+}
+
+pub fn get() -> &'static Config {
+    CONFIG.get().expect("configuration is loaded at startup")
+}
+
+impl Config {
+    pub fn load() -> Result<Self, ConfigError> {
+        let mut errors = Vec::new();
+
+        let explicit_path = env::var("CONFIG_FILE").ok();
+        let path = PathBuf::from(explicit_path.as_deref().unwrap_or("config.toml"));
+        let mut config = if path.exists() {
+            match fs::read_to_string(&path) {
+                Ok(contents) => toml::from_str(&contents).unwrap_or_else(|e| {
+                    errors.push(format!("{}: {}", path.display(), e));
+                    Config::default()
+                }),
+                Err(e) => {
+                    errors.push(format!("{}: {}", path.display(), e));
+                    Config::default()
+                }
+            }
+        } else {
+            if explicit_path.is_some() {
+                errors.push(format!("CONFIG_FILE {} does not exist", path.display()));
+            }
+            Config::default()
+        };
+
+        config.apply_env(&mut errors);
+        config.validate(&mut errors);
+        if errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(ConfigError { errors })
+        }
+    }
+
+    fn apply_env(&mut self, errors: &mut Vec<String>) {
+        let mut env = EnvOverrides { errors };
+
+        env.set("BIND_ADDRESS", &mut self.server.bind);
+        if let Some(port) = env.parse::<u16>("PORT") {
+            let host = self.server.bind.rsplit_once(':').map(|(host, _)| host).unwrap_or("0.0.0.0");
+            self.server.bind = format!("{}:{}", host, port);
+        }
+        env.set_opt("WORKERS", &mut self.server.workers);
+        env.set("DATA_DIR", &mut self.storage.data_dir);
+
+        env.set_opt("GROQ_API_KEY", &mut self.providers.groq.api_key);
+        env.set_opt("GROQ_CHAT_URL", &mut self.providers.groq.chat_url);
+        env.set_opt("OPENAI_API_KEY", &mut self.providers.openai.api_key);
+        env.set_opt("OPENAI_CHAT_URL", &mut self.providers.openai.chat_url);
+        env.set_opt("OPENAI_IMAGES_URL", &mut self.providers.openai.images_url);
+        env.set_opt("OPENAI_EMBEDDINGS_URL", &mut self.providers.openai.embeddings_url);
+
+        env.set("CHAT_MODEL", &mut self.models.chat);
+        env.set_list("CHAT_FALLBACK_MODELS", &mut self.models.chat_fallbacks);
+        env.set_list("VISION_MODELS", &mut self.models.vision);
+        env.set("IMAGE_MODEL", &mut self.models.image);
+        env.set("EMBEDDING_MODEL", &mut self.models.embedding);
+
+        env.set("PERSONAS_DIR", &mut self.personas.dir);
+        env.set("DEFAULT_PERSONA", &mut self.personas.default);
+
+        env.set("TENANT_NAME", &mut self.prompt.tenant_name);
+        env.set("CONTACT_EMAIL", &mut self.prompt.contact_email);
+        env.set("DEFAULT_LOCALE", &mut self.prompt.locale);
+
+        env.set("CHAT_TEMPERATURE", &mut self.generation.temperature);
+        env.set("CHAT_MAX_TOKENS", &mut self.generation.max_tokens);
+        env.set("CHAT_TOP_P", &mut self.generation.top_p);
+
+        env.set("HTTP_CONNECT_TIMEOUT_SECS", &mut self.http.connect_timeout_secs);
+        env.set("HTTP_TIMEOUT_SECS", &mut self.http.timeout_secs);
+        env.set("HTTP_STREAM_TIMEOUT_SECS", &mut self.http.stream_timeout_secs);
+        env.set("HTTP_MAX_RETRIES", &mut self.http.max_retries);
+        env.set("HTTP_BACKOFF_BASE_MS", &mut self.http.backoff_base_ms);
+        env.set("HTTP_BACKOFF_MAX_MS", &mut self.http.backoff_max_ms);
+        env.set("HTTP_MAX_RETRY_AFTER_SECS", &mut self.http.max_retry_after_secs);
+
+        env.set("CIRCUIT_FAILURE_THRESHOLD", &mut self.circuit_breaker.failure_threshold);
+        env.set("CIRCUIT_COOLDOWN_SECS", &mut self.circuit_breaker.cooldown_secs);
+
+        env.set("RATE_LIMIT_KEY_RPM", &mut self.limits.key_requests_per_minute);
+        env.set("RATE_LIMIT_KEY_BURST", &mut self.limits.key_burst);
+        env.set("RATE_LIMIT_SESSION_RPM", &mut self.limits.session_requests_per_minute);
+        env.set("RATE_LIMIT_SESSION_BURST", &mut self.limits.session_burst);
+        env.set_opt("QUOTA_DAILY_TOKENS", &mut self.limits.daily_tokens);
+        env.set_opt("QUOTA_MONTHLY_TOKENS", &mut self.limits.monthly_tokens);
+        env.set_opt("QUOTA_DAILY_IMAGES", &mut self.limits.daily_images);
+        env.set_opt("QUOTA_MONTHLY_IMAGES", &mut self.limits.monthly_images);
+
+        env.set_opt("API_KEY", &mut self.auth.api_key);
+        env.set_opt("JWT_HS256_SECRET", &mut self.auth.jwt_hs256_secret);
+        env.set_opt("JWT_JWKS_FILE", &mut self.auth.jwt_jwks_file);
+        env.set_opt("JWT_AUDIENCE", &mut self.auth.jwt_audience);
+        env.set_opt("JWT_ISSUER", &mut self.auth.jwt_issuer);
+        env.set("JWT_SCOPE_CLAIM", &mut self.auth.jwt_scope_claim);
+
+        env.set("RUST_LOG", &mut self.logging.filter);
+        env.set_enum("LOG_FORMAT", &mut self.logging.format);
+        env.set_enum("LOG_CONTENT_MODE", &mut self.logging.content_mode);
+
+        env.set("FEATURE_INTERACTIVE_CONSOLE", &mut self.features.interactive_console);
+        env.set("FEATURE_WEBSOCKET", &mut self.features.websocket);
+        env.set("FEATURE_METRICS", &mut self.features.metrics);
+        env.set("FEATURE_USAGE_API", &mut self.features.usage_api);
+        env.set("FEATURE_VISION", &mut self.features.vision);
+        env.set("FEATURE_IMAGE_GENERATION", &mut self.features.image_generation);
+        env.set("FEATURE_RAG", &mut self.features.rag);
+        env.set("FEATURE_MEMORY", &mut self.features.memory);
+        env.set("FEATURE_LANGUAGE_DETECTION", &mut self.features.language_detection);
+        env.set("FEATURE_TOOLS", &mut self.features.tools);
+        env.set("SESSION_ACTIVE_WINDOW_SECS", &mut self.features.session_active_window_secs);
+
+        env.set("RAG_CHUNK_SIZE", &mut self.rag.chunk_size);
+        env.set("RAG_CHUNK_OVERLAP", &mut self.rag.chunk_overlap);
+        env.set("RAG_TOP_K", &mut self.rag.top_k);
+        env.set("RAG_MIN_SCORE", &mut self.rag.min_score);
+        env.set("RAG_MAX_DOCUMENT_BYTES", &mut self.rag.max_document_bytes);
+
+        env.set_enum("EMBEDDINGS_BACKEND", &mut self.embeddings.backend);
+        env.set_opt("EMBEDDINGS_API_KEY", &mut self.embeddings.api_key);
+        env.set("EMBEDDINGS_LOCAL_MODEL_DIR", &mut self.embeddings.local_model_dir);
+        env.set("EMBEDDINGS_BATCH_SIZE", &mut self.embeddings.batch_size);
+        env.set("EMBEDDINGS_CACHE_SIZE", &mut self.embeddings.cache_size);
+
+        env.set_opt("MEMORY_MODEL", &mut self.memory.model);
+        env.set("MEMORY_TOP_K", &mut self.memory.top_k);
+        env.set("MEMORY_MIN_SCORE", &mut self.memory.min_score);
+        env.set("MEMORY_DUPLICATE_SCORE", &mut self.memory.duplicate_score);
+        env.set("MEMORY_MAX_PER_USER", &mut self.memory.max_per_user);
+
+        env.set("LANGUAGE_MIN_CONFIDENCE", &mut self.language.min_confidence);
+        env.set_list("LANGUAGES", &mut self.language.languages);
+        env.set("LANGUAGE_TRANSLATE_IMAGE_PROMPTS", &mut self.language.translate_image_prompts);
+        env.set_opt("LANGUAGE_MODEL", &mut self.language.model);
+
+        env.set_list("TOOLS_ENABLED", &mut self.tools.enabled);
+        env.set("TOOLS_MAX_ITERATIONS", &mut self.tools.max_iterations);
+        env.set("TOOLS_TIMEOUT_SECS", &mut self.tools.timeout_secs);
+        env.set("TOOLS_MAX_OUTPUT_CHARS", &mut self.tools.max_output_chars);
+        for (name, server) in &mut self.tools.mcp {
+            env.set_opt(&format!("MCP_{}_TOKEN", name.to_uppercase().replace('-', "_")), &mut server.token);
+        }
+
+        for (id, tenant) in &mut self.tenants {
+            let prefix = format!("TENANT_{}", id.to_uppercase().replace('-', "_"));
+            env.set_opt(&format!("{}_GROQ_API_KEY", prefix), &mut tenant.groq_api_key);
+            env.set_opt(&format!("{}_OPENAI_API_KEY", prefix), &mut tenant.openai_api_key);
+        }
+    }
+
+    fn validate(&mut self, errors: &mut Vec<String>) {
+        if !self.server.bind.to_socket_addrs().is_ok_and(|mut addrs| addrs.next().is_some()) {
+            errors.push(format!("server.bind: '{}' is not a valid host:port address", self.server.bind));
+        }
+        if self.server.workers == Some(0) {
+            errors.push("server.workers: must be at least 1".to_string());
+        }
+
+        // Blank keys (e.g. an empty variable in compose.yaml) count as unset
+        for key in [
+            &mut self.providers.groq.api_key,
+            &mut self.providers.openai.api_key,
+            &mut self.auth.api_key,
+            &mut self.embeddings.api_key,
+        ]
+        .into_iter()
+        .chain(self.tools.mcp.values_mut().map(|server| &mut server.token))
+        {
+            if key.as_deref().is_some_and(|value| value.trim().is_empty()) {
+                *key = None;
+            }
+        }
+        if self.providers.groq.api_key.is_none() {
+            errors.push("providers.groq.api_key: not set (or set GROQ_API_KEY)".to_string());
+        }
+
+        for (name, limits) in &self.chat_models {
+            if crate::provider_chain::provider(&limits.provider).is_none() {
+                errors.push(format!("chat_models.{}.provider: unknown provider '{}'", name, limits.provider));
+            }
+            if limits.max_tokens == 0 {
+                errors.push(format!("chat_models.{}.max_tokens: must be greater than 0", name));
+            }
+        }
+        for model in std::iter::once(&self.models.chat).chain(&self.models.chat_fallbacks).chain(&self.models.vision) {
+            if self.chat_model(model).is_none() {
+                errors.push(format!("models: unknown chat model '{}'", model));
+            }
+        }
+        if self.models.image.trim().is_empty() {
+            errors.push("models.image: must not be empty".to_string());
+        }
+        if self.models.embedding.trim().is_empty() {
+            errors.push("models.embedding: must not be empty".to_string());
+        }
+
+        if self.http.timeout_secs == 0 || self.http.stream_timeout_secs == 0 || self.http.connect_timeout_secs == 0 {
+            errors.push("http: timeouts must be greater than 0".to_string());
+        }
+        if self.http.backoff_base_ms > self.http.backoff_max_ms {
+            errors.push("http.backoff_base_ms: must not exceed backoff_max_ms".to_string());
+        }
+        if self.circuit_breaker.failure_threshold == 0 {
+            errors.push("circuit_breaker.failure_threshold: must be at least 1".to_string());
+        }
+        if self.limits.key_burst == 0 || self.limits.session_burst == 0 {
+            errors.push("limits: bursts must be at least 1".to_string());
+        }
+        // 0 has always meant "no quota"
+        for quota in [
+            &mut self.limits.daily_tokens,
+            &mut self.limits.monthly_tokens,
+            &mut self.limits.daily_images,
+            &mut self.limits.monthly_images,
+        ] {
+            if *quota == Some(0) {
+                *quota = None;
+            }
+        }
+
+        if self.rag.chunk_size < 100 {
+            errors.push("rag.chunk_size: must be at least 100".to_string());
+        }
+        if self.rag.chunk_overlap >= self.rag.chunk_size {
+            errors.push("rag.chunk_overlap: must be smaller than chunk_size".to_string());
+        }
+        if self.rag.top_k == 0 {
+            errors.push("rag.top_k: must be at least 1".to_string());
+        }
+        if !(-1.0..=1.0).contains(&self.rag.min_score) {
+            errors.push("rag.min_score: must be between -1 and 1".to_string());
+        }
+        if let Some(model) = self.memory.model.as_deref().filter(|model| self.chat_model(model).is_none()) {
+            errors.push(format!("memory.model: unknown chat model '{}'", model));
+        }
+        if self.memory.top_k == 0 || self.memory.max_per_user == 0 {
+            errors.push("memory: top_k and max_per_user must be at least 1".to_string());
+        }
+        for (name, score) in [("min_score", self.memory.min_score), ("duplicate_score", self.memory.duplicate_score)] {
+            if !(-1.0..=1.0).contains(&score) {
+                errors.push(format!("memory.{}: must be between -1 and 1", name));
+            }
+        }
+        if !(0.0..=1.0).contains(&self.language.min_confidence) {
+            errors.push("language.min_confidence: must be between 0 and 1".to_string());
+        }
+        for code in &self.language.languages {
+            if whatlang::Lang::from_code(code.as_str()).is_none() {
+                errors.push(format!("language.languages: '{}' is not an ISO 639-3 language code", code));
+            }
+        }
+        if let Some(model) = self.language.model.as_deref().filter(|model| self.chat_model(model).is_none()) {
+            errors.push(format!("language.model: unknown chat model '{}'", model));
+        }
+        // Tool names are checked once the tools are registered (see tools.rs)
+        if self.tools.max_iterations == 0 || self.tools.timeout_secs == 0 || self.tools.max_output_chars == 0 {
+            errors.push("tools: max_iterations, timeout_secs and max_output_chars must be at least 1".to_string());
+        }
+        for (name, server) in &self.tools.mcp {
+            if name.is_empty() || !name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_') {
+                errors.push(format!("tools.mcp.{}: names may only contain a-z, 0-9, '-' and '_'", name));
+            }
+            match (&server.command, &server.url) {
+                (Some(_), None) => {}
+                (None, Some(url)) if url.starts_with("http://") || url.starts_with("https://") => {}
+                (None, Some(url)) => errors.push(format!("tools.mcp.{}.url: '{}' is not an http(s) URL", name, url)),
+                _ => errors.push(format!("tools.mcp.{}: set either command or url", name)),
+            }
+            for tenant in server.tenants.iter().filter(|id| *id != crate::tenants::DEFAULT_TENANT && !self.tenants.contains_key(*id)) {
+                errors.push(format!("tools.mcp.{}.tenants: unknown tenant '{}'", name, tenant));
+            }
+        }
+        if self.embeddings.batch_size == 0 {
+            errors.push("embeddings.batch_size: must be at least 1".to_string());
+        }
+        if self.embeddings.backend == EmbeddingBackend::Local {
+            if !cfg!(feature = "local-embeddings") {
+                errors.push("embeddings.backend: 'local' needs a build with the local-embeddings feature".to_string());
+            }
+            for file in ["config.json", "tokenizer.json", "model.safetensors"] {
+                let path = self.embeddings.local_model_dir.join(file);
+                if !path.exists() {
+                    errors.push(format!("embeddings.local_model_dir: {} does not exist", path.display()));
+                }
+            }
+        }
+
+        if self.auth.jwt_enabled() && self.auth.jwt_audience.is_none() {
+            errors.push("auth.jwt_audience: required when JWT authentication is enabled".to_string());
+        }
+        if let Some(path) = &self.auth.jwt_jwks_file {
+            if !path.exists() {
+                errors.push(format!("auth.jwt_jwks_file: {} does not exist", path.display()));
+            }
+        }
+
+        // Personas are checked once they are loaded (see tenants.rs)
+        for (id, tenant) in &mut self.tenants {
+            if id == crate::tenants::DEFAULT_TENANT {
+                errors.push(format!("tenants.{}: the id is reserved for the server-wide settings", id));
+            } else if id.is_empty() || !id.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_') {
+                errors.push(format!("tenants.{}: ids may only contain a-z, 0-9, '-' and '_'", id));
+            }
+            for key in [&mut tenant.groq_api_key, &mut tenant.openai_api_key] {
+                if key.as_deref().is_some_and(|value| value.trim().is_empty()) {
+                    *key = None;
+                }
+            }
+            for code in tenant.language_triggers.keys() {
+                if whatlang::Lang::from_code(code.as_str()).is_none() {
+                    errors.push(format!("tenants.{}.language_triggers.{}: not an ISO 639-3 language code", id, code));
+                }
+            }
+            if tenant.limits.burst == Some(0) {
+                errors.push(format!("tenants.{}.limits.burst: must be at least 1", id));
+            }
+            for quota in [
+                &mut tenant.quotas.daily_tokens,
+                &mut tenant.quotas.monthly_tokens,
+                &mut tenant.quotas.daily_images,
+                &mut tenant.quotas.monthly_images,
+            ] {
+                if *quota == Some(0) {
+                    *quota = None;
+                }
+            }
+        }
+    }
+
+    // A chat model's provider and completion limit: configured, or else built in.
+    pub fn chat_model(&self, name: &str) -> Option<ModelLimits> {
+        self.chat_models.get(name).cloned().or_else(|| builtin_model_limits(name))
+    }
+
+    // Path of a file in the data directory.
+    pub fn data_file(&self, name: impl AsRef<Path>) -> PathBuf {
+        self.storage.data_dir.join(name)
+    }
+}
+
+// Applies environment variables over file values, collecting parse errors.
+struct EnvOverrides<'a> {
+    errors: &'a mut Vec<String>,
+}
+
+impl EnvOverrides<'_> {
+    fn parse<T: FromStr>(&mut self, name: &str) -> Option<T> {
+        let value = env::var(name).ok()?;
+        match value.trim().parse() {
+            Ok(parsed) => Some(parsed),
+            Err(_) => {
+                self.errors.push(format!("{}: invalid value '{}'", name, value));
+                None
+            }
+        }
+    }
+
+    fn set<T: FromStr>(&mut self, name: &str, target: &mut T) {
+        if let Some(value) = self.parse(name) {
+            *target = value;
+        }
+    }
+
+    fn set_opt<T: FromStr>(&mut self, name: &str, target: &mut Option<T>) {
+        if let Some(value) = self.parse(name) {
+            *target = Some(value);
+        }
+    }
+
+    fn set_list(&mut self, name: &str, target: &mut Vec<String>) {
+        if let Ok(value) = env::var(name) {
+            *target = value.split(',').map(str::trim).filter(|item| !item.is_empty()).map(String::from).collect();
+        }
+    }
+
+    // Enums are spelled as in the TOML file, e.g. LOG_FORMAT=json
+    fn set_enum<T: for<'de> Deserialize<'de>>(&mut self, name: &str, target: &mut T) {
+        if let Ok(value) = env::var(name) {
+            match T::deserialize(serde::de::value::StrDeserializer::<serde::de::value::Error>::new(&value.to_lowercase())) {
+                Ok(parsed) => *target = parsed,
+                Err(e) => self.errors.push(format!("{}: {}", name, e)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn configured_chat_models_extend_and_override_the_builtins() {
+        let mut config = Config::default();
+        config.chat_models.insert("gpt-4o".to_string(), ModelLimits { provider: "openai".to_string(), max_tokens: 1000 });
+        config.chat_models.insert("llama-3.1-8b-instant".to_string(), ModelLimits { provider: "groq".to_string(), max_tokens: 8000 });
+
+        assert_eq!(config.chat_model("gpt-4o").map(|limits| limits.max_tokens), Some(1000));
+        assert_eq!(config.chat_model("llama-3.1-8b-instant").map(|limits| limits.provider), Some("groq".to_string()));
+        assert_eq!(config.chat_model("gemma-7b-it").map(|limits| limits.max_tokens), Some(8192));
+        assert_eq!(config.chat_model("unknown"), None);
+    }
+}
@@ -3,7 +3,6 @@ pub mod manage_context {
     pub const MAX_CONTEXT_MESSAGES: usize = 10;
 
     use crate::session_manager::SessionManager;
-    use std::path::PathBuf;
     use serde_json::Value;
     use tokio::fs;
     use tokio::io::{AsyncReadExt, AsyncWriteExt};
@@ -11,15 +10,19 @@ pub mod manage_context {
     use uuid::Uuid;
     use log::{info};
     use std::net::{IpAddr};
+    use std::path::PathBuf;
 
     pub struct ContextManager {
         session_manager: SessionManager,
+        // The tenant's user_sessions directory
+        sessions_dir: PathBuf,
     }
 
     impl ContextManager {
-        pub fn new() -> Self {
+        pub fn new(sessions_dir: PathBuf) -> Self {
             ContextManager {
                 session_manager: SessionManager::new(),
+                sessions_dir,
             }
         }
         // Add a new message to a user's session. 
@@ -59,8 +62,7 @@ pub mod manage_context {
         // Save the current state of a user's session to a file during different steps. 
         // It takes a session ID as input, retrieves the corresponding session from the SessionManager, and saves the session to a file in JSON format.
         pub async fn save_context(&mut self, session_id: &Uuid) -> io::Result<()> {
-            let mut path = PathBuf::from("src/data"); // Create a "data" directory in your project's root directory
-            path.push("user_sessions"); // Add the "user_sessions" directory
+            let mut path = self.sessions_dir.clone(); // Sessions live in the tenant's storage
             path.push(session_id.to_string()); // Add the session ID
             path.push("context.json"); // Add the file name
             let dir_path = path.parent().unwrap();
@@ -81,23 +83,23 @@ pub mod manage_context {
         }
 
         pub async fn load_context(&mut self, session_id: &Uuid) -> io::Result<()> {
-            let mut path = PathBuf::from("src/data"); // Create a "data" directory in your project's root directory
-            path.push("user_sessions"); // Add the "user_sessions" directory
+            let mut path = self.sessions_dir.clone(); // Sessions live in the tenant's storage
             path.push(session_id.to_string()); // Add the session ID
             path.push("context.json"); // Add the file name
             let dir_path = path.parent().unwrap();
             fs::create_dir_all(dir_path).await?;
+            // Keep the loaded history under the caller's session id so get_context finds it
+            let ip_addr = IpAddr::V4("95.94.61.253".parse().unwrap()); // Replace with the actual IP address
+            self.session_manager.bind_session(ip_addr, *session_id);
             if path.exists() {
                 let mut file = fs::File::open(path).await?;
                 let mut contents = String::new();
                 file.read_to_string(&mut contents).await?;
                 let session: Vec<Value> = serde_json::from_str(&contents)?;
-                let ip_addr = IpAddr::V4("95.94.61.253".parse().unwrap()); // Replace with the actual IP address
-                let new_session_id = self.session_manager.create_session(ip_addr);
-                if let Some(session_mut) = self.session_manager.get_session(&new_session_id) {
+                if let Some(session_mut) = self.session_manager.get_session(session_id) {
                     session_mut.extend(session);
                 } else {
-                    return Err(io::Error::new(io::ErrorKind::Other, "Failed to create new session"));
+                    return Err(io::Error::other("Failed to create new session"));
                 }
             }
             Ok(())
@@ -0,0 +1,381 @@
+// embeddings.rs
+use crate::http_client::{self, ProviderRequest};
+use crate::chat_error::ChatError;
+use crate::config::{Config, EmbeddingBackend};
+use crate::interaction::{ProviderUsage, TokenUsage};
+use crate::tenants::ProviderCredentials;
+
+use futures::future::BoxFuture;
+use log::{debug, info};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::{Mutex, OnceLock};
+
+const EMBEDDINGS_URL: &str = "https://api.openai.com/v1/embeddings";
+
+static SERVICE: OnceLock<EmbeddingService> = OnceLock::new();
+
+pub type EmbedError = Box<dyn std::error::Error + Send + Sync>;
+
+// Vectors for one batch of inputs, in input order.
+pub struct EmbeddingBatch {
+    pub vectors: Vec<Vec<f32>>,
+    pub total_tokens: u64,
+}
+
+// Turns text into vectors. Implementations are handed at most `embeddings.batch_size`
+// inputs at a time; caching happens in front of them.
+pub trait Embedder: Send + Sync {
+    // Identifies the vector space: vectors of different models can't be compared
+    fn model(&self) -> &str;
+
+    // The provider billed for embeddings; None when they cost nothing, like a local model
+    fn provider(&self) -> Option<&'static str> {
+        None
+    }
+
+    // Hosted backends bill the tenant's credentials; local ones ignore them
+    fn embed_batch<'a>(&'a self, texts: &'a [String], credentials: &'a ProviderCredentials) -> BoxFuture<'a, Result<EmbeddingBatch, EmbedError>>;
+}
+
+#[derive(Serialize)]
+struct EmbeddingRequest<'a> {
+    model: &'a str,
+    input: &'a [String],
+}
+
+#[derive(Deserialize)]
+struct EmbeddingResponse {
+    data: Vec<EmbeddingData>,
+    #[serde(default)]
+    usage: Option<EmbeddingUsage>,
+}
+
+#[derive(Deserialize)]
+struct EmbeddingData {
+    index: usize,
+    embedding: Vec<f32>,
+}
+
+#[derive(Deserialize)]
+struct EmbeddingUsage {
+    #[serde(default)]
+    total_tokens: u64,
+}
+
+// The /v1/embeddings API of OpenAI and the servers that mimic it.
+pub struct HttpEmbedder {
+    client: Client,
+    url: String,
+    model: String,
+    // Replaces the tenant's OpenAI key, for servers that aren't OpenAI
+    api_key: Option<String>,
+}
+
+impl HttpEmbedder {
+    pub fn from_config(config: &Config) -> Self {
+        HttpEmbedder {
+            client: http_client::shared_client().clone(),
+            url: config.providers.openai.embeddings_url.clone().unwrap_or_else(|| EMBEDDINGS_URL.to_string()),
+            model: config.models.embedding.clone(),
+            api_key: config.embeddings.api_key.clone(),
+        }
+    }
+}
+
+impl Embedder for HttpEmbedder {
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn provider(&self) -> Option<&'static str> {
+        Some("openai")
+    }
+
+    fn embed_batch<'a>(&'a self, texts: &'a [String], credentials: &'a ProviderCredentials) -> BoxFuture<'a, Result<EmbeddingBatch, EmbedError>> {
+        Box::pin(async move {
+            let api_key = self
+                .api_key
+                .as_deref()
+                .or_else(|| credentials.api_key("openai"))
+                .ok_or_else(|| ChatError::NotConfigured { provider: "openai".to_string() })?;
+            let body = serde_json::to_value(EmbeddingRequest { model: &self.model, input: texts })?;
+            let mut response = http_client::send(&self.client, ProviderRequest {
+                provider: "openai",
+                url: &self.url,
+                api_key,
+                body: &body,
+                idempotent: true,
+                streaming: false,
+            })
+            .await?
+            .json::<EmbeddingResponse>()
+            .await
+            .map_err(|e| ChatError::InvalidResponse { provider: "openai".to_string(), message: e.to_string() })?;
+
+            if response.data.len() != texts.len() {
+                return Err(ChatError::InvalidResponse {
+                    provider: "openai".to_string(),
+                    message: format!("expected {} embeddings, got {}", texts.len(), response.data.len()),
+                }
+                .into());
+            }
+            response.data.sort_by_key(|data| data.index);
+            Ok(EmbeddingBatch {
+                vectors: response.data.into_iter().map(|data| data.embedding).collect(),
+                total_tokens: response.usage.map(|usage| usage.total_tokens).unwrap_or(0),
+            })
+        })
+    }
+}
+
+type ContentHash = [u8; 32];
+
+// Most recently computed vectors, oldest evicted first.
+struct VectorCache {
+    capacity: usize,
+    vectors: HashMap<ContentHash, Vec<f32>>,
+    order: VecDeque<ContentHash>,
+}
+
+impl VectorCache {
+    fn get(&self, hash: &ContentHash) -> Option<Vec<f32>> {
+        self.vectors.get(hash).cloned()
+    }
+
+    fn insert(&mut self, hash: ContentHash, vector: Vec<f32>) {
+        if self.capacity == 0 || self.vectors.contains_key(&hash) {
+            return;
+        }
+        while self.vectors.len() >= self.capacity {
+            match self.order.pop_front() {
+                Some(oldest) => self.vectors.remove(&oldest),
+                None => break,
+            };
+        }
+        self.order.push_back(hash);
+        self.vectors.insert(hash, vector);
+    }
+}
+
+pub struct Embeddings {
+    // One per input, in input order
+    pub vectors: Vec<Vec<f32>>,
+    pub model: String,
+    // Only for inputs that missed the cache
+    pub total_tokens: u64,
+    provider: Option<&'static str>,
+}
+
+impl Embeddings {
+    // What the call is billed as, when a hosted provider embedded anything.
+    pub fn usage(&self) -> Option<ProviderUsage> {
+        let provider = self.provider.filter(|_| self.total_tokens > 0)?;
+        let tokens = TokenUsage { prompt_tokens: self.total_tokens, completion_tokens: 0, total_tokens: self.total_tokens };
+        Some(ProviderUsage { provider: provider.to_string(), model: self.model.clone(), tokens: Some(tokens), images: 0 })
+    }
+}
+
+// A message's vector, computed once and shared by the knowledge base and memory searches.
+pub struct QueryVector {
+    pub vector: Vec<f32>,
+    pub model: String,
+}
+
+// The configured embedder behind a batching, content-addressed cache.
+pub struct EmbeddingService {
+    embedder: Box<dyn Embedder>,
+    batch_size: usize,
+    cache: Mutex<VectorCache>,
+}
+
+impl EmbeddingService {
+    pub fn new(embedder: Box<dyn Embedder>, batch_size: usize, cache_size: usize) -> Self {
+        EmbeddingService {
+            embedder,
+            batch_size,
+            cache: Mutex::new(VectorCache { capacity: cache_size, vectors: HashMap::new(), order: VecDeque::new() }),
+        }
+    }
+
+    pub fn model(&self) -> &str {
+        self.embedder.model()
+    }
+
+    fn content_hash(&self, text: &str) -> ContentHash {
+        let mut hasher = Sha256::new();
+        hasher.update(self.model().as_bytes());
+        hasher.update([0]);
+        hasher.update(text.as_bytes());
+        hasher.finalize().into()
+    }
+
+    pub async fn embed(&self, texts: &[String], credentials: &ProviderCredentials) -> Result<Embeddings, EmbedError> {
+        let hashes: Vec<ContentHash> = texts.iter().map(|text| self.content_hash(text)).collect();
+        let mut vectors: Vec<Option<Vec<f32>>> = {
+            let cache = self.cache.lock().unwrap();
+            hashes.iter().map(|hash| cache.get(hash)).collect()
+        };
+
+        // Each distinct text that missed the cache is embedded once
+        let mut missing: Vec<usize> = Vec::new();
+        for (i, hash) in hashes.iter().enumerate() {
+            if vectors[i].is_none() && !missing.iter().any(|&j| hashes[j] == *hash) {
+                missing.push(i);
+            }
+        }
+
+        let mut total_tokens = 0;
+        for batch in missing.chunks(self.batch_size) {
+            let inputs: Vec<String> = batch.iter().map(|&i| texts[i].clone()).collect();
+            let result = self.embedder.embed_batch(&inputs, credentials).await?;
+            total_tokens += result.total_tokens;
+            let mut cache = self.cache.lock().unwrap();
+            for (&i, vector) in batch.iter().zip(result.vectors) {
+                cache.insert(hashes[i], vector.clone());
+                vectors[i] = Some(vector);
+            }
+        }
+        // Repeats of a text share the vector of its first occurrence
+        for i in 0..vectors.len() {
+            if vectors[i].is_none() {
+                let first = hashes.iter().position(|hash| *hash == hashes[i]).unwrap_or(i);
+                vectors[i] = vectors[first].clone();
+            }
+        }
+
+        debug!(
+            "Embedded {} text(s) with {} ({} from cache, {} tokens)",
+            texts.len(),
+            self.model(),
+            texts.len() - missing.len(),
+            total_tokens
+        );
+        Ok(Embeddings {
+            vectors: vectors.into_iter().map(Option::unwrap_or_default).collect(),
+            model: self.model().to_string(),
+            total_tokens,
+            provider: self.embedder.provider(),
+        })
+    }
+}
+
+impl EmbeddingService {
+    // Embed one search query, with what it is billed as.
+    pub async fn embed_query(&self, query: &str, credentials: &ProviderCredentials) -> Result<(QueryVector, Option<ProviderUsage>), EmbedError> {
+        let embeddings = self.embed(&[query.to_string()], credentials).await?;
+        let usage = embeddings.usage();
+        let vector = embeddings.vectors.into_iter().next().unwrap_or_default();
+        Ok((QueryVector { vector, model: embeddings.model }, usage))
+    }
+}
+
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+    let (mut dot, mut norm_a, mut norm_b) = (0.0f32, 0.0f32, 0.0f32);
+    for (x, y) in a.iter().zip(b) {
+        dot += x * y;
+        norm_a += x * x;
+        norm_b += y * y;
+    }
+    if norm_a == 0.0 || norm_b == 0.0 {
+        0.0
+    } else {
+        dot / (norm_a.sqrt() * norm_b.sqrt())
+    }
+}
+
+// Build the configured embedder once at startup; a local model is loaded here.
+pub fn init(config: &Config) -> io::Result<&'static EmbeddingService> {
+    let embedder: Box<dyn Embedder> = match config.embeddings.backend {
+        EmbeddingBackend::Openai => Box::new(HttpEmbedder::from_config(config)),
+        #[cfg(feature = "local-embeddings")]
+        EmbeddingBackend::Local => Box::new(local::LocalEmbedder::load(&config.embeddings.local_model_dir).map_err(io::Error::other)?),
+        // Rejected by config validation
+        #[cfg(not(feature = "local-embeddings"))]
+        EmbeddingBackend::Local => return Err(io::Error::other("built without the local-embeddings feature")),
+    };
+    info!("Embedding with {}", embedder.model());
+    let service = EmbeddingService::new(embedder, config.embeddings.batch_size, config.embeddings.cache_size);
+    Ok(SERVICE.get_or_init(|| service))
+}
+
+pub fn get() -> &'static EmbeddingService {
+    SERVICE.get().expect("embeddings are initialised at startup")
+}
+
+#[cfg(feature = "local-embeddings")]
+mod local {
+    use super::{EmbedError, Embedder, EmbeddingBatch};
+    use crate::tenants::ProviderCredentials;
+
+    use candle_core::{Device, Tensor};
+    use candle_nn::VarBuilder;
+    use candle_transformers::models::bert::{BertModel, Config as BertConfig, DTYPE};
+    use futures::future::BoxFuture;
+    use std::fs;
+    use std::path::Path;
+    use std::sync::Arc;
+    use tokenizers::{PaddingParams, Tokenizer, TruncationParams};
+
+    // A BERT sentence-transformer (e.g. all-MiniLM-L6-v2) with mean pooling, on the CPU.
+    pub struct LocalEmbedder {
+        name: String,
+        model: Arc<BertModel>,
+        tokenizer: Arc<Tokenizer>,
+    }
+
+    impl LocalEmbedder {
+        pub fn load(dir: &Path) -> Result<Self, EmbedError> {
+            let config: BertConfig = serde_json::from_str(&fs::read_to_string(dir.join("config.json"))?)?;
+            let mut tokenizer = Tokenizer::from_file(dir.join("tokenizer.json"))?;
+            tokenizer.with_padding(Some(PaddingParams::default()));
+            tokenizer.with_truncation(Some(TruncationParams { max_length: config.max_position_embeddings, ..Default::default() }))?;
+            // SAFETY: the weights file is not modified while the server runs
+            let weights = unsafe { VarBuilder::from_mmaped_safetensors(&[dir.join("model.safetensors")], DTYPE, &Device::Cpu)? };
+            let model = BertModel::load(weights, &config)?;
+            let name = dir.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+            Ok(LocalEmbedder { name: format!("local/{}", name), model: Arc::new(model), tokenizer: Arc::new(tokenizer) })
+        }
+    }
+
+    // Mean of the token states, ignoring padding, scaled to unit length.
+    fn embed(model: &BertModel, tokenizer: &Tokenizer, texts: Vec<String>) -> Result<EmbeddingBatch, EmbedError> {
+        let encodings = tokenizer.encode_batch(texts, true)?;
+        let ids = encodings.iter().map(|encoding| Tensor::new(encoding.get_ids(), &Device::Cpu)).collect::<Result<Vec<_>, _>>()?;
+        let mask = encodings
+            .iter()
+            .map(|encoding| Tensor::new(encoding.get_attention_mask(), &Device::Cpu))
+            .collect::<Result<Vec<_>, _>>()?;
+        let ids = Tensor::stack(&ids, 0)?;
+        let mask = Tensor::stack(&mask, 0)?;
+
+        let states = model.forward(&ids, &ids.zeros_like()?, Some(&mask))?;
+        let mask = mask.to_dtype(DTYPE)?.unsqueeze(2)?;
+        let mean = states.broadcast_mul(&mask)?.sum(1)?.broadcast_div(&mask.sum(1)?)?;
+        let norm = mean.sqr()?.sum_keepdim(1)?.sqrt()?;
+        let vectors = mean.broadcast_div(&norm)?.to_vec2::<f32>()?;
+
+        let total_tokens = encodings.iter().map(|encoding| encoding.get_attention_mask().iter().sum::<u32>() as u64).sum();
+        Ok(EmbeddingBatch { vectors, total_tokens })
+    }
+
+    impl Embedder for LocalEmbedder {
+        fn model(&self) -> &str {
+            &self.name
+        }
+
+        fn embed_batch<'a>(&'a self, texts: &'a [String], _credentials: &'a ProviderCredentials) -> BoxFuture<'a, Result<EmbeddingBatch, EmbedError>> {
+            let model = self.model.clone();
+            let tokenizer = self.tokenizer.clone();
+            let texts = texts.to_vec();
+            // Inference is CPU-bound; keep it off the async workers
+            Box::pin(async move { tokio::task::spawn_blocking(move || embed(&model, &tokenizer, texts)).await? })
+        }
+    }
+}
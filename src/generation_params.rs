@@ -0,0 +1,153 @@
+// generation_params.rs
+use crate::config::{self, Config};
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+// A chat model we route to: the provider serving it and the largest completion it accepts.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ModelLimits {
+    pub provider: String,
+    pub max_tokens: u32,
+}
+
+// Models known without configuration; `[chat_models]` adds others or changes these.
+const BUILTIN_CHAT_MODELS: &[(&str, &str, u32)] = &[
+    ("mixtral-8x7b-32768", "groq", 4096),
+    ("llama3-8b-8192", "groq", 8192),
+    ("llama3-70b-8192", "groq", 8192),
+    ("gemma-7b-it", "groq", 8192),
+    ("gpt-4o", "openai", 4096),
+    ("gpt-4o-mini", "openai", 16384),
+];
+
+const MAX_STOP_SEQUENCES: usize = 4;
+
+pub fn builtin_model_limits(model: &str) -> Option<ModelLimits> {
+    BUILTIN_CHAT_MODELS
+        .iter()
+        .find(|(name, _, _)| *name == model)
+        .map(|(_, provider, max_tokens)| ModelLimits { provider: provider.to_string(), max_tokens: *max_tokens })
+}
+
+pub fn model_limits(model: &str) -> Option<ModelLimits> {
+    config::get().chat_model(model)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ResponseFormat {
+    Text,
+    JsonObject,
+}
+
+// Optional per-request overrides, as sent by API clients.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct GenerationOverrides {
+    pub model: Option<String>,
+    pub temperature: Option<f32>,
+    pub max_tokens: Option<u32>,
+    pub top_p: Option<f32>,
+    pub stop: Option<Vec<String>>,
+    pub seed: Option<u64>,
+    pub response_format: Option<ResponseFormat>,
+}
+
+// Server-wide defaults, read once at startup.
+#[derive(Debug, Clone)]
+pub struct GenerationDefaults {
+    pub model: String,
+    pub temperature: f32,
+    pub max_tokens: u32,
+    pub top_p: f32,
+}
+
+impl GenerationDefaults {
+    pub fn from_config(config: &Config) -> Self {
+        GenerationDefaults {
+            model: config.models.chat.clone(),
+            temperature: config.generation.temperature,
+            max_tokens: config.generation.max_tokens,
+            top_p: config.generation.top_p,
+        }
+    }
+}
+
+// Fully resolved parameters for one chat completion.
+#[derive(Debug, Clone)]
+pub struct GenerationParams {
+    pub model: String,
+    pub temperature: f32,
+    pub max_tokens: u32,
+    pub top_p: f32,
+    pub stop: Option<Vec<String>>,
+    pub seed: Option<u64>,
+    pub response_format: Option<ResponseFormat>,
+}
+
+impl GenerationParams {
+    // Merge request overrides over the defaults and check them against the model's limits.
+    pub fn resolve(overrides: &GenerationOverrides, defaults: &GenerationDefaults) -> Result<Self, ParamError> {
+        let model = overrides.model.clone().unwrap_or_else(|| defaults.model.clone());
+        let limits = model_limits(&model)
+            .ok_or_else(|| ParamError::new("model", format!("unknown model '{}'", model)))?;
+
+        let temperature = overrides.temperature.unwrap_or(defaults.temperature);
+        if !(0.0..=2.0).contains(&temperature) {
+            return Err(ParamError::new("temperature", "must be between 0 and 2"));
+        }
+
+        let top_p = overrides.top_p.unwrap_or(defaults.top_p);
+        if top_p <= 0.0 || top_p > 1.0 {
+            return Err(ParamError::new("top_p", "must be greater than 0 and at most 1"));
+        }
+
+        let max_tokens = overrides.max_tokens.unwrap_or_else(|| defaults.max_tokens.min(limits.max_tokens));
+        if max_tokens == 0 || max_tokens > limits.max_tokens {
+            return Err(ParamError::new(
+                "max_tokens",
+                format!("must be between 1 and {} for {}", limits.max_tokens, model),
+            ));
+        }
+
+        if let Some(stop) = &overrides.stop {
+            if stop.len() > MAX_STOP_SEQUENCES {
+                return Err(ParamError::new("stop", format!("at most {} sequences are allowed", MAX_STOP_SEQUENCES)));
+            }
+            if stop.iter().any(|sequence| sequence.is_empty()) {
+                return Err(ParamError::new("stop", "sequences must not be empty"));
+            }
+        }
+
+        Ok(GenerationParams {
+            model,
+            temperature,
+            max_tokens,
+            top_p,
+            stop: overrides.stop.clone(),
+            seed: overrides.seed,
+            response_format: overrides.response_format,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct ParamError {
+    pub field: &'static str,
+    pub message: String,
+}
+
+impl ParamError {
+    fn new(field: &'static str, message: impl Into<String>) -> Self {
+        ParamError { field, message: message.into() }
+    }
+}
+
+impl fmt::Display for ParamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid {}: {}", self.field, self.message)
+    }
+}
+
+impl std::error::Error for ParamError {}
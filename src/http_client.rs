@@ -0,0 +1,214 @@
+// http_client.rs
+use crate::chat_error::ErrorDetail;
+use crate::config::HttpConfig;
+use crate::{config, metrics};
+
+use lazy_static::lazy_static;
+use log::{debug, warn};
+use rand::Rng;
+use reqwest::header::RETRY_AFTER;
+use reqwest::{Client, Response, StatusCode};
+use serde_json::Value;
+use std::fmt;
+use std::time::{Duration, Instant, SystemTime};
+use uuid::Uuid;
+
+lazy_static! {
+    static ref POLICY: RetryPolicy = RetryPolicy::from_config(&config::get().http);
+    static ref SHARED_CLIENT: Client = build_client(&POLICY);
+}
+
+// Timeouts and backoff applied to every outbound provider call.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub connect_timeout: Duration,
+    pub request_timeout: Duration,
+    pub stream_timeout: Duration,
+    pub max_retries: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    // Longer Retry-After waits are surfaced to the caller instead of slept through
+    pub max_retry_after: Duration,
+}
+
+impl RetryPolicy {
+    pub fn from_config(http: &HttpConfig) -> Self {
+        RetryPolicy {
+            connect_timeout: Duration::from_secs(http.connect_timeout_secs),
+            request_timeout: Duration::from_secs(http.timeout_secs),
+            stream_timeout: Duration::from_secs(http.stream_timeout_secs),
+            max_retries: http.max_retries,
+            base_delay: Duration::from_millis(http.backoff_base_ms),
+            max_delay: Duration::from_millis(http.backoff_max_ms),
+            max_retry_after: Duration::from_secs(http.max_retry_after_secs),
+        }
+    }
+
+    pub fn global() -> &'static RetryPolicy {
+        &POLICY
+    }
+
+    // Exponential backoff with full jitter; a provider's Retry-After always wins.
+    fn delay(&self, attempt: u32, retry_after: Option<Duration>) -> Duration {
+        if let Some(retry_after) = retry_after {
+            return retry_after;
+        }
+        let ceiling = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay);
+        let jitter = rand::thread_rng().gen_range(0..=ceiling.as_millis() as u64);
+        Duration::from_millis(jitter)
+    }
+}
+
+pub fn build_client(policy: &RetryPolicy) -> Client {
+    Client::builder()
+        .connect_timeout(policy.connect_timeout)
+        .build()
+        .expect("Failed to build HTTP client")
+}
+
+// Client for modules that are not handed one by their caller.
+pub fn shared_client() -> &'static Client {
+    &SHARED_CLIENT
+}
+
+#[derive(Debug)]
+pub enum ProviderError {
+    RateLimited { retry_after: Option<Duration>, detail: ErrorDetail },
+    Auth { status: u16, detail: ErrorDetail },
+    BadRequest { status: u16, detail: ErrorDetail },
+    Server { status: u16, detail: ErrorDetail },
+    Timeout,
+    // The connection was never established, so nothing reached the provider
+    Connect(String),
+    Network(String),
+    // Every provider that could serve the request is cooling down
+    CircuitOpen { providers: String },
+}
+
+impl ProviderError {
+    fn from_status(status: StatusCode, retry_after: Option<Duration>, detail: ErrorDetail) -> Self {
+        let code = status.as_u16();
+        match status {
+            StatusCode::TOO_MANY_REQUESTS => ProviderError::RateLimited { retry_after, detail },
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => ProviderError::Auth { status: code, detail },
+            s if s.is_server_error() => ProviderError::Server { status: code, detail },
+            _ => ProviderError::BadRequest { status: code, detail },
+        }
+    }
+
+    fn from_reqwest(e: reqwest::Error) -> Self {
+        if e.is_timeout() {
+            ProviderError::Timeout
+        } else if e.is_connect() {
+            ProviderError::Connect(e.to_string())
+        } else {
+            ProviderError::Network(e.to_string())
+        }
+    }
+
+    // Whether another attempt could succeed. Calls that are not idempotent are
+    // only retried when the provider certainly did not act on the request.
+    fn is_retryable(&self, idempotent: bool) -> bool {
+        match self {
+            ProviderError::RateLimited { .. } => true,
+            ProviderError::Connect(_) => true,
+            ProviderError::Server { status, .. } => idempotent || *status == 503,
+            ProviderError::Timeout | ProviderError::Network(_) => idempotent,
+            ProviderError::Auth { .. } | ProviderError::BadRequest { .. } | ProviderError::CircuitOpen { .. } => false,
+        }
+    }
+}
+
+impl fmt::Display for ProviderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProviderError::RateLimited { detail, .. } => write!(f, "provider rate limit exceeded: {}", detail),
+            ProviderError::Auth { status, detail } => write!(f, "provider rejected credentials ({}): {}", status, detail),
+            ProviderError::BadRequest { status, detail } => write!(f, "provider rejected request ({}): {}", status, detail),
+            ProviderError::Server { status, detail } => write!(f, "provider server error ({}): {}", status, detail),
+            ProviderError::Timeout => write!(f, "provider request timed out"),
+            ProviderError::Connect(message) => write!(f, "provider unreachable: {}", message),
+            ProviderError::Network(message) => write!(f, "provider connection failed: {}", message),
+            ProviderError::CircuitOpen { providers } => write!(f, "no provider available, circuit open for: {}", providers),
+        }
+    }
+}
+
+impl std::error::Error for ProviderError {}
+
+// One JSON POST to a provider.
+pub struct ProviderRequest<'a> {
+    pub provider: &'static str,
+    pub url: &'a str,
+    pub api_key: &'a str,
+    pub body: &'a Value,
+    // Safe to repeat after the provider may already have acted on it
+    pub idempotent: bool,
+    // The body is read incrementally, so the longer stream timeout applies
+    pub streaming: bool,
+}
+
+// Send a request, retrying transient failures, and return the successful response.
+pub async fn send(client: &Client, request: ProviderRequest<'_>) -> Result<Response, ProviderError> {
+    let policy = RetryPolicy::global();
+    let timeout = if request.streaming { policy.stream_timeout } else { policy.request_timeout };
+    // Kept stable across attempts so providers that honour it can deduplicate
+    let idempotency_key = Uuid::new_v4().to_string();
+    let mut attempt = 0;
+
+    loop {
+        let started = Instant::now();
+        let result = client
+            .post(request.url)
+            .header("Content-Type", "application/json")
+            .header("Authorization", format!("Bearer {}", request.api_key.trim()))
+            .header("Idempotency-Key", &idempotency_key)
+            .timeout(timeout)
+            .json(request.body)
+            .send()
+            .await;
+
+        let error = match result {
+            Ok(response) if response.status().is_success() => {
+                metrics::observe_provider_call(request.provider, started.elapsed(), None);
+                return Ok(response);
+            }
+            Ok(response) => {
+                let status = response.status();
+                let retry_after = parse_retry_after(&response);
+                let body = response.text().await.unwrap_or_default();
+                ProviderError::from_status(status, retry_after, ErrorDetail::parse(request.provider, &body))
+            }
+            Err(e) => ProviderError::from_reqwest(e),
+        };
+        metrics::observe_provider_call(request.provider, started.elapsed(), Some(&error));
+
+        let retry_after = match &error {
+            ProviderError::RateLimited { retry_after, .. } => *retry_after,
+            _ => None,
+        };
+        let wait_too_long = retry_after.is_some_and(|wait| wait > policy.max_retry_after);
+
+        if attempt >= policy.max_retries || wait_too_long || !error.is_retryable(request.idempotent) {
+            warn!("{} request failed after {} attempt(s): {}", request.provider, attempt + 1, error);
+            return Err(error);
+        }
+
+        let delay = policy.delay(attempt, retry_after);
+        debug!("{} request failed ({}), retrying in {:?}", request.provider, error, delay);
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+fn parse_retry_after(response: &Response) -> Option<Duration> {
+    let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?;
+    if let Ok(seconds) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(date.duration_since(SystemTime::now()).unwrap_or_default())
+}
@@ -1,6 +1,19 @@
-use reqwest::Client;
+use crate::http_client::{self, ProviderRequest};
+use crate::chat_error::ChatError;
+use crate::config;
+use crate::language;
+use crate::interaction::{ProviderUsage, Route};
+use crate::tenants::ProviderCredentials;
+use crate::tools::{Tool, ToolContext, ToolError, ToolOutput};
+use futures::future::LocalBoxFuture;
+use serde_json::{json, Value};
 use serde::{Deserialize, Serialize};
-use std::env;
+use whatlang::Lang;
+
+const IMAGES_URL: &str = "https://api.openai.com/v1/images/generations";
+
+// Sizes DALL-E 3 accepts; the first is used unless the model picks another
+pub const IMAGE_SIZES: &[&str] = &["1024x1024", "1792x1024", "1024x1792"];
 
 #[derive(Serialize)]
 struct CreateImageRequest {
@@ -24,31 +37,111 @@ fn generation_prompt(user_input: &str) -> String {
     format!("Create a visually stunning and detailed image based on the following prompt: {}", user_input)
 }
 
-pub async fn generate_image(user_input: &str) -> Result<String, Box<dyn std::error::Error>> {
-    let api_key = env::var("OPENAI_API_KEY").expect("OPENAI_API_KEY not set");
-    let client = Client::new();
+pub struct GeneratedImage {
+    pub url: String,
+    pub model: String,
+    // Translating the prompt to English, when that took a provider call
+    pub translation: Option<ProviderUsage>,
+}
+
+impl GeneratedImage {
+    // Every provider call that went into the image, to be billed with it.
+    pub fn usage(&self) -> Vec<ProviderUsage> {
+        let image = ProviderUsage { provider: "openai".to_string(), model: self.model.clone(), tokens: None, images: 1 };
+        self.translation.iter().cloned().chain(std::iter::once(image)).collect()
+    }
+}
+
+pub async fn generate_image(user_input: &str, size: &str, language: Option<Lang>, credentials: &ProviderCredentials) -> Result<GeneratedImage, Box<dyn std::error::Error>> {
+    let config = config::get();
+    let openai = &config.providers.openai;
+    let api_key = credentials.api_key("openai").ok_or_else(|| ChatError::NotConfigured { provider: "openai".to_string() })?;
+    let model = &config.models.image;
 
-    let prompt = generation_prompt(user_input);
+    let (prompt, translation) = language::image_prompt(user_input, language, credentials).await;
+    let prompt = generation_prompt(&prompt);
 
     let request = CreateImageRequest {
         prompt,
         n: 1,
-        size: "1024x1024".to_string(),
-        model: "dall-e-3".to_string(), // Specify DALL-E 3 model
+        size: size.to_string(),
+        model: model.clone(),
     };
 
-    let response = client.post("https://api.openai.com/v1/images/generations")
-        .header("Authorization", format!("Bearer {}", api_key))
-        .json(&request)
-        .send()
-        .await?
-        .json::<CreateImageResponse>()
-        .await?;
+    // Every accepted generation is billed, so only retry when the provider certainly did not act
+    let body = serde_json::to_value(&request)?;
+    let response = http_client::send(http_client::shared_client(), ProviderRequest {
+        provider: "openai",
+        url: openai.images_url.as_deref().unwrap_or(IMAGES_URL),
+        api_key,
+        body: &body,
+        idempotent: false,
+        streaming: false,
+    })
+    .await?
+    .json::<CreateImageResponse>()
+    .await
+    .map_err(|e| ChatError::InvalidResponse { provider: "openai".to_string(), message: e.to_string() })?;
 
     if let Some(image_data) = response.data.first() {
-        Ok(image_data.url.clone())
+        Ok(GeneratedImage {
+            url: image_data.url.clone(),
+            model: model.clone(),
+            translation,
+        })
     } else {
-        Err("No image URL returned".into())
+        Err(ChatError::EmptyResponse { provider: "openai".to_string() }.into())
     }
 }
 
+
+// Image generation offered to the chat model, so one answer can combine text and images.
+pub struct GenerateImageTool;
+
+#[derive(Deserialize)]
+struct GenerateImageArguments {
+    prompt: String,
+    size: Option<String>,
+}
+
+impl Tool for GenerateImageTool {
+    fn name(&self) -> &str {
+        "generate_image"
+    }
+
+    fn description(&self) -> &str {
+        "Create an image from a detailed description and show it to the user. Returns the image URL."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "prompt": { "type": "string", "description": "What the image shows: subject, setting, style, lighting" },
+                "size": { "type": "string", "enum": IMAGE_SIZES, "description": "Square, landscape or portrait" }
+            },
+            "required": ["prompt"],
+        })
+    }
+
+    fn route(&self) -> Route {
+        Route::Generate
+    }
+
+    fn call<'a>(&'a self, arguments: Value, context: &'a ToolContext<'a>) -> LocalBoxFuture<'a, Result<ToolOutput, ToolError>> {
+        Box::pin(async move {
+            let arguments: GenerateImageArguments = serde_json::from_value(arguments)?;
+            let size = arguments.size.as_deref().unwrap_or(IMAGE_SIZES[0]);
+            if !IMAGE_SIZES.contains(&size) {
+                return Err(format!("size must be one of {}", IMAGE_SIZES.join(", ")).into());
+            }
+            let language = language::detect(&arguments.prompt);
+            let image = generate_image(&arguments.prompt, size, language, &context.tenant.credentials).await?;
+            Ok(ToolOutput {
+                content: json!({ "url": image.url, "note": "The image is shown to the user; do not repeat the URL." }).to_string(),
+                usage: image.usage(),
+                image_urls: vec![image.url],
+            })
+        })
+    }
+}
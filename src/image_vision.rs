@@ -1,7 +1,17 @@
-use reqwest::Client;
 use serde::{Deserialize, Serialize};
-use std::env;
 use log::{info, debug, error};
+use crate::interaction::TokenUsage;
+use crate::http_client::{self, ProviderRequest};
+use crate::chat_error::ChatError;
+use crate::provider_chain::{self, ChainEntry};
+use crate::redaction;
+use crate::tenants::ProviderCredentials;
+use crate::interaction::{ProviderUsage, Route};
+use crate::tools::{Tool, ToolContext, ToolError, ToolOutput};
+use futures::future::LocalBoxFuture;
+use serde_json::{json, Value};
+
+const DEFAULT_INSTRUCTION: &str = "Analyze the image in a conversational and friendly manner, explaining your analysis with short details, ensure responses concise. Ensure that your responses are properly formatted with bolds and bullet points when they make sense";
 
 #[derive(Serialize, Debug)]
 struct AnalyzeImageRequest {
@@ -9,20 +19,20 @@ struct AnalyzeImageRequest {
     messages: Vec<Message>,
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Debug, Clone)]
 struct Message {
     role: String,
     content: Vec<Content>,
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Debug, Clone)]
 #[serde(untagged)]
 enum Content {
     Text { r#type: String, text: String },
     ImageUrl { r#type: String, image_url: ImageUrl },
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Debug, Clone)]
 struct ImageUrl {
     url: String,
 }
@@ -30,7 +40,7 @@ struct ImageUrl {
 #[derive(Deserialize, Debug)]
 struct AnalyzeImageResponse {
     choices: Vec<Choice>,
-    usage: Option<Usage>,
+    usage: Option<TokenUsage>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -43,34 +53,22 @@ struct MessageResponse {
     content: String,
 }
 
-#[derive(Deserialize, Debug)]
-struct Usage {
-    prompt_tokens: usize,
-    completion_tokens: usize,
-    total_tokens: usize,
-}
-
-#[derive(Deserialize, Debug)]
-struct OpenAIErrorResponse {
-    error: OpenAIError,
+pub struct ImageAnalysis {
+    pub content: String,
+    pub provider: String,
+    pub model: String,
+    pub usage: Option<TokenUsage>,
 }
 
-#[derive(Deserialize, Debug)]
-struct OpenAIError {
-    message: String,
-}
-
-pub async fn analyze_image(image_url: &str) -> Result<String, Box<dyn std::error::Error>> {
-    let api_key = env::var("OPENAI_API_KEY").expect("OPENAI_API_KEY not set");
-    let client = Client::new();
-
+// The question defaults to a general description of the image.
+pub async fn analyze_image(image_url: &str, question: Option<&str>, credentials: &ProviderCredentials) -> Result<ImageAnalysis, Box<dyn std::error::Error>> {
     let messages = vec![
         Message {
             role: "user".to_string(),
             content: vec![
                 Content::Text {
                     r#type: "text".to_string(),
-                    text: "Analyze the image in a conversational and friendly manner, explaining your analysis with short details, ensure responses concise. Ensure that your responses are properly formatted with bolds and bullet points when they make sense".to_string(),
+                    text: question.unwrap_or(DEFAULT_INSTRUCTION).to_string(),
                 },
                 Content::ImageUrl {
                     r#type: "image_url".to_string(),
@@ -82,45 +80,108 @@ pub async fn analyze_image(image_url: &str) -> Result<String, Box<dyn std::error
         },
     ];
 
-    let request = AnalyzeImageRequest {
-        model: "gpt-4o".to_string(),
-        messages,
-    };
+    let chain = provider_chain::vision_chain(credentials);
+    let (analyze_response, served_by) = provider_chain::call_with_fallback(&chain, |entry| {
+        let request = AnalyzeImageRequest {
+            model: entry.model.clone(),
+            messages: messages.clone(),
+        };
+        async move { send_analyze_request(entry, &request).await }
+    }).await?;
+
+    if let Some(usage) = &analyze_response.usage {
+        info!("Prompt tokens: {}", usage.prompt_tokens);
+        info!("Completion tokens: {}", usage.completion_tokens);
+        info!("Total tokens: {}", usage.total_tokens);
+    }
 
-    debug!("Sending analyze image request: {:?}", request);
+    if let Some(choice) = analyze_response.choices.first() {
+        Ok(ImageAnalysis {
+            content: choice.message.content.clone(),
+            provider: served_by.provider.name.to_string(),
+            model: served_by.model.clone(),
+            usage: analyze_response.usage,
+        })
+    } else {
+        Err(ChatError::EmptyResponse { provider: served_by.provider.name.to_string() }.into())
+    }
+}
 
-    let response = client.post("https://api.openai.com/v1/chat/completions")
-        .header("Authorization", format!("Bearer {}", api_key))
-        .header("Content-Type", "application/json")
-        .json(&request)
-        .send()
-        .await?;
+async fn send_analyze_request(entry: &ChainEntry, request: &AnalyzeImageRequest) -> Result<AnalyzeImageResponse, Box<dyn std::error::Error>> {
+    debug!("Sending analyze image request: {}", redaction::content(&format!("{:?}", request)));
+
+    let body = serde_json::to_value(request)?;
+    let response = http_client::send(http_client::shared_client(), ProviderRequest {
+        provider: entry.provider.name,
+        url: entry.provider.chat_url(),
+        api_key: &entry.api_key,
+        body: &body,
+        idempotent: true,
+        streaming: false,
+    }).await.map_err(|e| {
+        error!("API Error: {}", e);
+        e
+    })?;
 
     debug!("Received response: {:?}", response);
 
-    let status = response.status();
     let response_text = response.text().await?;
-    debug!("Response text: {}", response_text);
-
-    if status.is_success() {
-        let analyze_response: AnalyzeImageResponse = serde_json::from_str(&response_text)?;
-        debug!("Parsed response: {:?}", analyze_response);
-
-        if let Some(usage) = &analyze_response.usage {
-            info!("Prompt tokens: {}", usage.prompt_tokens);
-            info!("Completion tokens: {}", usage.completion_tokens);
-            info!("Total tokens: {}", usage.total_tokens);
-        }
-
-        if let Some(choice) = analyze_response.choices.first() {
-            Ok(choice.message.content.clone())
-        } else {
-            Err("No analysis response returned".into())
-        }
-    } else {
-        let error_response: OpenAIErrorResponse = serde_json::from_str(&response_text)?;
-        error!("API Error: {:?}", error_response.error);
-        Err(error_response.error.message.into())
-    }
+    debug!("Response text: {}", redaction::content(&response_text));
+
+    let analyze_response: AnalyzeImageResponse = serde_json::from_str(&response_text).map_err(|e| ChatError::InvalidResponse {
+        provider: entry.provider.name.to_string(),
+        message: e.to_string(),
+    })?;
+    debug!("Parsed response: {}", redaction::content(&format!("{:?}", analyze_response)));
+    Ok(analyze_response)
+}
+
+// Image analysis offered to the chat model, for images the user links or the model generated.
+pub struct AnalyzeImageTool;
+
+#[derive(Deserialize)]
+struct AnalyzeImageArguments {
+    image_url: String,
+    question: Option<String>,
 }
 
+impl Tool for AnalyzeImageTool {
+    fn name(&self) -> &str {
+        "analyze_image"
+    }
+
+    fn description(&self) -> &str {
+        "Look at an image and answer a question about it, or describe it when no question is given."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "image_url": { "type": "string", "description": "http(s) URL of the image, e.g. one the user shared" },
+                "question": { "type": "string", "description": "What to find out about the image" }
+            },
+            "required": ["image_url"],
+        })
+    }
+
+    fn route(&self) -> Route {
+        Route::Vision
+    }
+
+    fn call<'a>(&'a self, arguments: Value, context: &'a ToolContext<'a>) -> LocalBoxFuture<'a, Result<ToolOutput, ToolError>> {
+        Box::pin(async move {
+            let arguments: AnalyzeImageArguments = serde_json::from_value(arguments)?;
+            if !(arguments.image_url.starts_with("https://") || arguments.image_url.starts_with("http://")) {
+                return Err("image_url must be an http(s) URL".into());
+            }
+            let question = arguments.question.as_deref().map(str::trim).filter(|question| !question.is_empty());
+            let analysis = analyze_image(&arguments.image_url, question, &context.tenant.credentials).await?;
+            Ok(ToolOutput {
+                content: analysis.content,
+                image_urls: vec![arguments.image_url],
+                usage: vec![ProviderUsage { provider: analysis.provider, model: analysis.model, tokens: analysis.usage, images: 0 }],
+            })
+        })
+    }
+}
@@ -1,35 +1,54 @@
 // input_process.rs
-use crate::triggers_generate;
-use crate::dotenv;
-use crate::session_manager::SessionManager;
+use crate::config;
 use crate::url_handler::handle_url;
 use crate::trigger_handler::handle_trigger;
 use crate::context_manager::manage_context::ContextManager;
 use crate::context_manager::manage_context::MAX_CONTEXT_MESSAGES;
-use crate::system_prompt::SYSTEM_PROMPT;
+use crate::personas::Persona;
+use crate::embeddings;
+use crate::knowledge_base;
+use crate::language;
+use crate::memory;
+use crate::tenants::Tenant;
+use crate::prompt_template::PromptVariables;
+use crate::interaction::{EventSender, Interaction, Route, StreamEvent, TokenUsage};
+use crate::generation_params::GenerationParams;
+use crate::http_client::{self, ProviderRequest};
+use crate::chat_error::ChatError;
+use crate::provider_chain::{self, ChainEntry};
+use crate::redaction;
+use crate::tools::{self, ToolCall, ToolContext};
 
 use serde_json::map::Map;
 use serde_json::Value;
 use reqwest::Client;
-use log::{info, debug, error};
+use log::{info, debug, error, warn};
 use serde_json::json;
 use uuid::Uuid;
 use std::net::IpAddr;
+use futures::StreamExt;
+use whatlang::Lang;
 
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(name = "interaction", skip_all, fields(session_id = %session_id, tenant = %tenant.id, persona = %persona.name, route = tracing::field::Empty))]
 pub async fn process_user_input(
     user_input: String,
-    session_manager: &mut SessionManager,
+    routing: Routing,
+    session_id: Uuid,
     client: &Client,
-    groq_api_key: &str,
+    tenant: &Tenant,
     ip_addr: IpAddr,
-) -> Result<String, Box<dyn std::error::Error>> {
-    dotenv().ok();
-    info!("Processing user input: {}", user_input);
+    persona: &Persona,
+    prompt: &PromptVariables,
+    params: &GenerationParams,
+    user_id: Option<&str>,
+    events: Option<&EventSender>,
+) -> Result<Interaction, Box<dyn std::error::Error>> {
+    info!("Processing user input: {}", redaction::content(&user_input));
 
-    let session_id = session_manager.create_session(ip_addr);
     info!("Session ID: {}", session_id);
 
-    let mut context_manager = ContextManager::new();
+    let mut context_manager = ContextManager::new(tenant.sessions_dir());
     match context_manager.load_context(&session_id).await {
         Ok(_) => info!("Context loaded successfully"),
         Err(e) => eprintln!("Error loading context: {}", e),
@@ -40,40 +59,84 @@ pub async fn process_user_input(
     user_message.insert("content".to_string(), Value::from(user_input.clone()));
     user_message.insert("r#type".to_string(), Value::from("text"));
     // Process user input
-    info!("Processing user input: {}", user_input);
+    info!("Processing user input: {}", redaction::content(&user_input));
 
-    if let Some(url) = crate::url_handler::contains_url(&user_input) {
-        let result = handle_url(url, &mut context_manager, ip_addr, &session_id).await;
-        match context_manager.save_context(&session_id).await {
-            Ok(_) => info!("Context stored successfully"),
-            Err(e) => error!("Error saving context: {}", e),
+    let language = language::record(&tenant.sessions_dir(), &session_id, routing.language).await;
+    let route = routing.route;
+    tracing::Span::current().record("route", route.as_str());
+    crate::metrics::record_route(route);
+    let result = match route {
+        Route::Vision => {
+            let url = crate::url_handler::contains_url(&user_input).unwrap_or_default();
+            handle_url(url, &mut context_manager, ip_addr, &session_id, &tenant.credentials).await
         }
-        return result;
-    } else if triggers_generate::contains_trigger_word(&user_input) {
-        let result = handle_trigger(&user_input, &mut context_manager, ip_addr, &session_id).await;
-        match context_manager.save_context(&session_id).await {
-            Ok(_) => info!("Context stored successfully"),
-            Err(e) => error!("Error saving context: {}", e),
-        }
-        return result;
+        Route::Generate => handle_trigger(&user_input, &mut context_manager, ip_addr, &session_id, &tenant.credentials, language, events).await,
+        Route::Chat => process_text_input(&user_input, &mut context_manager, client, tenant, &session_id, persona, prompt, params, user_id, language, events).await,
+    };
+    let result = result.map(|interaction| Interaction { language: language.map(|language| language.code().to_string()), ..interaction });
+    match context_manager.save_context(&session_id).await {
+        Ok(_) => info!("Context stored successfully"),
+        Err(e) => error!("Error saving context: {}", e),
+    }
+    if let Ok(interaction) = &result {
+        crate::metrics::record_interaction(interaction);
+    }
+    result
+}
+
+// How a message is handled, decided once per message before any provider is called.
+#[derive(Debug, Clone, Copy)]
+pub struct Routing {
+    pub route: Route,
+    // The language the message is written in, when it can be told
+    pub language: Option<Lang>,
+}
+
+// Decide which pipeline handles a message. Shared with the API layer so it can
+// check the caller's scopes before any provider is called. Disabled features, and
+// capabilities the persona lacks, fall back to chat. Trigger words are the tenant's.
+// Capabilities the chat model can reach as tools are left to the model to use.
+pub fn route_for(user_input: &str, persona: &Persona, tenant: &Tenant) -> Routing {
+    let language = language::detect(user_input);
+    let route = pipeline_for(user_input, language, persona, tenant);
+    Routing { route, language }
+}
+
+fn pipeline_for(user_input: &str, language: Option<Lang>, persona: &Persona, tenant: &Tenant) -> Route {
+    let features = &config::get().features;
+    let by_model = |route| features.tools && tools::get().handles(route);
+    if features.vision
+        && persona.allows(Route::Vision)
+        && !by_model(Route::Vision)
+        && crate::url_handler::contains_url(user_input).is_some()
+    {
+        Route::Vision
+    } else if features.image_generation
+        && persona.allows(Route::Generate)
+        && !by_model(Route::Generate)
+        && tenant.is_trigger(user_input, language)
+    {
+        Route::Generate
     } else {
-        let result = process_text_input(&user_input, &mut context_manager, client, groq_api_key, &session_id).await;
-        match context_manager.save_context(&session_id).await {
-            Ok(_) => info!("Context stored successfully"),
-            Err(e) => error!("Error saving context: {}", e),
-        }
-        return result;
+        Route::Chat
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn process_text_input(
     user_input: &str,
     context_manager: &mut ContextManager,
     client: &Client,
-    groq_api_key: &str,
+    tenant: &Tenant,
     session_id: &Uuid,
-) -> Result<String, Box<dyn std::error::Error>> {
-    info!("No URL or trigger word detected. Processing text input: {}", user_input);
+    persona: &Persona,
+    prompt: &PromptVariables,
+    params: &GenerationParams,
+    user_id: Option<&str>,
+    language: Option<Lang>,
+    events: Option<&EventSender>,
+) -> Result<Interaction, Box<dyn std::error::Error>> {
+    info!("No URL or trigger word detected. Processing text input: {}", redaction::content(user_input));
 
     // Retrieve context messages
     let context_messages = context_manager.get_context(session_id).await;
@@ -88,16 +151,60 @@ pub async fn process_text_input(
         }
     }
 
+    // Rendered for every request so the date, user and tools are current
+    let system_prompt = persona.system_prompt(prompt)?;
+
     let mut payload_messages = vec![];
     if!system_message_exists {
         let mut system_message = Map::new();
         system_message.insert("role".to_string(), Value::from("system"));
-        system_message.insert("content".to_string(), Value::from(SYSTEM_PROMPT));
+        system_message.insert("content".to_string(), Value::from(system_prompt));
         context_manager.add_message(IpAddr::V4("95.94.61.253".parse().unwrap()), serde_json::Value::Object(system_message.clone())).await;
         payload_messages.push(serde_json::Value::Object(system_message.clone()));
     } else {
-        for message in context_messages.clone() {
-            payload_messages.push(message.clone());
+        for mut message in context_messages.clone() {
+            if message.get("role").and_then(Value::as_str) == Some("system") {
+                message["content"] = Value::from(system_prompt.as_str());
+            }
+            payload_messages.push(message);
+        }
+    }
+
+    // The message is embedded once for both searches and billed with the reply. When that
+    // fails, chat carries on without excerpts or memories.
+    let mut auxiliary = Vec::new();
+    let search_knowledge = knowledge_base::is_searched(tenant);
+    let recall_for = user_id.filter(|_| config::get().features.memory);
+    let query = if search_knowledge || recall_for.is_some() {
+        match embeddings::get().embed_query(user_input, &tenant.credentials).await {
+            Ok((query, usage)) => {
+                auxiliary.extend(usage);
+                Some(query)
+            }
+            Err(e) => {
+                warn!("Searches skipped for tenant {}: {}", tenant.id, e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    // Excerpts only go to the provider; the stored context keeps just the conversation
+    let sources = match &query {
+        Some(query) if search_knowledge => knowledge_base::retrieve(tenant, query),
+        _ => Vec::new(),
+    };
+    if !sources.is_empty() {
+        payload_messages.push(json!({ "role": "system", "content": knowledge_base::context_message(&sources) }));
+    }
+    if let Some(language) = language {
+        payload_messages.push(json!({ "role": "system", "content": language::instruction(language) }));
+    }
+    if let (Some(user_id), Some(query)) = (recall_for, &query) {
+        let memories = memory::recall(tenant, user_id, query);
+        if !memories.is_empty() {
+            payload_messages.push(json!({ "role": "system", "content": memory::context_message(&memories) }));
         }
     }
 
@@ -108,78 +215,282 @@ pub async fn process_text_input(
     context_manager.add_message(IpAddr::V4("95.94.61.253".parse().unwrap()), serde_json::Value::Object(user_message.clone())).await;
     payload_messages.push(serde_json::Value::Object(user_message.clone()));
 
-    let payload = json!({
-        "model": "mixtral-8x7b-32768",
+    let mut payload = json!({
+        "model": params.model,
         "messages": payload_messages,
-        "temperature": 0.5,
-        "max_tokens": 4000,
-        "top_p": 1,
-        "stop": null,
+        "temperature": params.temperature,
+        "max_tokens": params.max_tokens,
+        "top_p": params.top_p,
+        "stop": params.stop,
         "stream": false,
     });
+    if let Some(seed) = params.seed {
+        payload["seed"] = json!(seed);
+    }
+    if let Some(response_format) = params.response_format {
+        payload["response_format"] = json!({ "type": response_format });
+    }
 
-    debug!("Prepared payload for API request: {:?}", payload);
+    debug!("Prepared payload for API request: {}", redaction::content(&payload.to_string()));
 
     // Trim the context if it exceeds the maximum length
     context_manager.trim_context(session_id).await;
     debug!("Trimmed context messages to {}", MAX_CONTEXT_MESSAGES);
 
-    // Send the request to the Groq API
-    let response = client
-       .post("https://api.groq.com/openai/v1/chat/completions")
-       .header("Content-Type", "application/json")
-       .header("Authorization", format!("Bearer {}", &groq_api_key.trim()))
-       .json(&payload)
-       .send()
-       .await;
-
-    match response {
-        Ok(resp) => {
-            debug!("Received response from Groq API");
-            let body = resp.text().await?;
-            debug!("Groq API response body: {}", body);
-            let json: Value = serde_json::from_str(&body)?;
-            debug!("Received and parsed response from Groq API");
-
-            if let Some(choices) = json["choices"].as_array() {
-                if let Some(choice) = choices.get(0) {
-                    if let Some(message) = choice.get("message") {
-                        if let Some(content) = message.get("content") {
-                            let content = content.as_str().unwrap_or("");
-                            println!("\nFANA:\n{}", content);
-                            info!("FANA response: {}", content);
-
-                            // Add the assistant message to the context
-                            let mut assistant_message = Map::new();
-                            assistant_message.insert("role".to_string(), Value::from("assistant"));
-                            assistant_message.insert("content".to_string(), Value::from(content));
-                            context_manager.add_message(IpAddr::V4("95.94.61.253".parse().unwrap()), serde_json::Value::Object(assistant_message)).await;
-                            info!("Added assistant message to context for session {}", session_id);
-                            debug!("Added assistant message to context");
-
-                            // Log token usage
-                            if let Some(usage) = json["usage"].as_object() {
-                                let prompt_tokens = usage.get("prompt_tokens").and_then(Value::as_u64).unwrap_or(0);
-                                let completion_tokens = usage.get("completion_tokens").and_then(Value::as_u64).unwrap_or(0);
-                                let total_tokens = usage.get("total_tokens").and_then(Value::as_u64).unwrap_or(0);
-                                info!("Token usage - Prompt tokens: {}, Completion tokens: {}, Total tokens: {}", prompt_tokens, completion_tokens, total_tokens);
-                            }
-
-                            // Return the content of the assistant's response
-                            return Ok(content.to_string());
-                        }
-                    }
+    // Send the request down the provider chain, streaming tokens when someone is listening.
+    // With tools on, the model may call them over several rounds before it answers; calls
+    // and results go to the provider only and are recorded in the session's tool_calls.jsonl
+    let offered = tools::for_request(tenant, &prompt.routes);
+    let offer_tools = !offered.is_empty();
+    if offer_tools {
+        payload["tools"] = json!(tools::definitions(&offered));
+    }
+    let max_rounds = config::get().tools.max_iterations;
+    let tool_context = ToolContext { tenant, session_id: *session_id, routes: &prompt.routes };
+    let mut tool_calls = Vec::new();
+    let mut usage: Option<TokenUsage> = None;
+    let mut round = 0;
+    let chain = provider_chain::chat_chain(&params.model, &tenant.credentials);
+    let (completion, served_by) = loop {
+        if offer_tools {
+            // Out of rounds, the model has to answer with what it has
+            payload["tool_choice"] = json!(if round < max_rounds { "auto" } else { "none" });
+        }
+        let (completion, served_by) = provider_chain::call_with_fallback(&chain, |entry| {
+            let mut payload = payload.clone();
+            payload["model"] = json!(entry.model);
+            payload["max_tokens"] = json!(params.max_tokens.min(entry.max_tokens));
+            async move {
+                match events {
+                    Some(events) => stream_chat_completion(client, entry, payload, events).await,
+                    None => send_chat_completion(client, entry, &payload).await,
                 }
             }
-            error!("Failed to parse Groq API response");
-            return Ok("".to_string());
+        }).await?;
+        if let Some(round_usage) = completion.usage {
+            let total = usage.get_or_insert_with(TokenUsage::default);
+            total.prompt_tokens += round_usage.prompt_tokens;
+            total.completion_tokens += round_usage.completion_tokens;
+            total.total_tokens += round_usage.total_tokens;
+        }
+        if completion.tool_calls.is_empty() || round >= max_rounds || events.is_some_and(|events| events.is_closed()) {
+            break (completion, served_by);
+        }
+        round += 1;
+
+        info!("Model requested {} tool call(s) in round {} for session {}", completion.tool_calls.len(), round, session_id);
+        let mut messages = vec![json!({
+            "role": "assistant",
+            "content": (!completion.content.is_empty()).then_some(completion.content.as_str()),
+            "tool_calls": completion.tool_calls.iter().map(ToolCall::to_message).collect::<Vec<_>>(),
+        })];
+        for call in &completion.tool_calls {
+            if let Some(events) = events {
+                let _ = events.send(StreamEvent::ToolCall(call.name.clone()));
+            }
+            let (output, record) = tools::execute(call, &tool_context).await;
+            messages.push(json!({ "role": "tool", "tool_call_id": call.id, "content": output }));
+            tool_calls.push(record);
         }
-        Err(e) => {
-            error!("Error sending request to Groq API: {:?}", e);
-            return Err(e.into());
+        if let Some(payload_messages) = payload["messages"].as_array_mut() {
+            payload_messages.extend(messages);
         }
+    };
+    // A cancelled generation keeps whatever it produced, so its usage is still counted
+    let cancelled = events.is_some_and(|events| events.is_closed());
+    if completion.content.is_empty() && !cancelled {
+        error!("{} kept calling tools after {} rounds without answering", served_by.provider.name, max_rounds);
+        return Err(ChatError::EmptyResponse { provider: served_by.provider.name.to_string() }.into());
+    }
+
+    info!("FANA response: {}", redaction::content(&completion.content));
+
+    // Add the assistant message to the context
+    if !completion.content.is_empty() {
+        let mut assistant_message = Map::new();
+        assistant_message.insert("role".to_string(), Value::from("assistant"));
+        assistant_message.insert("content".to_string(), Value::from(completion.content.clone()));
+        context_manager.add_message(IpAddr::V4("95.94.61.253".parse().unwrap()), serde_json::Value::Object(assistant_message)).await;
+        info!("Added assistant message to context for session {}", session_id);
+        debug!("Added assistant message to context");
     }
+
+    // Log token usage
+    if let Some(usage) = &usage {
+        info!("Token usage - Prompt tokens: {}, Completion tokens: {}, Total tokens: {}", usage.prompt_tokens, usage.completion_tokens, usage.total_tokens);
+    }
+
+    // Return the content of the assistant's response
+    Ok(Interaction {
+        session_id: *session_id,
+        route: Route::Chat,
+        provider: served_by.provider.name.to_string(),
+        model: served_by.model.clone(),
+        content: completion.content,
+        usage,
+        image_urls: tool_calls.iter().flat_map(|call| call.image_urls.iter().cloned()).collect(),
+        sources,
+        language: None,
+        tool_calls,
+        auxiliary,
+    })
+}
+
+pub(crate) struct ChatCompletion {
+    pub content: String,
+    pub usage: Option<TokenUsage>,
+    // Only when tools were offered
+    pub tool_calls: Vec<ToolCall>,
 }
 
+pub(crate) async fn send_chat_completion(
+    client: &Client,
+    entry: &ChainEntry,
+    payload: &Value,
+) -> Result<ChatCompletion, Box<dyn std::error::Error>> {
+    let response = http_client::send(client, ProviderRequest {
+        provider: entry.provider.name,
+        url: entry.provider.chat_url(),
+        api_key: &entry.api_key,
+        body: payload,
+        idempotent: true,
+        streaming: false,
+    })
+    .await
+    .map_err(|e| {
+        error!("Error sending chat completion request: {}", e);
+        e
+    })?;
+
+    debug!("Received response from {}", entry.provider.name);
+    let body = response.text().await?;
+    debug!("{} response body: {}", entry.provider.name, redaction::content(&body));
+    let json: Value = serde_json::from_str(&body).map_err(|e| ChatError::InvalidResponse {
+        provider: entry.provider.name.to_string(),
+        message: e.to_string(),
+    })?;
+    debug!("Received and parsed response from {}", entry.provider.name);
+
+    let content = json["choices"][0]["message"]["content"].as_str().unwrap_or("");
+    let tool_calls = parse_tool_calls(&json["choices"][0]["message"]["tool_calls"]);
+    if content.is_empty() && tool_calls.is_empty() {
+        error!("{} returned no content: {}", entry.provider.name, redaction::content(&body));
+        return Err(ChatError::EmptyResponse { provider: entry.provider.name.to_string() }.into());
+    }
 
+    Ok(ChatCompletion {
+        content: content.to_string(),
+        usage: serde_json::from_value::<TokenUsage>(json["usage"].clone()).ok(),
+        tool_calls,
+    })
+}
+
+// Request a server-sent event stream and forward each content delta as it arrives. When the
+// listener goes away (the client cancelled) the stream is cut short between chunks, and
+// what was generated so far is returned with estimated usage, since it is billed anyway.
+async fn stream_chat_completion(
+    client: &Client,
+    entry: &ChainEntry,
+    mut payload: Value,
+    events: &EventSender,
+) -> Result<ChatCompletion, Box<dyn std::error::Error>> {
+    payload["stream"] = json!(true);
+    if entry.provider.name == "openai" {
+        payload["stream_options"] = json!({ "include_usage": true });
+    }
+
+    let response = http_client::send(client, ProviderRequest {
+        provider: entry.provider.name,
+        url: entry.provider.chat_url(),
+        api_key: &entry.api_key,
+        body: &payload,
+        idempotent: true,
+        streaming: true,
+    }).await.map_err(|e| {
+        error!("Error sending chat completion request: {}", e);
+        e
+    })?;
+    debug!("Receiving streamed response from {}", entry.provider.name);
+
+    let mut stream = response.bytes_stream();
+    // Raw bytes, decoded a line at a time so characters split across chunks stay whole
+    let mut buffer: Vec<u8> = Vec::new();
+    let mut content = String::new();
+    let mut usage = None;
+    let mut tool_calls: Vec<ToolCall> = Vec::new();
+
+    'stream: while let Some(chunk) = stream.next().await {
+        if events.is_closed() {
+            info!("Stopping the stream from {}: nobody is listening", entry.provider.name);
+            return Ok(ChatCompletion {
+                usage: Some(usage.unwrap_or_else(|| TokenUsage::estimate(&payload["messages"], &content))),
+                content,
+                tool_calls: Vec::new(),
+            });
+        }
+        buffer.extend_from_slice(&chunk?);
+        while let Some(newline) = buffer.iter().position(|byte| *byte == b'\n') {
+            let line: Vec<u8> = buffer.drain(..=newline).collect();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim();
 
+            let Some(data) = line.strip_prefix("data:") else { continue };
+            let data = data.trim();
+            if data == "[DONE]" {
+                break 'stream;
+            }
+
+            let json: Value = serde_json::from_str(data).map_err(|e| ChatError::InvalidResponse {
+                provider: entry.provider.name.to_string(),
+                message: e.to_string(),
+            })?;
+            if let Some(delta) = json["choices"][0]["delta"]["content"].as_str() {
+                content.push_str(delta);
+                // The listener may have gone away; keep collecting so the context stays complete
+                let _ = events.send(StreamEvent::Token(delta.to_string()));
+            }
+            // Tool calls arrive in pieces: the id and name first, then fragments of the arguments
+            for delta in json["choices"][0]["delta"]["tool_calls"].as_array().into_iter().flatten() {
+                let index = delta["index"].as_u64().unwrap_or(0) as usize;
+                if tool_calls.len() <= index {
+                    tool_calls.resize_with(index + 1, || ToolCall { id: String::new(), name: String::new(), arguments: String::new() });
+                }
+                let call = &mut tool_calls[index];
+                if let Some(id) = delta["id"].as_str() {
+                    call.id = id.to_string();
+                }
+                if let Some(name) = delta["function"]["name"].as_str() {
+                    call.name.push_str(name);
+                }
+                if let Some(arguments) = delta["function"]["arguments"].as_str() {
+                    call.arguments.push_str(arguments);
+                }
+            }
+            // Groq reports usage on the final chunk under `x_groq`, OpenAI under `usage`
+            let chunk_usage = if json["x_groq"]["usage"].is_object() { &json["x_groq"]["usage"] } else { &json["usage"] };
+            if let Ok(chunk_usage) = serde_json::from_value::<TokenUsage>(chunk_usage.clone()) {
+                usage = Some(chunk_usage);
+            }
+        }
+    }
+
+    if content.is_empty() && tool_calls.is_empty() {
+        error!("{} streamed no content", entry.provider.name);
+        return Err(ChatError::EmptyResponse { provider: entry.provider.name.to_string() }.into());
+    }
+    Ok(ChatCompletion { content, usage, tool_calls })
+}
+
+// The `tool_calls` of a complete assistant message.
+fn parse_tool_calls(tool_calls: &Value) -> Vec<ToolCall> {
+    tool_calls
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|call| ToolCall {
+            id: call["id"].as_str().unwrap_or_default().to_string(),
+            name: call["function"]["name"].as_str().unwrap_or_default().to_string(),
+            arguments: call["function"]["arguments"].as_str().unwrap_or_default().to_string(),
+        })
+        .collect()
+}
@@ -0,0 +1,111 @@
+// interaction.rs
+use crate::knowledge_base::Citation;
+use crate::tools::ToolCallRecord;
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use tokio::sync::mpsc::UnboundedSender;
+
+// Which pipeline handled the user message.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Route {
+    Chat,
+    Vision,
+    Generate,
+}
+
+impl Route {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Route::Chat => "chat",
+            Route::Vision => "vision",
+            Route::Generate => "generate",
+        }
+    }
+}
+
+// Token usage as reported by OpenAI-compatible providers.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct TokenUsage {
+    #[serde(default)]
+    pub prompt_tokens: u64,
+    #[serde(default)]
+    pub completion_tokens: u64,
+    #[serde(default)]
+    pub total_tokens: u64,
+}
+
+impl TokenUsage {
+    // About four characters a token; for streams stopped before the provider reported usage.
+    pub fn estimate(prompt: &serde_json::Value, completion: &str) -> Self {
+        let prompt_tokens = prompt.to_string().len().div_ceil(4) as u64;
+        let completion_tokens = completion.len().div_ceil(4) as u64;
+        TokenUsage { prompt_tokens, completion_tokens, total_tokens: prompt_tokens + completion_tokens }
+    }
+}
+
+// A provider call made besides the interaction's own model (by a tool, or in the
+// background), billed alongside it.
+#[derive(Debug, Clone)]
+pub struct ProviderUsage {
+    pub provider: String,
+    pub model: String,
+    pub tokens: Option<TokenUsage>,
+    pub images: u64,
+}
+
+// Outcome of processing one user message, independent of how it is delivered
+// (console, plaintext or JSON response).
+#[derive(Serialize, Debug, Clone)]
+pub struct Interaction {
+    pub session_id: Uuid,
+    pub route: Route,
+    pub provider: String,
+    pub model: String,
+    pub content: String,
+    pub usage: Option<TokenUsage>,
+    pub image_urls: Vec<String>,
+    // Knowledge base excerpts the answer was given
+    pub sources: Vec<Citation>,
+    // ISO 639-3 code of the language the user wrote in, when it could be told
+    pub language: Option<String>,
+    // Tools the model called on the way to its answer
+    pub tool_calls: Vec<ToolCallRecord>,
+    // Other provider calls made for the message, such as translating an image prompt
+    #[serde(skip)]
+    pub auxiliary: Vec<ProviderUsage>,
+}
+
+impl Interaction {
+    // Provider work done by tools and auxiliary calls on top of the interaction's own model.
+    pub fn extra_usage(&self) -> impl Iterator<Item = &ProviderUsage> {
+        self.tool_calls.iter().flat_map(|call| call.usage.iter()).chain(self.auxiliary.iter())
+    }
+
+    // Images created for the user, which count against image quotas.
+    pub fn generated_images(&self) -> u64 {
+        let own = match self.route {
+            Route::Generate => self.image_urls.len() as u64,
+            Route::Chat | Route::Vision => 0,
+        };
+        own + self.extra_usage().map(|usage| usage.images).sum::<u64>()
+    }
+
+    // Tokens of the interaction's model, its tools and auxiliary calls.
+    pub fn total_tokens(&self) -> u64 {
+        let own = self.usage.as_ref().map(|usage| usage.total_tokens).unwrap_or(0);
+        own + self.extra_usage().filter_map(|usage| usage.tokens.as_ref()).map(|tokens| tokens.total_tokens).sum::<u64>()
+    }
+}
+
+// Incremental progress pushed to streaming clients while an interaction runs.
+#[derive(Debug, Clone)]
+pub enum StreamEvent {
+    Token(String),
+    ImageProgress(&'static str),
+    // A tool the model called is running
+    ToolCall(String),
+}
+
+pub type EventSender = UnboundedSender<StreamEvent>;
@@ -0,0 +1,163 @@
+// jwt_auth.rs
+use crate::api_keys::{AuthFailure, AuthenticatedKey, KeyLimits, Scope};
+use crate::config::AuthConfig;
+use crate::tenants::DEFAULT_TENANT;
+
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use log::{info, warn};
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use uuid::Uuid;
+
+// Verifies user tokens issued by our identity provider. Enabled when auth.jwt_hs256_secret
+// and/or auth.jwt_jwks_file (RS256 public keys) is set; the audience is then required.
+pub struct JwtVerifier {
+    hs256_key: Option<DecodingKey>,
+    jwks: JwkSet,
+    audience: String,
+    issuer: Option<String>,
+    scope_claim: String,
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    sub: String,
+    // Session of the end user, if the token carries one
+    #[serde(default)]
+    sid: Option<String>,
+    #[serde(default)]
+    persona: Option<String>,
+    // Checked against the configured tenants by the auth middleware
+    #[serde(default)]
+    tenant: Option<String>,
+    #[serde(flatten)]
+    extra: HashMap<String, Value>,
+}
+
+impl JwtVerifier {
+    pub fn from_config(auth: &AuthConfig) -> io::Result<Option<Self>> {
+        let hs256_key = auth.jwt_hs256_secret.as_ref().map(|secret| DecodingKey::from_secret(secret.as_bytes()));
+        let jwks = match &auth.jwt_jwks_file {
+            Some(path) => {
+                let contents = fs::read_to_string(path)?;
+                serde_json::from_str::<JwkSet>(&contents)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))?
+            }
+            None => JwkSet { keys: Vec::new() },
+        };
+        if hs256_key.is_none() && jwks.keys.is_empty() {
+            return Ok(None);
+        }
+
+        // Checked when the configuration is validated
+        let audience = auth.jwt_audience.clone().unwrap_or_default();
+        info!(
+            "JWT authentication enabled (HS256: {}, RS256 keys: {}, audience: {})",
+            hs256_key.is_some(),
+            jwks.keys.len(),
+            audience
+        );
+        Ok(Some(JwtVerifier {
+            hs256_key,
+            jwks,
+            audience,
+            issuer: auth.jwt_issuer.clone(),
+            scope_claim: auth.jwt_scope_claim.clone(),
+        }))
+    }
+
+    // API keys are opaque strings; anything shaped like a compact JWS is treated as a token.
+    pub fn looks_like_jwt(token: &str) -> bool {
+        token.split('.').count() == 3 && token.starts_with("eyJ")
+    }
+
+    pub fn verify(&self, token: &str) -> Result<AuthenticatedKey, AuthFailure> {
+        let header = decode_header(token).map_err(|e| invalid("malformed header", e))?;
+        // Only accept the algorithm that matches the configured key type
+        let key = match header.alg {
+            Algorithm::HS256 => self.hs256_key.clone().ok_or(AuthFailure::InvalidToken)?,
+            Algorithm::RS256 => self.rs256_key(header.kid.as_deref())?,
+            other => {
+                info!("Rejected JWT signed with unsupported algorithm {:?}", other);
+                return Err(AuthFailure::InvalidToken);
+            }
+        };
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_audience(&[&self.audience]);
+        validation.set_required_spec_claims(&["exp", "sub", "aud"]);
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+
+        let claims = decode::<Claims>(token, &key, &validation)
+            .map_err(|e| match e.kind() {
+                ErrorKind::ExpiredSignature => AuthFailure::TokenExpired,
+                _ => invalid("rejected", e),
+            })?
+            .claims;
+
+        let scopes = self.scopes(&claims);
+        let session = claims.sid.as_deref().map(|sid| session_uuid(&claims.sub, sid));
+        let tenant = claims.tenant.unwrap_or_else(|| DEFAULT_TENANT.to_string());
+        // Subjects are only unique within a tenant
+        let id = if tenant == DEFAULT_TENANT { format!("jwt:{}", claims.sub) } else { format!("jwt:{}:{}", tenant, claims.sub) };
+        Ok(AuthenticatedKey {
+            id,
+            name: claims.sub.clone(),
+            subject: Some(claims.sub),
+            scopes,
+            limits: KeyLimits::default(),
+            session,
+            persona: claims.persona,
+            tenant,
+        })
+    }
+
+    fn rs256_key(&self, kid: Option<&str>) -> Result<DecodingKey, AuthFailure> {
+        let jwk = match kid {
+            Some(kid) => self.jwks.find(kid),
+            // Without a key id we can only pick if there is no ambiguity
+            None if self.jwks.keys.len() == 1 => self.jwks.keys.first(),
+            None => None,
+        }
+        .ok_or(AuthFailure::InvalidToken)?;
+        DecodingKey::from_jwk(jwk).map_err(|e| invalid("unusable JWK", e))
+    }
+
+    // The scope claim may be an OAuth-style space separated string or an array; names
+    // we don't know are ignored.
+    fn scopes(&self, claims: &Claims) -> Vec<Scope> {
+        let names: Vec<String> = match claims.extra.get(&self.scope_claim) {
+            Some(Value::String(scopes)) => scopes.split_whitespace().map(String::from).collect(),
+            Some(Value::Array(scopes)) => scopes.iter().filter_map(|scope| scope.as_str().map(String::from)).collect(),
+            _ => Vec::new(),
+        };
+        names
+            .iter()
+            .filter_map(|name| serde_json::from_value::<Scope>(Value::String(name.clone())).ok())
+            .collect()
+    }
+}
+
+fn invalid(reason: &str, e: jsonwebtoken::errors::Error) -> AuthFailure {
+    warn!("Rejected JWT ({}): {}", reason, e);
+    AuthFailure::InvalidToken
+}
+
+// Session ids are UUIDs internally; other `sid` values map to a stable UUID per user.
+fn session_uuid(sub: &str, sid: &str) -> Uuid {
+    if let Ok(session) = Uuid::parse_str(sid) {
+        return session;
+    }
+    let digest = Sha256::digest(format!("{}\n{}", sub, sid).as_bytes());
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(&digest[..16]);
+    uuid::Builder::from_random_bytes(bytes).into_uuid()
+}
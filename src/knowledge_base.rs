@@ -0,0 +1,555 @@
+// knowledge_base.rs
+use crate::api_keys::now;
+use crate::config;
+use crate::embeddings::{self, cosine_similarity, QueryVector};
+use crate::interaction::ProviderUsage;
+use crate::tenants::{Tenant, TenantRegistry, DEFAULT_TENANT};
+
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+const SUPPORTED_EXTENSIONS: &[&str] = &["md", "markdown", "txt", "text", "pdf"];
+
+// One embedded piece of a document.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct StoredChunk {
+    document: String,
+    chunk: usize,
+    // Nearest Markdown heading above the chunk
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    heading: Option<String>,
+    text: String,
+    // Vectors of different models can't be compared, so each chunk records its own
+    model: String,
+    ingested_at: u64,
+    embedding: Vec<f32>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct DocumentSummary {
+    pub name: String,
+    pub chunks: usize,
+    pub characters: usize,
+    pub model: String,
+    pub ingested_at: u64,
+}
+
+// Where an excerpt in the prompt came from; returned to clients alongside the answer.
+#[derive(Serialize, Debug, Clone)]
+pub struct Citation {
+    // As cited in the prompt, e.g. [1]
+    pub index: usize,
+    pub document: String,
+    pub chunk: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub heading: Option<String>,
+    pub score: f32,
+    #[serde(skip)]
+    pub text: String,
+}
+
+#[derive(Debug)]
+pub enum KnowledgeError {
+    InvalidName(String),
+    UnsupportedFormat(String),
+    TooLarge { limit: usize },
+    Unreadable(String),
+    Empty,
+    Embedding(String),
+    Io(io::Error),
+}
+
+impl fmt::Display for KnowledgeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KnowledgeError::InvalidName(name) => write!(f, "'{}' is not a valid document name", name),
+            KnowledgeError::UnsupportedFormat(name) => {
+                write!(f, "{}: unsupported format, use {}", name, SUPPORTED_EXTENSIONS.join(", "))
+            }
+            KnowledgeError::TooLarge { limit } => write!(f, "document exceeds {} bytes", limit),
+            KnowledgeError::Unreadable(message) => write!(f, "could not read document: {}", message),
+            KnowledgeError::Empty => write!(f, "document contains no text"),
+            KnowledgeError::Embedding(message) => write!(f, "embedding failed: {}", message),
+            KnowledgeError::Io(e) => write!(f, "failed to store document: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for KnowledgeError {}
+
+impl From<io::Error> for KnowledgeError {
+    fn from(e: io::Error) -> Self {
+        KnowledgeError::Io(e)
+    }
+}
+
+// A tenant's embedded documents (chunks.jsonl in its knowledge directory), held in memory
+// and searched exhaustively; re-ingesting a document replaces its chunks.
+pub struct KnowledgeStore {
+    path: PathBuf,
+    chunks: RwLock<Vec<Arc<StoredChunk>>>,
+    // Held for a whole change, so changes are written in the order they are made
+    writer: tokio::sync::Mutex<()>,
+}
+
+impl fmt::Debug for KnowledgeStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KnowledgeStore")
+            .field("path", &self.path)
+            .field("chunks", &self.chunks.read().unwrap().len())
+            .finish()
+    }
+}
+
+impl KnowledgeStore {
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        let path = dir.join("chunks.jsonl");
+        let mut chunks = Vec::new();
+        if path.exists() {
+            let reader = BufReader::new(fs::File::open(&path)?);
+            for (number, line) in reader.lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match serde_json::from_str::<StoredChunk>(&line) {
+                    Ok(chunk) => chunks.push(Arc::new(chunk)),
+                    Err(e) => error!("Skipping malformed knowledge chunk at {}:{}: {}", path.display(), number + 1, e),
+                }
+            }
+            info!("Loaded {} knowledge chunks from {}", chunks.len(), path.display());
+        }
+        Ok(KnowledgeStore { path, chunks: RwLock::new(chunks), writer: tokio::sync::Mutex::new(()) })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chunks.read().unwrap().is_empty()
+    }
+
+    pub fn documents(&self) -> Vec<DocumentSummary> {
+        let mut documents: BTreeMap<&str, DocumentSummary> = BTreeMap::new();
+        let chunks = self.chunks.read().unwrap();
+        for chunk in chunks.iter() {
+            let summary = documents.entry(&chunk.document).or_insert_with(|| DocumentSummary {
+                name: chunk.document.clone(),
+                chunks: 0,
+                characters: 0,
+                model: chunk.model.clone(),
+                ingested_at: chunk.ingested_at,
+            });
+            summary.chunks += 1;
+            summary.characters += chunk.text.chars().count();
+        }
+        documents.into_values().collect()
+    }
+
+    // Every chunk not of the named document.
+    fn without(&self, name: &str) -> Vec<Arc<StoredChunk>> {
+        self.chunks.read().unwrap().iter().filter(|chunk| chunk.document != name).cloned().collect()
+    }
+
+    async fn replace_document(&self, name: &str, new_chunks: Vec<StoredChunk>) -> io::Result<()> {
+        let _writer = self.writer.lock().await;
+        let mut updated = self.without(name);
+        updated.extend(new_chunks.into_iter().map(Arc::new));
+        let updated = self.save(updated).await?;
+        *self.chunks.write().unwrap() = updated;
+        Ok(())
+    }
+
+    // Whether the document existed.
+    pub async fn delete_document(&self, name: &str) -> io::Result<bool> {
+        let _writer = self.writer.lock().await;
+        let updated = self.without(name);
+        if updated.len() == self.chunks.read().unwrap().len() {
+            return Ok(false);
+        }
+        let updated = self.save(updated).await?;
+        *self.chunks.write().unwrap() = updated;
+        Ok(true)
+    }
+
+    // The closest chunks embedded with the same model, best first.
+    fn search(&self, query: &[f32], model: &str, top_k: usize, min_score: f32) -> Vec<Citation> {
+        let chunks = self.chunks.read().unwrap();
+        let mut scored: Vec<(f32, &Arc<StoredChunk>)> = chunks
+            .iter()
+            .filter(|chunk| chunk.model == model)
+            .map(|chunk| (cosine_similarity(query, &chunk.embedding), chunk))
+            .filter(|(score, _)| *score >= min_score)
+            .collect();
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+        scored
+            .into_iter()
+            .take(top_k)
+            .enumerate()
+            .map(|(i, (score, chunk))| Citation {
+                index: i + 1,
+                document: chunk.document.clone(),
+                chunk: chunk.chunk,
+                heading: chunk.heading.clone(),
+                score,
+                text: chunk.text.clone(),
+            })
+            .collect()
+    }
+
+    // Rewrite chunks.jsonl on a blocking thread, off the locks searches take, and hand the
+    // chunks back once they are on disk.
+    async fn save(&self, chunks: Vec<Arc<StoredChunk>>) -> io::Result<Vec<Arc<StoredChunk>>> {
+        let path = self.path.clone();
+        tokio::task::spawn_blocking(move || {
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            let mut contents = String::new();
+            for chunk in &chunks {
+                contents.push_str(&serde_json::to_string(chunk.as_ref())?);
+                contents.push('\n');
+            }
+            let tmp_path = path.with_extension("jsonl.tmp");
+            fs::write(&tmp_path, contents)?;
+            fs::rename(&tmp_path, &path)?;
+            Ok(chunks)
+        })
+        .await
+        .map_err(io::Error::other)?
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct IngestReport {
+    pub document: String,
+    pub chunks: usize,
+    pub characters: usize,
+    pub embedding_tokens: u64,
+    // Billed to the key that ingested the document
+    #[serde(skip)]
+    pub usage: Option<ProviderUsage>,
+}
+
+// Names are relative paths such as `guides/setup.md`; the extension picks the parser.
+fn validate_name(name: &str) -> Result<(), KnowledgeError> {
+    let valid = !name.is_empty()
+        && name.len() <= 255
+        && !name.starts_with('/')
+        && !name.contains('\\')
+        && name.split('/').all(|part| !part.is_empty() && part != "." && part != "..");
+    if !valid {
+        return Err(KnowledgeError::InvalidName(name.to_string()));
+    }
+    let extension = Path::new(name).extension().and_then(|extension| extension.to_str()).map(str::to_lowercase);
+    match extension {
+        Some(extension) if SUPPORTED_EXTENSIONS.contains(&extension.as_str()) => Ok(()),
+        _ => Err(KnowledgeError::UnsupportedFormat(name.to_string())),
+    }
+}
+
+fn extract_text(name: &str, bytes: Vec<u8>) -> Result<String, KnowledgeError> {
+    if name.to_lowercase().ends_with(".pdf") {
+        pdf_extract::extract_text_from_mem(&bytes).map_err(|e| KnowledgeError::Unreadable(e.to_string()))
+    } else {
+        String::from_utf8(bytes).map_err(|_| KnowledgeError::Unreadable(format!("{} is not UTF-8 text", name)))
+    }
+}
+
+// Parse, chunk and embed a document into the tenant's knowledge base.
+pub async fn ingest(tenant: &Tenant, name: &str, bytes: Vec<u8>) -> Result<IngestReport, KnowledgeError> {
+    let rag = &config::get().rag;
+    validate_name(name)?;
+    if bytes.len() > rag.max_document_bytes {
+        return Err(KnowledgeError::TooLarge { limit: rag.max_document_bytes });
+    }
+
+    // PDF parsing is CPU-bound and panics on some malformed files
+    let owned_name = name.to_string();
+    let text = tokio::task::spawn_blocking(move || extract_text(&owned_name, bytes))
+        .await
+        .map_err(|_| KnowledgeError::Unreadable("the parser failed on this file".to_string()))??;
+    let pieces = chunk_text(&text, rag.chunk_size, rag.chunk_overlap);
+    if pieces.is_empty() {
+        return Err(KnowledgeError::Empty);
+    }
+
+    let texts: Vec<String> = pieces.iter().map(|piece| piece.text.clone()).collect();
+    let embeddings = embeddings::get()
+        .embed(&texts, &tenant.credentials)
+        .await
+        .map_err(|e| KnowledgeError::Embedding(e.to_string()))?;
+
+    let usage = embeddings.usage();
+    let ingested_at = now();
+    let characters = texts.iter().map(|text| text.chars().count()).sum();
+    let chunks: Vec<StoredChunk> = pieces
+        .into_iter()
+        .zip(embeddings.vectors)
+        .enumerate()
+        .map(|(i, (piece, embedding))| StoredChunk {
+            document: name.to_string(),
+            chunk: i,
+            heading: piece.heading,
+            text: piece.text,
+            model: embeddings.model.clone(),
+            ingested_at,
+            embedding,
+        })
+        .collect();
+    let count = chunks.len();
+    tenant.knowledge.replace_document(name, chunks).await?;
+    info!("Ingested {} into the knowledge base of tenant {} ({} chunks)", name, tenant.id, count);
+
+    Ok(IngestReport { document: name.to_string(), chunks: count, characters, embedding_tokens: embeddings.total_tokens, usage })
+}
+
+// Whether messages are searched against the tenant's knowledge base: retrieval is on and
+// there is something to find.
+pub fn is_searched(tenant: &Tenant) -> bool {
+    config::get().features.rag && !tenant.knowledge.is_empty()
+}
+
+// Excerpts relevant to the embedded message, best first.
+pub fn retrieve(tenant: &Tenant, query: &QueryVector) -> Vec<Citation> {
+    let config = config::get();
+    let citations = tenant.knowledge.search(&query.vector, &query.model, config.rag.top_k, config.rag.min_score);
+    info!("Retrieved {} knowledge base excerpt(s) for tenant {}", citations.len(), tenant.id);
+    citations
+}
+
+// The system message that hands the excerpts to the model.
+pub fn context_message(citations: &[Citation]) -> String {
+    let mut message = String::from(
+        "Excerpts from the knowledge base that may help answer the next message. \
+         Use them when they are relevant, cite them as [n] after the statements they support, \
+         and do not claim they say anything they don't.\n",
+    );
+    for citation in citations {
+        message.push_str(&format!("\n[{}] {}", citation.index, citation.document));
+        if let Some(heading) = &citation.heading {
+            message.push_str(&format!(" — {}", heading));
+        }
+        message.push_str(&format!("\n{}\n", citation.text));
+    }
+    message
+}
+
+pub struct TextChunk {
+    pub heading: Option<String>,
+    pub text: String,
+}
+
+// Split text into chunks of about `size` characters along paragraph boundaries, each
+// starting with the last `overlap` characters of the previous one. Markdown headings
+// start a new chunk and are remembered for citations.
+pub fn chunk_text(text: &str, size: usize, overlap: usize) -> Vec<TextChunk> {
+    let mut chunks = Vec::new();
+    let mut heading: Option<String> = None;
+    let mut current = String::new();
+    // Whether `current` holds more than the overlap carried over from the previous chunk
+    let mut has_new_text = false;
+
+    let text = text.replace("\r\n", "\n");
+    for paragraph in text.split("\n\n").map(str::trim).filter(|paragraph| !paragraph.is_empty()) {
+        let mut body = paragraph;
+        if let Some((line, title, rest)) = markdown_heading(paragraph) {
+            if has_new_text {
+                chunks.push(TextChunk { heading: heading.clone(), text: current.clone() });
+            }
+            // The heading opens the next chunk but is not worth a chunk of its own
+            current = line.to_string();
+            has_new_text = false;
+            heading = Some(title.to_string());
+            body = rest;
+        }
+        // Room is left for the overlap carried into the next chunk
+        for piece in split_long(body, size - overlap) {
+            if has_new_text && current.chars().count() + piece.chars().count() + 2 > size {
+                chunks.push(TextChunk { heading: heading.clone(), text: current.clone() });
+                current = tail(&current, overlap).to_string();
+            }
+            if !current.is_empty() {
+                current.push_str("\n\n");
+            }
+            current.push_str(piece);
+            has_new_text = true;
+        }
+    }
+    if has_new_text {
+        chunks.push(TextChunk { heading, text: current });
+    }
+    chunks
+}
+
+// The heading line, its title and the text below it.
+fn markdown_heading(paragraph: &str) -> Option<(&str, &str, &str)> {
+    let (line, rest) = paragraph.split_once('\n').unwrap_or((paragraph, ""));
+    let title = line.trim_start_matches('#');
+    (line.starts_with('#') && title.starts_with(' ')).then(|| (line, title.trim(), rest.trim()))
+}
+
+// Pieces of at most `size` characters, cut at whitespace where possible.
+fn split_long(paragraph: &str, size: usize) -> Vec<&str> {
+    let mut pieces = Vec::new();
+    let mut rest = paragraph;
+    while rest.chars().count() > size {
+        let end = rest.char_indices().nth(size).map(|(i, _)| i).unwrap_or(rest.len());
+        let cut = rest[..end].rfind(char::is_whitespace).filter(|&i| i > 0).unwrap_or(end);
+        pieces.push(rest[..cut].trim_end());
+        rest = rest[cut..].trim_start();
+    }
+    if !rest.is_empty() {
+        pieces.push(rest);
+    }
+    pieces
+}
+
+// The last `max` characters of the text, starting at a word.
+fn tail(text: &str, max: usize) -> &str {
+    let count = text.chars().count();
+    if count <= max {
+        return text;
+    }
+    let start = text.char_indices().nth(count - max).map(|(i, _)| i).unwrap_or(0);
+    let rest = &text[start..];
+    match rest.find(char::is_whitespace) {
+        Some(i) => rest[i..].trim_start(),
+        None => rest,
+    }
+}
+
+// `fanallmrust ingest [--tenant <id>] <path>...`: add files, or every supported file
+// under a directory, to a tenant's knowledge base and exit.
+pub async fn run_ingest_command(args: &[String], tenants: &TenantRegistry) -> io::Result<()> {
+    let mut tenant_id = DEFAULT_TENANT.to_string();
+    let mut paths = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--tenant" => match args.next() {
+                Some(id) => tenant_id = id.clone(),
+                None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "--tenant needs a tenant id")),
+            },
+            _ => paths.push(PathBuf::from(arg)),
+        }
+    }
+    if paths.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "usage: fanallmrust ingest [--tenant <id>] <path>..."));
+    }
+    let tenant = tenants
+        .get(&tenant_id)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("Unknown tenant '{}'", tenant_id)))?;
+
+    let mut failures = 0;
+    for path in paths {
+        let mut files = Vec::new();
+        collect_files(&path, &path, &mut files)?;
+        if files.is_empty() {
+            println!("{}: no supported documents found", path.display());
+        }
+        for (file, name) in files {
+            let result = match fs::read(&file) {
+                Ok(bytes) => ingest(&tenant, &name, bytes).await,
+                Err(e) => Err(KnowledgeError::Io(e)),
+            };
+            match result {
+                Ok(report) => println!("{}: {} chunks, {} tokens", report.document, report.chunks, report.embedding_tokens),
+                Err(e) => {
+                    failures += 1;
+                    println!("{}: {}", name, e);
+                }
+            }
+        }
+    }
+    if failures > 0 {
+        return Err(io::Error::other(format!("{} document(s) could not be ingested", failures)));
+    }
+    Ok(())
+}
+
+// Files under `root` with a supported extension, named by their path relative to it.
+fn collect_files(root: &Path, path: &Path, files: &mut Vec<(PathBuf, String)>) -> io::Result<()> {
+    if path.is_dir() {
+        let mut entries: Vec<PathBuf> = fs::read_dir(path)?.map(|entry| entry.map(|entry| entry.path())).collect::<io::Result<_>>()?;
+        entries.sort();
+        for entry in entries {
+            collect_files(root, &entry, files)?;
+        }
+        return Ok(());
+    }
+
+    let name = if path == root {
+        path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default()
+    } else {
+        let relative = path.strip_prefix(root).unwrap_or(path);
+        relative.components().map(|part| part.as_os_str().to_string_lossy()).collect::<Vec<_>>().join("/")
+    };
+    let supported = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| SUPPORTED_EXTENSIONS.contains(&extension.to_lowercase().as_str()));
+    if supported {
+        files.push((path.to_path_buf(), name));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_relative_names_of_supported_formats() {
+        assert!(validate_name("guide.md").is_ok());
+        assert!(validate_name("manuals/Setup.PDF").is_ok());
+        for name in ["", "/etc/notes.md", "../notes.md", "docs/./notes.md", "docs//notes.md", "docs\\notes.md"] {
+            assert!(matches!(validate_name(name), Err(KnowledgeError::InvalidName(_))), "{:?}", name);
+        }
+        assert!(matches!(validate_name(&format!("{}.md", "a".repeat(255))), Err(KnowledgeError::InvalidName(_))));
+        assert!(matches!(validate_name("slides.pptx"), Err(KnowledgeError::UnsupportedFormat(_))));
+        assert!(matches!(validate_name("README"), Err(KnowledgeError::UnsupportedFormat(_))));
+    }
+
+    #[test]
+    fn splits_long_paragraphs_at_whitespace() {
+        assert_eq!(split_long("one two three four", 9), vec!["one two", "three", "four"]);
+        assert_eq!(split_long("abcdefghij", 4), vec!["abcd", "efgh", "ij"]);
+        assert_eq!(split_long("short", 10), vec!["short"]);
+        assert!(split_long("", 10).is_empty());
+        assert!(split_long("çãé õü ñ", 3).iter().all(|piece| piece.chars().count() <= 3));
+    }
+
+    #[test]
+    fn tail_starts_at_a_word() {
+        assert_eq!(tail("short", 10), "short");
+        assert_eq!(tail("the quick brown fox", 8), "fox");
+        assert_eq!(tail("the quick brown fox", 10), "brown fox");
+        assert_eq!(tail("unbroken", 3), "ken");
+        assert_eq!(tail("não há pão", 7), "há pão");
+    }
+
+    #[test]
+    fn chunks_carry_overlap_and_headings() {
+        let text = "Intro paragraph here.\r\n\r\n# Setup\nInstall the app.\n\nThen sign in to continue.\n\n## Billing\nPlans renew monthly.";
+        let chunks = chunk_text(text, 40, 10);
+        let headings: Vec<Option<&str>> = chunks.iter().map(|chunk| chunk.heading.as_deref()).collect();
+        assert_eq!(headings, vec![None, Some("Setup"), Some("Setup"), Some("Billing")]);
+        assert_eq!(chunks[0].text, "Intro paragraph here.");
+        assert_eq!(chunks[1].text, "# Setup\n\nInstall the app.");
+        // The next chunk opens with the end of the previous one
+        assert_eq!(chunks[2].text, "the app.\n\nThen sign in to continue.");
+        assert_eq!(chunks[3].text, "## Billing\n\nPlans renew monthly.");
+        assert!(chunks.iter().all(|chunk| chunk.text.chars().count() <= 40));
+    }
+
+    #[test]
+    fn headings_alone_make_no_chunks() {
+        assert!(chunk_text("# Title\n\n## Empty section", 100, 10).is_empty());
+        assert!(chunk_text("  \n\n  ", 100, 10).is_empty());
+        assert_eq!(chunk_text("#hashtag is not a heading", 100, 10)[0].heading, None);
+    }
+}
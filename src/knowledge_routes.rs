@@ -0,0 +1,122 @@
+// knowledge_routes.rs
+use crate::api_keys::{AuthenticatedKey, Scope};
+use crate::audit_log::AuditLog;
+use crate::config;
+use crate::interaction::Route;
+use crate::knowledge_base::{self, KnowledgeError};
+use crate::rate_limit::QuotaTracker;
+use crate::tenants::Tenant;
+use crate::usage_ledger::UsageLedger;
+
+use actix_web::{web, HttpResponse, Responder};
+use log::{error, info};
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+use uuid::Uuid;
+
+#[derive(Deserialize)]
+struct IngestQuery {
+    // e.g. `guides/setup.md`; the extension picks the parser
+    name: String,
+}
+
+// Set Knowledge Routes (nested under /api). Documents are managed per tenant by its admins.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/knowledge/documents")
+            .route("", web::get().to(list_documents))
+            .route("", web::post().to(ingest_document))
+            .route("/{name:.*}", web::delete().to(delete_document))
+    );
+}
+
+fn error_response(mut builder: actix_web::HttpResponseBuilder, code: &str, message: String) -> HttpResponse {
+    builder.json(json!({ "error": { "code": code, "message": message } }))
+}
+
+fn forbidden_unless_admin(auth: &AuthenticatedKey) -> Option<HttpResponse> {
+    if auth.has_scope(Scope::Admin) {
+        None
+    } else {
+        info!("Key {} ({}) attempted to manage the knowledge base without the admin scope", auth.id, auth.name);
+        Some(error_response(HttpResponse::Forbidden(), "insufficient_scope", "This API key is not allowed to manage the knowledge base".to_string()))
+    }
+}
+
+fn knowledge_error(e: KnowledgeError) -> HttpResponse {
+    match e {
+        KnowledgeError::InvalidName(_) => error_response(HttpResponse::BadRequest(), "invalid_parameter", e.to_string()),
+        KnowledgeError::UnsupportedFormat(_) => error_response(HttpResponse::UnsupportedMediaType(), "unsupported_format", e.to_string()),
+        KnowledgeError::TooLarge { .. } => error_response(HttpResponse::PayloadTooLarge(), "document_too_large", e.to_string()),
+        KnowledgeError::Unreadable(_) | KnowledgeError::Empty => {
+            error_response(HttpResponse::UnprocessableEntity(), "unreadable_document", e.to_string())
+        }
+        KnowledgeError::Embedding(_) => error_response(HttpResponse::BadGateway(), "embedding_failed", e.to_string()),
+        KnowledgeError::Io(_) => {
+            error!("{}", e);
+            error_response(HttpResponse::InternalServerError(), "storage_failed", e.to_string())
+        }
+    }
+}
+
+async fn list_documents(auth: web::ReqData<AuthenticatedKey>, tenant: web::ReqData<Arc<Tenant>>) -> impl Responder {
+    if let Some(response) = forbidden_unless_admin(&auth) {
+        return response;
+    }
+    HttpResponse::Ok().json(json!({ "documents": tenant.knowledge.documents() }))
+}
+
+// The raw document is the request body; re-ingesting a name replaces the document.
+// Embedding it is billed to the key, outside any session. The body is only read once the
+// key is known to be allowed to ingest.
+async fn ingest_document(
+    query: web::Query<IngestQuery>,
+    body: web::Payload,
+    audit_log: web::Data<AuditLog>,
+    quotas: web::Data<QuotaTracker>,
+    usage_ledger: web::Data<UsageLedger>,
+    auth: web::ReqData<AuthenticatedKey>,
+    tenant: web::ReqData<Arc<Tenant>>,
+) -> impl Responder {
+    if let Some(response) = forbidden_unless_admin(&auth) {
+        return response;
+    }
+    let limit = config::get().rag.max_document_bytes;
+    let body = match body.to_bytes_limited(limit).await {
+        Ok(Ok(body)) => body,
+        Ok(Err(e)) => return error_response(HttpResponse::BadRequest(), "invalid_parameter", e.to_string()),
+        Err(_) => return knowledge_error(KnowledgeError::TooLarge { limit }),
+    };
+    match knowledge_base::ingest(&tenant, &query.name, body.to_vec()).await {
+        Ok(report) => {
+            if let Some(usage) = &report.usage {
+                quotas.record_call(&auth, usage);
+                usage_ledger.record_call(&auth, Uuid::nil(), Route::Chat, usage);
+                crate::metrics::record_provider_usage(usage);
+            }
+            audit_log.record(&auth.id, "knowledge.ingest", &report.document, json!({ "tenant": tenant.id, "chunks": report.chunks }));
+            HttpResponse::Created().json(report)
+        }
+        Err(e) => knowledge_error(e),
+    }
+}
+
+async fn delete_document(
+    path: web::Path<String>,
+    audit_log: web::Data<AuditLog>,
+    auth: web::ReqData<AuthenticatedKey>,
+    tenant: web::ReqData<Arc<Tenant>>,
+) -> impl Responder {
+    if let Some(response) = forbidden_unless_admin(&auth) {
+        return response;
+    }
+    match tenant.knowledge.delete_document(&path).await {
+        Ok(true) => {
+            audit_log.record(&auth.id, "knowledge.delete", &path, json!({ "tenant": tenant.id }));
+            HttpResponse::NoContent().finish()
+        }
+        Ok(false) => error_response(HttpResponse::NotFound(), "document_not_found", format!("Document '{}' not found", path)),
+        Err(e) => knowledge_error(KnowledgeError::Io(e)),
+    }
+}
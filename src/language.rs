@@ -0,0 +1,157 @@
+// language.rs
+use crate::api_keys::now;
+use crate::config;
+use crate::input_process::send_chat_completion;
+use crate::interaction::ProviderUsage;
+use crate::provider_chain;
+use crate::redaction;
+use crate::tenants::ProviderCredentials;
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::path::Path;
+use std::sync::OnceLock;
+use tokio::fs;
+use uuid::Uuid;
+use whatlang::{Detector, Lang};
+
+// Detections kept per session
+const MAX_DETECTIONS: usize = 100;
+
+const TRANSLATION_PROMPT: &str = "Translate the user's image request into English for an image generation model. \
+Keep every visual detail, name and style, add nothing, and reply with the translation only.";
+
+static DETECTOR: OnceLock<Detector> = OnceLock::new();
+
+fn detector() -> &'static Detector {
+    DETECTOR.get_or_init(|| {
+        // Codes were checked with the rest of the configuration
+        let allowed: Vec<Lang> = config::get().language.languages.iter().filter_map(|code| Lang::from_code(code.as_str())).collect();
+        if allowed.is_empty() {
+            Detector::new()
+        } else {
+            Detector::with_allowlist(allowed)
+        }
+    })
+}
+
+// The language of a message, when detection is on and confident about it.
+pub fn detect(text: &str) -> Option<Lang> {
+    let config = config::get();
+    if !config.features.language_detection {
+        return None;
+    }
+    let info = detector().detect(text)?;
+    debug!("Detected {} with confidence {:.2}", info.lang().code(), info.confidence());
+    (info.confidence() >= config.language.min_confidence).then(|| info.lang())
+}
+
+// Language history of a session (language.json next to its context).
+#[derive(Serialize, Deserialize, Default)]
+struct SessionLanguage {
+    // Last language detected; carries messages too short to tell, like "ok"
+    current: Option<String>,
+    // One per user message, newest last
+    messages: Vec<Detection>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Detection {
+    at: u64,
+    language: Option<String>,
+}
+
+// Record a message's detected language in the session and return the language to answer in:
+// the message's own, or the session's when the message was inconclusive.
+pub async fn record(sessions_dir: &Path, session_id: &Uuid, detected: Option<Lang>) -> Option<Lang> {
+    if !config::get().features.language_detection {
+        return None;
+    }
+    let path = sessions_dir.join(session_id.to_string()).join("language.json");
+    let mut session: SessionLanguage = match fs::read_to_string(&path).await {
+        Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|e| {
+            warn!("Ignoring unreadable {}: {}", path.display(), e);
+            SessionLanguage::default()
+        }),
+        Err(_) => SessionLanguage::default(),
+    };
+    let code = detected.map(|lang| lang.code().to_string());
+    session.messages.push(Detection { at: now(), language: code.clone() });
+    if session.messages.len() > MAX_DETECTIONS {
+        let excess = session.messages.len() - MAX_DETECTIONS;
+        session.messages.drain(..excess);
+    }
+    if code.is_some() {
+        session.current = code;
+    }
+
+    let language = session.current.as_deref().and_then(Lang::from_code);
+    let saved = async {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).await?;
+        }
+        let tmp_path = path.with_extension("json.tmp");
+        fs::write(&tmp_path, serde_json::to_string_pretty(&session)?).await?;
+        fs::rename(&tmp_path, &path).await
+    };
+    if let Err(e) = saved.await {
+        warn!("Failed to record the language of session {}: {}", session_id, e);
+    }
+    language
+}
+
+// Sent with chat requests so the model answers in the user's language.
+pub fn instruction(language: Lang) -> String {
+    format!(
+        "The user is writing in {}. Reply in {} unless they ask for another language.",
+        language.eng_name(),
+        language.eng_name()
+    )
+}
+
+// Image models follow English prompts best. Falls back to the original text when translation
+// is off or fails, since a prompt in another language still produces an image. The
+// translation call, if one was made, is returned so it can be billed with the image.
+pub async fn image_prompt(user_input: &str, language: Option<Lang>, credentials: &ProviderCredentials) -> (String, Option<ProviderUsage>) {
+    let config = config::get();
+    let Some(language) = language.filter(|language| *language != Lang::Eng) else {
+        return (user_input.to_string(), None);
+    };
+    if !config.language.translate_image_prompts {
+        return (user_input.to_string(), None);
+    }
+    match translate_to_english(user_input, credentials).await {
+        Ok((translation, usage)) => {
+            debug!("Translated {} image prompt: {}", language.code(), redaction::content(&translation));
+            (translation, Some(usage))
+        }
+        Err(e) => {
+            warn!("Using the untranslated {} image prompt: {}", language.code(), e);
+            (user_input.to_string(), None)
+        }
+    }
+}
+
+async fn translate_to_english(text: &str, credentials: &ProviderCredentials) -> Result<(String, ProviderUsage), Box<dyn std::error::Error>> {
+    let config = config::get();
+    let payload = json!({
+        "messages": [
+            { "role": "system", "content": TRANSLATION_PROMPT },
+            { "role": "user", "content": text },
+        ],
+        "temperature": 0.0,
+        "stream": false,
+    });
+    let chain = provider_chain::chat_chain(config.language.model(&config.models), credentials);
+    let client = crate::http_client::shared_client();
+    let (completion, served_by) = provider_chain::call_with_fallback(&chain, |entry| {
+        let mut payload = payload.clone();
+        payload["model"] = json!(entry.model);
+        payload["max_tokens"] = json!(entry.max_tokens.min(1000));
+        async move { send_chat_completion(client, entry, &payload).await }
+    })
+    .await?;
+    let usage = ProviderUsage { provider: served_by.provider.name.to_string(), model: served_by.model.clone(), tokens: completion.usage, images: 0 };
+    Ok((completion.content.trim().to_string(), usage))
+}
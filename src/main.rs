@@ -1,56 +1,98 @@
 // main.rs
+mod admin_routes;
 mod api_auth;
+mod api_keys;
 mod api_routes;
+mod audit_log;
+mod chat_error;
+mod config;
 mod context_manager;
+mod embeddings;
+mod generation_params;
+mod http_client;
 mod image_diffusion;
 mod image_vision;
 mod input_process;
+mod interaction;
+mod jwt_auth;
+mod knowledge_base;
+mod knowledge_routes;
+mod language;
+mod mcp;
+mod memory;
+mod memory_routes;
+mod metrics;
+mod personas;
+mod prompt_template;
+mod provider_chain;
+mod rate_limit;
+mod redaction;
+mod session_records;
 mod system_prompt;
+mod telemetry;
+mod tenants;
+mod tools;
 mod trigger_handler;
 mod triggers_generate;
 mod url_handler;
+mod usage_ledger;
+mod usage_routes;
+mod ws_chat;
 mod session_manager;
 
-use crate::session_manager::SessionManager;
+use crate::generation_params::{GenerationDefaults, GenerationParams, GenerationOverrides};
 
 use actix_web::{App, HttpServer, middleware, web};
-use std::env;
+use actix_web::dev::Service;
+use actix_web::http::header::{HeaderName, HeaderValue};
+use tracing::Instrument;
 use log::{info, error};
-use log4rs;
-use std::fs;
 use std::io::{self, Write};
 use reqwest::Client;
 use dotenv::dotenv;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
+use std::time::Duration;
 use std::net::{IpAddr, Ipv4Addr};
 use lazy_static::lazy_static;
 
 
+// How often state kept in memory between writes is persisted, and idle sessions leave the metrics
+const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
 lazy_static! {
     static ref ip_address: IpAddr = IpAddr::V4("95.94.61.253".parse::<Ipv4Addr>().unwrap());
 }
 
 async fn run_interactive_mode(
     client: Client,
-    groq_api_key: String,
+    tenant: Arc<tenants::Tenant>,
     mut session_manager: crate::session_manager::SessionManager,
+    persona: Arc<personas::Persona>,
+    params: GenerationParams,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    // let session_id = session_manager.create_session(ip_address.clone()); // Pass ip_address to create_session
+    let session_id = session_manager.create_session(*ip_address);
+    let prompt = persona.prompt_variables(&tenant, None);
     loop {
         print!("\nYou:\n");
         io::stdout().flush()?;
         let mut user_input = String::new();
         io::stdin().read_line(&mut user_input)?;
         let user_input = user_input.trim().to_string();
-        info!("User input: {}", user_input);
+        info!("User input: {}", redaction::content(&user_input));
 
         if user_input.eq_ignore_ascii_case("exit") {
             info!("User requested exit");
             break;
         }
 
-        if let Err(e) = input_process::process_user_input(user_input.clone(), &mut session_manager, &client, &groq_api_key, ip_address.clone()).await {
-            error!("Error processing user input: {}", e);
+        let routing = input_process::route_for(&user_input, &persona, &tenant);
+        // Only the console prints replies; the server paths log them through the redaction layer
+        match input_process::process_user_input(user_input.clone(), routing, session_id, &client, &tenant, *ip_address, &persona, &prompt, &params, None, None).await {
+            Ok(interaction) => println!("\nFANA:\n{}", interaction.content),
+            Err(e) => {
+                println!("\nFANA:\n{}", e);
+                error!("Error processing user input: {}", e);
+            }
         }
     }
 
@@ -61,49 +103,148 @@ async fn run_interactive_mode(
 async fn main() -> std::io::Result<()> {
     dotenv().ok();
 
-    // Create logs directory if it doesn't exist
-    fs::create_dir_all("logs")?;
-    // Configure log4rs
-    log4rs::init_file("log4rs.yaml", Default::default()).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, anyhow::anyhow!(e)))?;
+    // Loaded once; every problem is reported before anything starts
+    let config = match config::init() {
+        Ok(config) => config,
+        Err(e) => {
+            eprint!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // Log to logs/app.log with request and session context
+    let _telemetry = telemetry::init(&config.logging)?;
 
     info!("Starting Fana AI assistant");
+    if config.providers.openai.api_key.is_none() {
+        log::warn!("No OpenAI API key configured; vision, image generation and OpenAI models are unavailable");
+    }
+
+    let client = crate::http_client::shared_client().clone();
 
-    let groq_api_key = env::var("GROQ_API_KEY").expect("GROQ_API_KEY not set");
-    let client = Client::new();
+    let personas = web::Data::new(personas::PersonaRegistry::load(config)?);
+    let console_persona = personas.default_persona();
+    let tenants = Arc::new(tenants::TenantRegistry::load(config, &personas)?);
+    embeddings::init(config)?;
+
+    // `fanallmrust ingest ...` fills a knowledge base instead of starting the server
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("ingest") {
+        return knowledge_base::run_ingest_command(&args[1..], &tenants).await;
+    }
+
+    // MCP servers are only started when the model may call their tools
+    let mcp_tools = if config.features.tools { mcp::connect(config).await } else { Vec::new() };
+    tools::init(config, mcp_tools)?;
+
+    // Validate the default generation parameters before serving anything
+    let generation_defaults = GenerationDefaults::from_config(config);
+    let default_params = GenerationParams::resolve(&GenerationOverrides::default(), &console_persona.generation_defaults(&generation_defaults))
+        .map_err(std::io::Error::other)?;
 
     // Clone the variables to move them into the thread
     let client_clone = client.clone();
-    let groq_api_key_clone = groq_api_key.clone();
+    // The console speaks for the default tenant
+    let console_tenant = tenants.default_tenant();
 
     // Initialize the session manager
     let session_manager = crate::session_manager::SessionManager::new();
     let session_manager_clone = session_manager.clone(); // Clone the session manager
 
     // Spawn a new thread for the interactive console mode
-    std::thread::spawn(move || {
-        let rt = tokio::runtime::Runtime::new().unwrap();
-        rt.block_on(async {
-            if let Err(e) = run_interactive_mode(client_clone, groq_api_key_clone, session_manager_clone).await {
-                error!("Error in interactive mode: {}", e);
-            }
+    if config.features.interactive_console {
+        std::thread::spawn(move || {
+            let rt = tokio::runtime::Runtime::new().unwrap();
+            rt.block_on(async {
+                if let Err(e) = run_interactive_mode(client_clone, console_tenant, session_manager_clone, console_persona, default_params).await {
+                    error!("Error in interactive mode: {}", e);
+                }
+            });
         });
+    }
+
+    let key_registry = Arc::new(api_keys::KeyRegistry::load(config)?);
+    let jwt_verifier = jwt_auth::JwtVerifier::from_config(&config.auth)?.map(Arc::new);
+    let audit_log = web::Data::new(audit_log::AuditLog::open(config));
+    let rate_limiter = Arc::new(rate_limit::RateLimiter::new(&config.limits));
+    let quotas = web::Data::new(rate_limit::QuotaTracker::load(config)?);
+    let usage_ledger = web::Data::new(usage_ledger::UsageLedger::open(config)?);
+
+    if config.features.memory {
+        memory::start(tenants.clone(), quotas.clone(), usage_ledger.clone());
+    }
+
+    // Quota usage and key use are written out on an interval rather than on every request
+    let flushed_quotas = quotas.clone();
+    let final_quotas = quotas.clone();
+    let flushed_keys = key_registry.clone();
+    let final_keys = key_registry.clone();
+    let final_ledger = usage_ledger.clone();
+    let metrics_enabled = config.features.metrics;
+    std::thread::spawn(move || loop {
+        std::thread::sleep(FLUSH_INTERVAL);
+        flushed_quotas.flush();
+        flushed_keys.flush();
+        if metrics_enabled {
+            metrics::prune_sessions();
+        }
     });
 
-    HttpServer::new(move || {
-        let groq_api_key_clone = web::Data::new(groq_api_key.clone());
-        let session_manager_clone = web::Data::new(Arc::new(Mutex::new(SessionManager::new())));
+    info!("Listening on {}", config.server.bind);
+    let server = HttpServer::new(move || {
         App::new()
             .wrap(middleware::Logger::default())
-            .wrap(api_auth::ApiKey)
-            .app_data(groq_api_key_clone.clone())
-            .app_data(session_manager_clone.clone())
+            .wrap(api_auth::ApiKey::new(key_registry.clone(), jwt_verifier.clone(), tenants.clone(), rate_limiter.clone()))
+            // Rejected requests are counted too
+            .wrap_fn(|req, srv| {
+                let started = std::time::Instant::now();
+                let method = req.method().to_string();
+                let fut = srv.call(req);
+                async move {
+                    let res = fut.await?;
+                    let route = res.request().match_pattern().unwrap_or_else(|| "unmatched".to_string());
+                    metrics::observe_http(&route, &method, res.status().as_u16(), started.elapsed());
+                    Ok(res)
+                }
+            })
+            // Outermost, so every log line of the request carries its id
+            .wrap_fn(|req, srv| {
+                let request_id = telemetry::request_id(&req);
+                let span = telemetry::request_span(&request_id, &req);
+                let fut = span.in_scope(|| srv.call(req));
+                async move {
+                    let mut res = fut.await?;
+                    if let Ok(value) = HeaderValue::from_str(&request_id) {
+                        res.headers_mut().insert(HeaderName::from_static(telemetry::REQUEST_ID_HEADER), value);
+                    }
+                    Ok(res)
+                }
+                .instrument(span)
+            })
+            .app_data(web::Data::new(key_registry.clone()))
+            .app_data(audit_log.clone())
+            .app_data(personas.clone())
+            .app_data(quotas.clone())
+            .app_data(web::Data::new(rate_limiter.clone()))
+            .app_data(usage_ledger.clone())
+            .app_data(web::Data::new(tenants.clone()))
+            .app_data(web::Data::new(generation_defaults.clone()))
             .configure(move |cfg| {
-                api_routes::configure(cfg, groq_api_key_clone.clone())
+                api_routes::configure(cfg);
+                if config.features.metrics {
+                    cfg.route("/metrics", web::get().to(metrics::metrics_route));
+                }
             })
             .app_data(web::Data::new(client.clone()))
-    })
-    .bind("127.0.0.1:8080")?
-    .run()
-    .await
+    });
+    let server = match config.server.workers {
+        Some(workers) => server.workers(workers),
+        None => server,
+    };
+    server.bind(&config.server.bind)?.run().await?;
+    final_quotas.flush();
+    final_keys.flush();
+    final_ledger.close();
+    Ok(())
 }
 
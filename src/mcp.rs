@@ -0,0 +1,523 @@
+// mcp.rs
+use crate::config::{Config, McpServerConfig};
+use crate::tools::{Tool, ToolContext, ToolError, ToolOutput};
+
+use futures::future::{join_all, LocalBoxFuture};
+use log::{debug, info, warn};
+use reqwest::header::{ACCEPT, CONTENT_TYPE};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::Command;
+use tokio::runtime::Runtime;
+use tokio::sync::{mpsc, oneshot};
+
+// The Model Context Protocol revision this client speaks
+const PROTOCOL_VERSION: &str = "2025-06-18";
+
+// OpenAI function names are limited to 64 characters
+const MAX_TOOL_NAME: usize = 64;
+
+// Subprocess servers live on their own runtime, so their pipes keep being read whichever
+// worker thread is waiting on them.
+static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+
+#[derive(Debug)]
+pub enum McpError {
+    Transport(String),
+    // A JSON-RPC error returned by the server
+    Rpc { code: i64, message: String },
+    Closed,
+    // An HTTP server no longer knows the session
+    SessionExpired,
+}
+
+impl fmt::Display for McpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            McpError::Transport(message) => write!(f, "{}", message),
+            McpError::Rpc { code, message } => write!(f, "{} (error {})", message, code),
+            McpError::Closed => write!(f, "The server is not running"),
+            McpError::SessionExpired => write!(f, "The server ended the session"),
+        }
+    }
+}
+
+impl Error for McpError {}
+
+impl From<reqwest::Error> for McpError {
+    fn from(e: reqwest::Error) -> Self {
+        McpError::Transport(e.to_string())
+    }
+}
+
+// Start every configured server and list its tools. A server that cannot be reached is
+// left out with a warning rather than keeping the assistant from starting.
+pub async fn connect(config: &Config) -> Vec<Box<dyn Tool>> {
+    let timeout = Duration::from_secs(config.tools.timeout_secs);
+    let connections = config.tools.mcp.iter().map(|(name, server)| async move {
+        match tokio::time::timeout(timeout, list_tools(name, server)).await {
+            Ok(Ok(tools)) => tools,
+            Ok(Err(e)) => {
+                warn!("MCP server {} is unavailable: {}", name, e);
+                Vec::new()
+            }
+            Err(_) => {
+                warn!("MCP server {} did not list its tools within {} seconds", name, timeout.as_secs());
+                Vec::new()
+            }
+        }
+    });
+    join_all(connections).await.into_iter().flatten().collect()
+}
+
+async fn list_tools(name: &str, server: &McpServerConfig) -> Result<Vec<Box<dyn Tool>>, McpError> {
+    let client = Arc::new(McpClient::connect(name, server).await?);
+    let mut tools: Vec<Box<dyn Tool>> = Vec::new();
+    let mut cursor: Option<String> = None;
+    loop {
+        let params = match &cursor {
+            Some(cursor) => json!({ "cursor": cursor }),
+            None => json!({}),
+        };
+        let page = client.request("tools/list", params).await?;
+        for tool in page["tools"].as_array().into_iter().flatten() {
+            let Some(remote_name) = tool["name"].as_str() else {
+                continue;
+            };
+            tools.push(Box::new(McpTool {
+                client: client.clone(),
+                tenants: server.tenants.clone(),
+                name: tool_name(name, remote_name),
+                remote_name: remote_name.to_string(),
+                description: tool["description"].as_str().unwrap_or(remote_name).to_string(),
+                parameters: match &tool["inputSchema"] {
+                    Value::Object(schema) => Value::Object(schema.clone()),
+                    _ => json!({ "type": "object", "properties": {} }),
+                },
+            }));
+        }
+        cursor = page["nextCursor"].as_str().map(String::from);
+        if cursor.is_none() {
+            break;
+        }
+    }
+    Ok(tools)
+}
+
+// The name the model sees: the server's name and the tool's, in the characters function
+// names allow.
+fn tool_name(server: &str, tool: &str) -> String {
+    let name: String = format!("{}__{}", server, tool)
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '-' { c } else { '_' })
+        .collect();
+    name.chars().take(MAX_TOOL_NAME).collect()
+}
+
+// A tool of an MCP server; calls are forwarded to it.
+struct McpTool {
+    client: Arc<McpClient>,
+    // The server's `tenants`; empty when every tenant may use it
+    tenants: Vec<String>,
+    name: String,
+    remote_name: String,
+    description: String,
+    parameters: Value,
+}
+
+impl Tool for McpTool {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn description(&self) -> &str {
+        &self.description
+    }
+
+    fn parameters(&self) -> Value {
+        self.parameters.clone()
+    }
+
+    fn offered_to(&self, tenant: &str) -> bool {
+        self.tenants.is_empty() || self.tenants.iter().any(|allowed| allowed == tenant)
+    }
+
+    fn call<'a>(&'a self, arguments: Value, _context: &'a ToolContext<'a>) -> LocalBoxFuture<'a, Result<ToolOutput, ToolError>> {
+        Box::pin(async move {
+            let result = self.client.request("tools/call", json!({ "name": self.remote_name, "arguments": arguments })).await?;
+            let content = result_text(&result);
+            if result["isError"].as_bool() == Some(true) {
+                return Err(content.into());
+            }
+            Ok(content.into())
+        })
+    }
+}
+
+// The text of a tools/call result. Binary content can't be handed to the model, so it
+// is only mentioned.
+fn result_text(result: &Value) -> String {
+    let parts: Vec<String> = result["content"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|item| match item["type"].as_str().unwrap_or_default() {
+            "text" => item["text"].as_str().unwrap_or_default().to_string(),
+            "resource" => match item["resource"]["text"].as_str() {
+                Some(text) => text.to_string(),
+                None => format!("[resource {}]", item["resource"]["uri"].as_str().unwrap_or_default()),
+            },
+            "resource_link" => format!("[{}]({})", item["name"].as_str().unwrap_or_default(), item["uri"].as_str().unwrap_or_default()),
+            other => format!("[{} {} omitted]", other, item["mimeType"].as_str().unwrap_or_default()),
+        })
+        .collect();
+    if parts.is_empty() {
+        if let Some(structured) = result.get("structuredContent") {
+            return structured.to_string();
+        }
+    }
+    parts.join("\n")
+}
+
+// A JSON-RPC request (or notification, without a reply) for a subprocess server.
+struct Request {
+    method: &'static str,
+    params: Value,
+    reply: Option<oneshot::Sender<Result<Value, McpError>>>,
+}
+
+// A connection to one server that is made again when the server goes away: a subprocess
+// that exited is started again, and an HTTP session the server forgot is opened again.
+struct McpClient {
+    name: String,
+    server: McpServerConfig,
+    transport: Mutex<Arc<Transport>>,
+    // Held while reconnecting, so calls that fail together reconnect once
+    reconnecting: tokio::sync::Mutex<()>,
+}
+
+impl McpClient {
+    async fn connect(name: &str, server: &McpServerConfig) -> Result<Self, McpError> {
+        let transport = Transport::connect(name, server).await?;
+        Ok(McpClient {
+            name: name.to_string(),
+            server: server.clone(),
+            transport: Mutex::new(Arc::new(transport)),
+            reconnecting: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    fn transport(&self) -> Arc<Transport> {
+        self.transport.lock().unwrap().clone()
+    }
+
+    // Retried once on a new connection when the request never reached a live server.
+    async fn request(&self, method: &'static str, params: Value) -> Result<Value, McpError> {
+        let transport = self.transport();
+        match transport.request(method, params.clone()).await {
+            Err(McpError::Closed | McpError::SessionExpired) => {
+                self.reconnect(&transport).await?;
+                self.transport().request(method, params).await
+            }
+            result => result,
+        }
+    }
+
+    // Replace a connection that stopped working, unless another call already has.
+    async fn reconnect(&self, failed: &Arc<Transport>) -> Result<(), McpError> {
+        let _reconnecting = self.reconnecting.lock().await;
+        if !Arc::ptr_eq(&self.transport(), failed) {
+            return Ok(());
+        }
+        warn!("Reconnecting to MCP server {}", self.name);
+        let transport = Transport::connect(&self.name, &self.server).await?;
+        *self.transport.lock().unwrap() = Arc::new(transport);
+        Ok(())
+    }
+}
+
+enum Transport {
+    Stdio(mpsc::UnboundedSender<Request>),
+    Http(HttpServer),
+}
+
+impl Transport {
+    // Start the server, or reach it, and go through the initialization handshake.
+    async fn connect(name: &str, server: &McpServerConfig) -> Result<Self, McpError> {
+        let transport = Transport::start(name, server).await?;
+        let initialized = transport
+            .request(
+                "initialize",
+                json!({
+                    "protocolVersion": PROTOCOL_VERSION,
+                    "capabilities": {},
+                    "clientInfo": { "name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION") },
+                }),
+            )
+            .await?;
+        let protocol_version = initialized["protocolVersion"].as_str().unwrap_or(PROTOCOL_VERSION);
+        info!(
+            "MCP server {} is {} (protocol {})",
+            name,
+            initialized["serverInfo"]["name"].as_str().unwrap_or("unnamed"),
+            protocol_version
+        );
+        if let Transport::Http(server) = &transport {
+            let _ = server.protocol_version.set(protocol_version.to_string());
+        }
+        transport.notify("notifications/initialized").await?;
+        Ok(transport)
+    }
+
+    async fn start(name: &str, server: &McpServerConfig) -> Result<Self, McpError> {
+        if let Some(url) = &server.url {
+            return Ok(Transport::Http(HttpServer {
+                name: name.to_string(),
+                url: url.clone(),
+                headers: server.headers.clone(),
+                token: server.token.clone(),
+                session_id: OnceLock::new(),
+                protocol_version: OnceLock::new(),
+            }));
+        }
+        let command = server.command.clone().unwrap_or_default();
+        let runtime = match RUNTIME.get() {
+            Some(runtime) => runtime,
+            None => {
+                let runtime = tokio::runtime::Builder::new_multi_thread()
+                    .worker_threads(1)
+                    .thread_name("mcp")
+                    .enable_all()
+                    .build()
+                    .map_err(|e| McpError::Transport(e.to_string()))?;
+                RUNTIME.get_or_init(|| runtime)
+            }
+        };
+        let (requests, receiver) = mpsc::unbounded_channel();
+        let (started, start_result) = oneshot::channel();
+        runtime.spawn(run_stdio(name.to_string(), command, server.args.clone(), server.env.clone(), receiver, started));
+        start_result.await.map_err(|_| McpError::Closed)??;
+        Ok(Transport::Stdio(requests))
+    }
+
+    async fn request(&self, method: &'static str, params: Value) -> Result<Value, McpError> {
+        match self {
+            Transport::Stdio(requests) => {
+                let (reply, response) = oneshot::channel();
+                requests.send(Request { method, params, reply: Some(reply) }).map_err(|_| McpError::Closed)?;
+                response.await.map_err(|_| McpError::Transport("The server exited".to_string()))?
+            }
+            Transport::Http(server) => server.request(method, params).await,
+        }
+    }
+
+    async fn notify(&self, method: &'static str) -> Result<(), McpError> {
+        match self {
+            Transport::Stdio(requests) => requests.send(Request { method, params: json!({}), reply: None }).map_err(|_| McpError::Closed),
+            Transport::Http(server) => server.post(&json!({ "jsonrpc": "2.0", "method": method })).await.map(|_| ()),
+        }
+    }
+}
+
+// The response to a request, or its error.
+fn into_result(message: Value) -> Result<Value, McpError> {
+    if let Some(error) = message.get("error") {
+        return Err(McpError::Rpc {
+            code: error["code"].as_i64().unwrap_or_default(),
+            message: error["message"].as_str().unwrap_or("Unknown error").to_string(),
+        });
+    }
+    Ok(message.get("result").cloned().unwrap_or(Value::Null))
+}
+
+// Own a subprocess server: write requests to its stdin and match the JSON lines on its
+// stdout to them by id. When the process exits, pending calls fail and later ones find
+// the channel closed, which starts a new process.
+// Variables a stdio server inherits from our environment.
+const INHERITED_ENV: &[&str] = &["PATH", "HOME", "LANG", "TZ", "TMPDIR"];
+
+async fn run_stdio(
+    name: String,
+    command: String,
+    args: Vec<String>,
+    env: BTreeMap<String, String>,
+    mut requests: mpsc::UnboundedReceiver<Request>,
+    started: oneshot::Sender<Result<(), McpError>>,
+) {
+    // Servers may be third-party code, so they get none of our credentials: only what is
+    // needed to find and run programs, plus their configured variables
+    let inherited = INHERITED_ENV.iter().filter_map(|name| std::env::var_os(name).map(|value| (*name, value)));
+    let spawned = Command::new(&command)
+        .args(&args)
+        .env_clear()
+        .envs(inherited)
+        .envs(&env)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn();
+    let mut child = match spawned {
+        Ok(child) => child,
+        Err(e) => {
+            let _ = started.send(Err(McpError::Transport(format!("Failed to start {}: {}", command, e))));
+            return;
+        }
+    };
+    let (Some(mut stdin), Some(stdout), Some(stderr)) = (child.stdin.take(), child.stdout.take(), child.stderr.take()) else {
+        let _ = started.send(Err(McpError::Closed));
+        return;
+    };
+    let _ = started.send(Ok(()));
+
+    // The server's own logging
+    let stderr_name = name.clone();
+    tokio::spawn(async move {
+        let mut lines = BufReader::new(stderr).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            debug!("MCP server {}: {}", stderr_name, line);
+        }
+    });
+
+    let mut lines = BufReader::new(stdout).lines();
+    let mut pending: HashMap<u64, oneshot::Sender<Result<Value, McpError>>> = HashMap::new();
+    let mut next_id: u64 = 1;
+    loop {
+        tokio::select! {
+            request = requests.recv() => {
+                let Some(request) = request else {
+                    break;
+                };
+                let mut message = json!({ "jsonrpc": "2.0", "method": request.method, "params": request.params });
+                if request.reply.is_some() {
+                    message["id"] = json!(next_id);
+                }
+                let mut line = message.to_string();
+                line.push('\n');
+                if let Err(e) = stdin.write_all(line.as_bytes()).await {
+                    if let Some(reply) = request.reply {
+                        let _ = reply.send(Err(McpError::Transport(e.to_string())));
+                    }
+                    continue;
+                }
+                if let Some(reply) = request.reply {
+                    pending.insert(next_id, reply);
+                    next_id += 1;
+                }
+            }
+            line = lines.next_line() => {
+                let line = match line {
+                    Ok(Some(line)) => line,
+                    Ok(None) | Err(_) => break,
+                };
+                let Ok(message) = serde_json::from_str::<Value>(&line) else {
+                    debug!("MCP server {} wrote a non-JSON line: {}", name, line);
+                    continue;
+                };
+                match (message.get("id").cloned(), message.get("method").and_then(Value::as_str)) {
+                    // Requests from the server; this client offers none of the optional features
+                    (Some(id), Some(method)) => {
+                        let response = if method == "ping" {
+                            json!({ "jsonrpc": "2.0", "id": id, "result": {} })
+                        } else {
+                            json!({ "jsonrpc": "2.0", "id": id, "error": { "code": -32601, "message": "Method not found" } })
+                        };
+                        let _ = stdin.write_all(format!("{}\n", response).as_bytes()).await;
+                    }
+                    (Some(id), None) => {
+                        if let Some(reply) = id.as_u64().and_then(|id| pending.remove(&id)) {
+                            let _ = reply.send(into_result(message));
+                        }
+                    }
+                    (None, _) => {}
+                }
+            }
+        }
+    }
+    warn!("MCP server {} exited", name);
+    for (_, reply) in pending.drain() {
+        let _ = reply.send(Err(McpError::Transport("The server exited".to_string())));
+    }
+}
+
+// A server reached over streamable HTTP: each message is POSTed, and the response comes
+// back as JSON or as an event stream.
+struct HttpServer {
+    name: String,
+    url: String,
+    headers: BTreeMap<String, String>,
+    token: Option<String>,
+    // Assigned by the server when initializing
+    session_id: OnceLock<String>,
+    // Agreed on when initializing, and sent with every later message
+    protocol_version: OnceLock<String>,
+}
+
+impl HttpServer {
+    async fn request(&self, method: &'static str, params: Value) -> Result<Value, McpError> {
+        let id = uuid::Uuid::new_v4().to_string();
+        let message = json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params });
+        match self.post(&message).await? {
+            Some(response) => into_result(response),
+            None => Err(McpError::Transport(format!("{} did not answer {}", self.name, method))),
+        }
+    }
+
+    async fn post(&self, message: &Value) -> Result<Option<Value>, McpError> {
+        let mut request = crate::http_client::shared_client()
+            .post(&self.url)
+            .header(ACCEPT, "application/json, text/event-stream")
+            .json(message);
+        for (name, value) in &self.headers {
+            request = request.header(name, value);
+        }
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        if let Some(session_id) = self.session_id.get() {
+            request = request.header("Mcp-Session-Id", session_id);
+        }
+        if let Some(protocol_version) = self.protocol_version.get() {
+            request = request.header("MCP-Protocol-Version", protocol_version);
+        }
+        let response = request.send().await?;
+        let status = response.status();
+        if status == reqwest::StatusCode::NOT_FOUND && self.session_id.get().is_some() {
+            return Err(McpError::SessionExpired);
+        }
+        if !status.is_success() {
+            return Err(McpError::Transport(format!("{} answered HTTP {}", self.name, status)));
+        }
+        if let Some(session_id) = response.headers().get("mcp-session-id").and_then(|value| value.to_str().ok()) {
+            let _ = self.session_id.set(session_id.to_string());
+        }
+        let Some(id) = message.get("id") else {
+            return Ok(None);
+        };
+        let is_stream = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with("text/event-stream"));
+        if !is_stream {
+            return Ok(Some(response.json().await?));
+        }
+
+        // The stream ends once the response is sent; earlier events are server notifications
+        let body = response.text().await?.replace("\r\n", "\n");
+        let response = body
+            .split("\n\n")
+            .filter_map(|event| {
+                let data: Vec<&str> = event.lines().filter_map(|line| line.strip_prefix("data:")).map(str::trim_start).collect();
+                serde_json::from_str::<Value>(&data.join("\n")).ok()
+            })
+            .find(|message| message.get("id") == Some(id) && message.get("method").is_none());
+        Ok(response)
+    }
+}
@@ -0,0 +1,502 @@
+// memory.rs
+use crate::api_keys::{now, AuthenticatedKey};
+use crate::config;
+use crate::embeddings::{self, cosine_similarity, QueryVector};
+use crate::input_process::send_chat_completion;
+use crate::interaction::{Interaction, ProviderUsage, Route};
+use crate::provider_chain;
+use crate::rate_limit::QuotaTracker;
+use crate::redaction;
+use crate::tenants::{Tenant, TenantRegistry};
+use crate::usage_ledger::UsageLedger;
+
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock, RwLock};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use uuid::Uuid;
+
+// Longest user id accepted from API key callers
+const MAX_USER_ID_LEN: usize = 128;
+// Facts kept from one exchange, and their length
+const MAX_FACTS_PER_EXCHANGE: usize = 10;
+const MAX_FACT_LEN: usize = 300;
+// Exchanges waiting for extraction; more are dropped rather than queued without bound
+const MAX_QUEUED: usize = 1000;
+
+const EXTRACTION_PROMPT: &str = "You maintain the long-term memory of an assistant about one user. \
+From the exchange below, extract durable facts about the user that will still matter in future \
+conversations: preferences (such as the language they want answers in), name, job, company, location, \
+ongoing projects. Ignore one-off requests, small talk, anything about the assistant and facts that are \
+already known. Write each fact as a short third-person sentence, e.g. \"Prefers answers in Portuguese.\" \
+Reply with JSON only: {\"facts\": [\"...\"]}, with an empty list when there is nothing worth remembering.";
+
+static QUEUE: OnceLock<mpsc::Sender<Extraction>> = OnceLock::new();
+
+// A durable fact about a user, with the embedding it is recalled by.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Memory {
+    pub id: Uuid,
+    pub fact: String,
+    pub created_at: u64,
+    // Conversation the fact was learned in
+    pub session_id: Uuid,
+    model: String,
+    embedding: Vec<f32>,
+}
+
+// A memory as shown to its user.
+#[derive(Serialize)]
+pub struct MemoryView<'a> {
+    pub id: Uuid,
+    pub fact: &'a str,
+    pub created_at: u64,
+    pub session_id: Uuid,
+}
+
+impl<'a> From<&'a Memory> for MemoryView<'a> {
+    fn from(memory: &'a Memory) -> Self {
+        MemoryView { id: memory.id, fact: &memory.fact, created_at: memory.created_at, session_id: memory.session_id }
+    }
+}
+
+// Memories of every user of a tenant, each user's shared so changes copy only what they touch.
+type Users = HashMap<String, Arc<Vec<Memory>>>;
+
+// A tenant's memories by user id (memories.json in its storage directory).
+pub struct MemoryStore {
+    path: PathBuf,
+    users: RwLock<Users>,
+    // Held for a whole change, so changes are written in the order they are made
+    writer: tokio::sync::Mutex<()>,
+}
+
+impl fmt::Debug for MemoryStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MemoryStore")
+            .field("path", &self.path)
+            .field("users", &self.users.read().unwrap().len())
+            .finish()
+    }
+}
+
+impl MemoryStore {
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        let path = dir.join("memories.json");
+        let users: HashMap<String, Vec<Memory>> = if path.exists() {
+            let contents = fs::read_to_string(&path)?;
+            serde_json::from_str(&contents)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))?
+        } else {
+            HashMap::new()
+        };
+        let users = users.into_iter().map(|(user_id, memories)| (user_id, Arc::new(memories))).collect();
+        Ok(MemoryStore { path, users: RwLock::new(users), writer: tokio::sync::Mutex::new(()) })
+    }
+
+    // Oldest first.
+    pub fn list(&self, user_id: &str) -> Vec<Memory> {
+        self.users.read().unwrap().get(user_id).map(|memories| memories.to_vec()).unwrap_or_default()
+    }
+
+    // Whether the memory existed.
+    pub async fn delete(&self, user_id: &str, id: Uuid) -> io::Result<bool> {
+        let _writer = self.writer.lock().await;
+        let mut users = self.users.read().unwrap().clone();
+        let Some(memories) = users.get(user_id) else {
+            return Ok(false);
+        };
+        let kept: Vec<Memory> = memories.iter().filter(|memory| memory.id != id).cloned().collect();
+        if kept.len() == memories.len() {
+            return Ok(false);
+        }
+        if kept.is_empty() {
+            users.remove(user_id);
+        } else {
+            users.insert(user_id.to_string(), Arc::new(kept));
+        }
+        self.replace(users).await?;
+        Ok(true)
+    }
+
+    // Forget everything about the user; returns how many memories there were.
+    pub async fn clear(&self, user_id: &str) -> io::Result<usize> {
+        let _writer = self.writer.lock().await;
+        let mut users = self.users.read().unwrap().clone();
+        let removed = users.remove(user_id).map(|memories| memories.len()).unwrap_or(0);
+        if removed > 0 {
+            self.replace(users).await?;
+        }
+        Ok(removed)
+    }
+
+    async fn add(&self, user_id: &str, new_memories: Vec<Memory>, max_per_user: usize) -> io::Result<()> {
+        let _writer = self.writer.lock().await;
+        let mut users = self.users.read().unwrap().clone();
+        let mut memories = users.get(user_id).map(|memories| memories.to_vec()).unwrap_or_default();
+        memories.extend(new_memories);
+        if memories.len() > max_per_user {
+            let excess = memories.len() - max_per_user;
+            memories.drain(..excess);
+        }
+        users.insert(user_id.to_string(), Arc::new(memories));
+        self.replace(users).await
+    }
+
+    // The user's memories closest to the query, best first.
+    fn search(&self, user_id: &str, query: &[f32], model: &str, top_k: usize, min_score: f32) -> Vec<Memory> {
+        let Some(memories) = self.users.read().unwrap().get(user_id).cloned() else {
+            return Vec::new();
+        };
+        let mut scored: Vec<(f32, &Memory)> = memories
+            .iter()
+            .filter(|memory| memory.model == model)
+            .map(|memory| (cosine_similarity(query, &memory.embedding), memory))
+            .filter(|(score, _)| *score >= min_score)
+            .collect();
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+        scored.into_iter().take(top_k).map(|(_, memory)| memory.clone()).collect()
+    }
+
+    // Write the changed memories and, once they are on disk, start using them. The caller
+    // holds the writer, so nothing else changed them meanwhile.
+    async fn replace(&self, users: Users) -> io::Result<()> {
+        let users = self.save(users).await?;
+        *self.users.write().unwrap() = users;
+        Ok(())
+    }
+
+    // Rewrite memories.json on a blocking thread, off the lock recalls take.
+    async fn save(&self, users: Users) -> io::Result<Users> {
+        let path = self.path.clone();
+        tokio::task::spawn_blocking(move || {
+            let by_user: HashMap<&String, &Vec<Memory>> = users.iter().map(|(user_id, memories)| (user_id, memories.as_ref())).collect();
+            let json = serde_json::to_string(&by_user)?;
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            let tmp_path = path.with_extension("json.tmp");
+            fs::write(&tmp_path, json)?;
+            fs::rename(&tmp_path, &path)?;
+            Ok(users)
+        })
+        .await
+        .map_err(io::Error::other)?
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum UserIdError {
+    // A JWT caller named someone other than its subject
+    Mismatch,
+    Invalid,
+}
+
+impl fmt::Display for UserIdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UserIdError::Mismatch => write!(f, "Tokens can only use the memories of their own subject"),
+            UserIdError::Invalid => write!(f, "user_id must be 1 to {} characters", MAX_USER_ID_LEN),
+        }
+    }
+}
+
+impl std::error::Error for UserIdError {}
+
+// Whose memories a caller works with: a JWT's subject, or the user an API key names.
+pub fn user_id(auth: &AuthenticatedKey, requested: Option<&str>) -> Result<Option<String>, UserIdError> {
+    match (&auth.subject, requested) {
+        (Some(subject), Some(requested)) if subject != requested => Err(UserIdError::Mismatch),
+        (Some(subject), _) => Ok(Some(subject.clone())),
+        (None, Some(requested)) if requested.trim().is_empty() || requested.len() > MAX_USER_ID_LEN => Err(UserIdError::Invalid),
+        (None, Some(requested)) => Ok(Some(requested.to_string())),
+        (None, None) => Ok(None),
+    }
+}
+
+// The user's memories relevant to the embedded message.
+pub fn recall(tenant: &Tenant, user_id: &str, query: &QueryVector) -> Vec<Memory> {
+    let config = config::get();
+    let memories = tenant.memories.search(user_id, &query.vector, &query.model, config.memory.top_k, config.memory.min_score);
+    debug!("Recalled {} memories for a user of tenant {}", memories.len(), tenant.id);
+    memories
+}
+
+// A system message sent alongside the persona's prompt.
+pub fn context_message(memories: &[Memory]) -> String {
+    let mut section = String::from("What you remember about this user from earlier conversations (use it when relevant, don't recite it):");
+    for memory in memories {
+        section.push_str("\n- ");
+        section.push_str(&memory.fact);
+    }
+    section
+}
+
+// One chat exchange waiting to be mined for facts.
+struct Extraction {
+    // The caller billed for the extraction
+    key: AuthenticatedKey,
+    tenant: String,
+    user_id: String,
+    session_id: Uuid,
+    user_message: String,
+    reply: String,
+}
+
+// Where extraction calls are counted, like the replies they follow.
+struct Accounting {
+    quotas: actix_web::web::Data<QuotaTracker>,
+    usage_ledger: actix_web::web::Data<UsageLedger>,
+}
+
+impl Accounting {
+    fn record(&self, job: &Extraction, usage: Option<ProviderUsage>) {
+        if let Some(usage) = usage {
+            self.quotas.record_call(&job.key, &usage);
+            self.usage_ledger.record_call(&job.key, job.session_id, Route::Chat, &usage);
+            crate::metrics::record_provider_usage(&usage);
+        }
+    }
+}
+
+// Run extractions one at a time on a thread of their own, so they never delay a reply.
+pub fn start(tenants: Arc<TenantRegistry>, quotas: actix_web::web::Data<QuotaTracker>, usage_ledger: actix_web::web::Data<UsageLedger>) {
+    let (sender, mut receiver) = mpsc::channel::<Extraction>(MAX_QUEUED);
+    let accounting = Accounting { quotas, usage_ledger };
+    if QUEUE.set(sender).is_err() {
+        return;
+    }
+    std::thread::spawn(move || {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            while let Some(job) = receiver.recv().await {
+                let Some(tenant) = tenants.get(&job.tenant) else {
+                    continue;
+                };
+                match extract(&tenant, &job, &accounting).await {
+                    Ok(0) => debug!("No new memories in session {}", job.session_id),
+                    Ok(count) => info!("Remembered {} new fact(s) from session {}", count, job.session_id),
+                    Err(e) => error!("Memory extraction failed for session {}: {}", job.session_id, e),
+                }
+            }
+        });
+    });
+}
+
+// Queue an answered chat message for extraction; a no-op when memory is off or there is
+// no user to remember for. The caller's key is billed for it.
+pub fn remember_later(key: &AuthenticatedKey, user_id: Option<&str>, user_message: &str, interaction: &Interaction) {
+    let Some(user_id) = user_id else {
+        return;
+    };
+    if !config::get().features.memory || interaction.route != Route::Chat || interaction.content.is_empty() {
+        return;
+    }
+    let Some(queue) = QUEUE.get() else {
+        return;
+    };
+    let job = Extraction {
+        key: key.clone(),
+        tenant: key.tenant.clone(),
+        user_id: user_id.to_string(),
+        session_id: interaction.session_id,
+        user_message: user_message.to_string(),
+        reply: interaction.content.clone(),
+    };
+    match queue.try_send(job) {
+        Ok(()) => {}
+        Err(TrySendError::Full(job)) => warn!("Memory extraction queue is full; skipping session {}", job.session_id),
+        Err(TrySendError::Closed(_)) => {}
+    }
+}
+
+#[derive(Deserialize)]
+struct ExtractedFacts {
+    #[serde(default)]
+    facts: Vec<String>,
+}
+
+// Models sometimes wrap JSON in prose or a code fence.
+fn parse_facts(content: &str) -> Option<Vec<String>> {
+    let start = content.find('{')?;
+    let end = content.rfind('}')?;
+    let extracted: ExtractedFacts = serde_json::from_str(content.get(start..=end)?).ok()?;
+    Some(
+        extracted
+            .facts
+            .into_iter()
+            .map(|fact| fact.trim().to_string())
+            .filter(|fact| !fact.is_empty() && fact.chars().count() <= MAX_FACT_LEN)
+            .take(MAX_FACTS_PER_EXCHANGE)
+            .collect(),
+    )
+}
+
+// Ask the model for new facts, then keep those not already remembered.
+async fn extract(tenant: &Tenant, job: &Extraction, accounting: &Accounting) -> Result<usize, Box<dyn std::error::Error>> {
+    let config = config::get();
+    let known = tenant.memories.list(&job.user_id);
+    let mut exchange = String::new();
+    if !known.is_empty() {
+        exchange.push_str("Known facts:\n");
+        for memory in &known {
+            exchange.push_str(&format!("- {}\n", memory.fact));
+        }
+        exchange.push('\n');
+    }
+    exchange.push_str(&format!("User: {}\n\nAssistant: {}", job.user_message, job.reply));
+
+    let payload = json!({
+        "messages": [
+            { "role": "system", "content": EXTRACTION_PROMPT },
+            { "role": "user", "content": exchange },
+        ],
+        "temperature": 0.0,
+        "response_format": { "type": "json_object" },
+        "stream": false,
+    });
+    let chain = provider_chain::chat_chain(config.memory.model(&config.models), &tenant.credentials);
+    let client = crate::http_client::shared_client();
+    let (completion, served_by) = provider_chain::call_with_fallback(&chain, |entry| {
+        let mut payload = payload.clone();
+        payload["model"] = json!(entry.model);
+        payload["max_tokens"] = json!(entry.max_tokens.min(1000));
+        async move { send_chat_completion(client, entry, &payload).await }
+    })
+    .await?;
+    accounting.record(
+        job,
+        Some(ProviderUsage { provider: served_by.provider.name.to_string(), model: served_by.model.clone(), tokens: completion.usage, images: 0 }),
+    );
+
+    let Some(facts) = parse_facts(&completion.content) else {
+        warn!("Memory extraction returned no usable JSON: {}", redaction::content(&completion.content));
+        return Ok(0);
+    };
+    if facts.is_empty() {
+        return Ok(0);
+    }
+
+    let embeddings = embeddings::get().embed(&facts, &tenant.credentials).await.map_err(|e| -> Box<dyn std::error::Error> { e })?;
+    accounting.record(job, embeddings.usage());
+    let created_at = now();
+    let mut new_memories: Vec<Memory> = Vec::new();
+    for (fact, embedding) in facts.into_iter().zip(embeddings.vectors) {
+        let is_repeat = known
+            .iter()
+            .chain(&new_memories)
+            .filter(|memory| memory.model == embeddings.model)
+            .any(|memory| cosine_similarity(&memory.embedding, &embedding) >= config.memory.duplicate_score);
+        if is_repeat {
+            debug!("Skipping a fact already remembered: {}", redaction::content(&fact));
+            continue;
+        }
+        new_memories.push(Memory {
+            id: Uuid::new_v4(),
+            fact,
+            created_at,
+            session_id: job.session_id,
+            model: embeddings.model.clone(),
+            embedding,
+        });
+    }
+
+    let count = new_memories.len();
+    if count > 0 {
+        tenant.memories.add(&job.user_id, new_memories, config.memory.max_per_user).await?;
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api_keys::KeyLimits;
+
+    fn key(subject: Option<&str>) -> AuthenticatedKey {
+        AuthenticatedKey {
+            id: "key".to_string(),
+            name: "test".to_string(),
+            scopes: Vec::new(),
+            limits: KeyLimits::default(),
+            session: None,
+            persona: None,
+            tenant: "default".to_string(),
+            subject: subject.map(String::from),
+        }
+    }
+
+    fn memory(fact: &str) -> Memory {
+        Memory { id: Uuid::new_v4(), fact: fact.to_string(), created_at: 0, session_id: Uuid::nil(), model: "m".to_string(), embedding: vec![1.0, 0.0] }
+    }
+
+    #[tokio::test]
+    async fn changes_are_written_before_they_are_used() {
+        let dir = std::env::temp_dir().join(format!("memory_{}", Uuid::new_v4()));
+        let store = MemoryStore::open(&dir).unwrap();
+        store.add("ada", vec![memory("Likes tea."), memory("Works at Acme."), memory("Lives in Porto.")], 2).await.unwrap();
+        let facts: Vec<String> = MemoryStore::open(&dir).unwrap().list("ada").into_iter().map(|memory| memory.fact).collect();
+        assert_eq!(facts, vec!["Works at Acme.", "Lives in Porto."]);
+
+        let id = store.list("ada")[0].id;
+        assert!(store.delete("ada", id).await.unwrap());
+        assert!(!store.delete("ada", id).await.unwrap());
+        assert_eq!(store.clear("ada").await.unwrap(), 1);
+        assert!(MemoryStore::open(&dir).unwrap().list("ada").is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn failed_writes_change_nothing() {
+        // The storage "directory" is a file, so every write fails
+        let blocker = std::env::temp_dir().join(format!("memory_{}", Uuid::new_v4()));
+        fs::write(&blocker, "").unwrap();
+        let store = MemoryStore { path: blocker.join("memories.json"), users: RwLock::new(HashMap::new()), writer: tokio::sync::Mutex::new(()) };
+        store.users.write().unwrap().insert("ada".to_string(), Arc::new(vec![memory("Likes tea.")]));
+
+        assert!(store.add("ada", vec![memory("Works at Acme.")], 10).await.is_err());
+        assert!(store.delete("ada", store.list("ada")[0].id).await.is_err());
+        assert!(store.clear("ada").await.is_err());
+        assert_eq!(store.list("ada").len(), 1);
+        fs::remove_file(blocker).unwrap();
+    }
+
+    #[test]
+    fn parses_facts_wrapped_in_prose() {
+        let content = "Sure! ```json\n{\"facts\": [\" Prefers Portuguese. \", \"\", \"Works at Acme.\"]}\n```";
+        assert_eq!(parse_facts(content), Some(vec!["Prefers Portuguese.".to_string(), "Works at Acme.".to_string()]));
+        assert_eq!(parse_facts("{}"), Some(Vec::new()));
+        assert_eq!(parse_facts("no json here"), None);
+        assert_eq!(parse_facts("{\"facts\": \"not a list\"}"), None);
+    }
+
+    #[test]
+    fn limits_facts_per_exchange_and_length() {
+        let long = "x".repeat(MAX_FACT_LEN + 1);
+        let facts: Vec<String> = (0..MAX_FACTS_PER_EXCHANGE + 5).map(|i| format!("Fact {}", i)).chain([long]).collect();
+        let parsed = parse_facts(&json!({ "facts": facts }).to_string()).unwrap();
+        assert_eq!(parsed.len(), MAX_FACTS_PER_EXCHANGE);
+        assert!(parsed.iter().all(|fact| fact.len() <= MAX_FACT_LEN));
+    }
+
+    #[test]
+    fn token_subjects_win_over_requested_users() {
+        assert_eq!(user_id(&key(Some("alice")), None), Ok(Some("alice".to_string())));
+        assert_eq!(user_id(&key(Some("alice")), Some("alice")), Ok(Some("alice".to_string())));
+        assert_eq!(user_id(&key(Some("alice")), Some("bob")), Err(UserIdError::Mismatch));
+    }
+
+    #[test]
+    fn api_keys_name_users_per_request() {
+        assert_eq!(user_id(&key(None), None), Ok(None));
+        assert_eq!(user_id(&key(None), Some("bob")), Ok(Some("bob".to_string())));
+        assert_eq!(user_id(&key(None), Some("  ")), Err(UserIdError::Invalid));
+        assert_eq!(user_id(&key(None), Some(&"b".repeat(MAX_USER_ID_LEN + 1))), Err(UserIdError::Invalid));
+    }
+}
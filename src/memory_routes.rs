@@ -0,0 +1,110 @@
+// memory_routes.rs
+use crate::api_keys::{AuthenticatedKey, Scope};
+use crate::api_routes::user_id_error;
+use crate::audit_log::AuditLog;
+use crate::memory::{self, MemoryView};
+use crate::tenants::Tenant;
+
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse, Responder};
+use log::{error, info};
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+use uuid::Uuid;
+
+#[derive(Deserialize)]
+struct MemoryQuery {
+    // Required for API keys; tokens always act for their subject
+    user_id: Option<String>,
+}
+
+// Set Memory Routes (nested under /api). Users see and erase what the assistant remembers about them.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/memories")
+            .route("", web::get().to(list_memories))
+            .route("", web::delete().to(clear_memories))
+            .route("/{id}", web::delete().to(delete_memory))
+    );
+}
+
+fn error_response(mut builder: actix_web::HttpResponseBuilder, code: &str, message: String) -> HttpResponse {
+    builder.json(json!({ "error": { "code": code, "message": message } }))
+}
+
+// The user whose memories the caller may manage, or why it may not.
+fn resolve_user(auth: &AuthenticatedKey, query: &MemoryQuery) -> Result<String, (StatusCode, &'static str, String)> {
+    if !auth.has_scope(Scope::Chat) && !auth.has_scope(Scope::Admin) {
+        info!("Key {} ({}) attempted to manage memories without the chat scope", auth.id, auth.name);
+        return Err((StatusCode::FORBIDDEN, "insufficient_scope", "This API key is not allowed to manage memories".to_string()));
+    }
+    match memory::user_id(auth, query.user_id.as_deref()) {
+        Ok(Some(user_id)) => Ok(user_id),
+        Ok(None) => Err((StatusCode::BAD_REQUEST, "invalid_parameter", "user_id is required".to_string())),
+        Err(e) => {
+            let (status, code) = user_id_error(&e);
+            Err((status, code, e.to_string()))
+        }
+    }
+}
+
+fn storage_error(e: std::io::Error) -> HttpResponse {
+    error!("Failed to update memories: {}", e);
+    error_response(HttpResponse::InternalServerError(), "storage_failed", e.to_string())
+}
+
+async fn list_memories(
+    query: web::Query<MemoryQuery>,
+    auth: web::ReqData<AuthenticatedKey>,
+    tenant: web::ReqData<Arc<Tenant>>,
+) -> impl Responder {
+    let user_id = match resolve_user(&auth, &query) {
+        Ok(user_id) => user_id,
+        Err((status, code, message)) => return error_response(HttpResponse::build(status), code, message),
+    };
+    let memories = tenant.memories.list(&user_id);
+    let views: Vec<MemoryView> = memories.iter().map(MemoryView::from).collect();
+    HttpResponse::Ok().json(json!({ "user_id": user_id, "memories": views }))
+}
+
+async fn clear_memories(
+    query: web::Query<MemoryQuery>,
+    audit_log: web::Data<AuditLog>,
+    auth: web::ReqData<AuthenticatedKey>,
+    tenant: web::ReqData<Arc<Tenant>>,
+) -> impl Responder {
+    let user_id = match resolve_user(&auth, &query) {
+        Ok(user_id) => user_id,
+        Err((status, code, message)) => return error_response(HttpResponse::build(status), code, message),
+    };
+    match tenant.memories.clear(&user_id).await {
+        Ok(removed) => {
+            audit_log.record(&auth.id, "memory.clear", &user_id, json!({ "tenant": tenant.id, "removed": removed }));
+            HttpResponse::NoContent().finish()
+        }
+        Err(e) => storage_error(e),
+    }
+}
+
+async fn delete_memory(
+    path: web::Path<Uuid>,
+    query: web::Query<MemoryQuery>,
+    audit_log: web::Data<AuditLog>,
+    auth: web::ReqData<AuthenticatedKey>,
+    tenant: web::ReqData<Arc<Tenant>>,
+) -> impl Responder {
+    let user_id = match resolve_user(&auth, &query) {
+        Ok(user_id) => user_id,
+        Err((status, code, message)) => return error_response(HttpResponse::build(status), code, message),
+    };
+    let id = path.into_inner();
+    match tenant.memories.delete(&user_id, id).await {
+        Ok(true) => {
+            audit_log.record(&auth.id, "memory.delete", &user_id, json!({ "tenant": tenant.id, "memory": id }));
+            HttpResponse::NoContent().finish()
+        }
+        Ok(false) => error_response(HttpResponse::NotFound(), "memory_not_found", format!("Memory '{}' not found", id)),
+        Err(e) => storage_error(e),
+    }
+}
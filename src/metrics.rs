@@ -0,0 +1,160 @@
+// metrics.rs
+use crate::api_keys::AuthenticatedKey;
+use crate::http_client::ProviderError;
+use crate::interaction::{Interaction, ProviderUsage, Route};
+
+use actix_web::{web, HttpResponse, Responder};
+use lazy_static::lazy_static;
+use log::error;
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, register_int_gauge, Encoder, HistogramVec, IntCounterVec,
+    IntGauge, TextEncoder,
+};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+lazy_static! {
+    static ref HTTP_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "fana_http_requests_total",
+        "HTTP requests by route pattern, method and status",
+        &["route", "method", "status"]
+    )
+    .unwrap();
+    static ref HTTP_DURATION: HistogramVec = register_histogram_vec!(
+        "fana_http_request_duration_seconds",
+        "HTTP request latency by route pattern and method",
+        &["route", "method"],
+        vec![0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0]
+    )
+    .unwrap();
+    static ref PROVIDER_DURATION: HistogramVec = register_histogram_vec!(
+        "fana_provider_request_duration_seconds",
+        "Latency of each provider call attempt, up to the response headers",
+        &["provider"],
+        vec![0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0]
+    )
+    .unwrap();
+    static ref PROVIDER_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "fana_provider_errors_total",
+        "Failed provider call attempts by HTTP status (or timeout/connect/network)",
+        &["provider", "status"]
+    )
+    .unwrap();
+    static ref TOKENS: IntCounterVec = register_int_counter_vec!(
+        "fana_tokens_total",
+        "Tokens consumed by provider, model and kind (prompt/completion)",
+        &["provider", "model", "kind"]
+    )
+    .unwrap();
+    static ref IMAGE_GENERATIONS: IntCounterVec = register_int_counter_vec!(
+        "fana_image_generations_total",
+        "Images generated by model",
+        &["model"]
+    )
+    .unwrap();
+    static ref TOOL_CALLS: IntCounterVec = register_int_counter_vec!(
+        "fana_tool_calls_total",
+        "Tool calls made by chat models, by tool and outcome (ok/error)",
+        &["tool", "outcome"]
+    )
+    .unwrap();
+    static ref ROUTE_DECISIONS: IntCounterVec = register_int_counter_vec!(
+        "fana_route_decisions_total",
+        "Messages by the pipeline chosen for them",
+        &["route"]
+    )
+    .unwrap();
+    static ref ACTIVE_SESSIONS: IntGauge = register_int_gauge!(
+        "fana_active_sessions",
+        "Sessions with an interaction within features.session_active_window_secs"
+    )
+    .unwrap();
+    static ref SESSION_ACTIVITY: Mutex<HashMap<Uuid, Instant>> = Mutex::new(HashMap::new());
+    static ref ACTIVE_WINDOW: Duration = Duration::from_secs(crate::config::get().features.session_active_window_secs);
+}
+
+pub fn observe_http(route: &str, method: &str, status: u16, elapsed: Duration) {
+    HTTP_REQUESTS.with_label_values(&[route, method, &status.to_string()]).inc();
+    HTTP_DURATION.with_label_values(&[route, method]).observe(elapsed.as_secs_f64());
+}
+
+pub fn observe_provider_call(provider: &str, elapsed: Duration, error: Option<&ProviderError>) {
+    PROVIDER_DURATION.with_label_values(&[provider]).observe(elapsed.as_secs_f64());
+    if let Some(error) = error {
+        let status = match error {
+            ProviderError::RateLimited { .. } => "429".to_string(),
+            ProviderError::Auth { status, .. }
+            | ProviderError::BadRequest { status, .. }
+            | ProviderError::Server { status, .. } => status.to_string(),
+            ProviderError::Timeout => "timeout".to_string(),
+            ProviderError::Connect(_) => "connect".to_string(),
+            ProviderError::Network(_) => "network".to_string(),
+            ProviderError::CircuitOpen { .. } => "circuit_open".to_string(),
+        };
+        PROVIDER_ERRORS.with_label_values(&[provider, &status]).inc();
+    }
+}
+
+pub fn record_tool_call(tool: &str, ok: bool) {
+    TOOL_CALLS.with_label_values(&[tool, if ok { "ok" } else { "error" }]).inc();
+}
+
+pub fn record_route(route: Route) {
+    ROUTE_DECISIONS.with_label_values(&[route.as_str()]).inc();
+}
+
+// Tokens and images of a call besides an interaction's own model.
+pub fn record_provider_usage(usage: &ProviderUsage) {
+    if let Some(tokens) = &usage.tokens {
+        TOKENS.with_label_values(&[&usage.provider, &usage.model, "prompt"]).inc_by(tokens.prompt_tokens);
+        TOKENS.with_label_values(&[&usage.provider, &usage.model, "completion"]).inc_by(tokens.completion_tokens);
+    }
+    if usage.images > 0 {
+        IMAGE_GENERATIONS.with_label_values(&[&usage.model]).inc_by(usage.images);
+    }
+}
+
+pub fn record_interaction(interaction: &Interaction) {
+    if let Some(usage) = &interaction.usage {
+        let (provider, model) = (interaction.provider.as_str(), interaction.model.as_str());
+        TOKENS.with_label_values(&[provider, model, "prompt"]).inc_by(usage.prompt_tokens);
+        TOKENS.with_label_values(&[provider, model, "completion"]).inc_by(usage.completion_tokens);
+    }
+    if interaction.route == Route::Generate {
+        IMAGE_GENERATIONS.with_label_values(&[&interaction.model]).inc_by(interaction.image_urls.len() as u64);
+    }
+    for usage in interaction.extra_usage() {
+        record_provider_usage(usage);
+    }
+    SESSION_ACTIVITY.lock().unwrap().insert(interaction.session_id, Instant::now());
+}
+
+// Forget sessions idle for longer than the window and update the gauge. Runs on scrape and
+// periodically, so the map stays bounded when nobody scrapes.
+pub fn prune_sessions() {
+    let mut activity = SESSION_ACTIVITY.lock().unwrap();
+    activity.retain(|_, last_seen| last_seen.elapsed() < *ACTIVE_WINDOW);
+    ACTIVE_SESSIONS.set(activity.len() as i64);
+}
+
+// Prometheus text exposition. The figures span every tenant, so only operators (admins of
+// the default tenant) may read them.
+pub async fn metrics_route(auth: web::ReqData<AuthenticatedKey>) -> impl Responder {
+    if !auth.is_operator() {
+        return HttpResponse::Forbidden().json(serde_json::json!({
+            "error": { "code": "insufficient_scope", "message": "Only operators can read metrics" }
+        }));
+    }
+
+    prune_sessions();
+
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        error!("Failed to encode metrics: {}", e);
+        return HttpResponse::InternalServerError().finish();
+    }
+    HttpResponse::Ok().content_type(encoder.format_type()).body(buffer)
+}
@@ -0,0 +1,277 @@
+// personas.rs
+use crate::api_keys::AuthenticatedKey;
+use crate::config::Config;
+use crate::generation_params::{model_limits, GenerationDefaults};
+use crate::interaction::Route;
+use crate::prompt_template::{PromptTemplate, PromptVariables, TemplateError};
+use crate::session_records::SessionRecords;
+use crate::system_prompt::SYSTEM_PROMPT;
+use crate::tenants::Tenant;
+
+use actix_web::{web, HttpResponse, Responder};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+// The built-in assistant; a `fana.toml` in the personas directory replaces it.
+pub const BUILTIN_PERSONA: &str = "fana";
+
+// A persona file as written; the system prompt is a template (see prompt_template.rs).
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PersonaFile {
+    // Defaults to the file name without `.toml`
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    description: String,
+    system_prompt: String,
+    // Replace the server-wide chat model and temperature for this persona's sessions
+    model: Option<String>,
+    temperature: Option<f32>,
+    #[serde(default = "all_capabilities")]
+    capabilities: Vec<Route>,
+}
+
+// A named assistant: its instructions, generation defaults and what it may do.
+#[derive(Debug)]
+pub struct Persona {
+    pub name: String,
+    pub description: String,
+    template: PromptTemplate,
+    pub model: Option<String>,
+    pub temperature: Option<f32>,
+    pub capabilities: Vec<Route>,
+}
+
+fn all_capabilities() -> Vec<Route> {
+    vec![Route::Chat, Route::Vision, Route::Generate]
+}
+
+impl Persona {
+    fn builtin() -> Result<Self, String> {
+        PersonaFile {
+            name: BUILTIN_PERSONA.to_string(),
+            description: "Fana Assistant, the friendly voice of Fana AI".to_string(),
+            system_prompt: SYSTEM_PROMPT.to_string(),
+            model: None,
+            temperature: None,
+            capabilities: all_capabilities(),
+        }
+        .into_persona()
+    }
+
+    pub fn allows(&self, route: Route) -> bool {
+        self.capabilities.contains(&route)
+    }
+
+    // Server defaults with this persona's model and temperature applied; per-request
+    // overrides are resolved on top of these as usual.
+    pub fn generation_defaults(&self, defaults: &GenerationDefaults) -> GenerationDefaults {
+        GenerationDefaults {
+            model: self.model.clone().unwrap_or_else(|| defaults.model.clone()),
+            temperature: self.temperature.unwrap_or(defaults.temperature),
+            ..defaults.clone()
+        }
+    }
+
+    // The system prompt for one request.
+    pub fn system_prompt(&self, variables: &PromptVariables) -> Result<String, TemplateError> {
+        self.template.render(variables)
+    }
+
+    // The prompt variables for a caller of this persona.
+    pub fn prompt_variables(&self, tenant: &Tenant, key: Option<&AuthenticatedKey>) -> PromptVariables {
+        PromptVariables::new(&self.capabilities, tenant, key)
+    }
+}
+
+impl PersonaFile {
+    fn into_persona(self) -> Result<Persona, String> {
+        if self.system_prompt.trim().is_empty() {
+            return Err("system_prompt must not be empty".to_string());
+        }
+        if let Some(model) = &self.model {
+            if model_limits(model).is_none() {
+                return Err(format!("unknown chat model '{}'", model));
+            }
+        }
+        if self.temperature.is_some_and(|temperature| !(0.0..=2.0).contains(&temperature)) {
+            return Err("temperature must be between 0 and 2".to_string());
+        }
+        if !self.capabilities.contains(&Route::Chat) {
+            return Err("capabilities must include chat".to_string());
+        }
+        let template = PromptTemplate::compile(&self.system_prompt).map_err(|e| e.to_string())?;
+        Ok(Persona {
+            name: self.name,
+            description: self.description,
+            template,
+            model: self.model,
+            temperature: self.temperature,
+            capabilities: self.capabilities,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum PersonaError {
+    Unknown(String),
+    // Sessions keep the persona they were created with
+    Conflict { session: String, requested: String },
+}
+
+impl fmt::Display for PersonaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PersonaError::Unknown(name) => write!(f, "Unknown persona '{}'", name),
+            PersonaError::Conflict { session, requested } => {
+                write!(f, "This session uses the '{}' persona and cannot switch to '{}'", session, requested)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PersonaError {}
+
+// The persona a session was created with, as recorded in session_personas.jsonl.
+#[derive(Serialize, Deserialize)]
+struct SessionPersona {
+    persona: String,
+}
+
+// Personas loaded from the personas directory, and the persona each session was created with
+// (session_personas.jsonl in the data directory) so a session keeps it across requests and restarts.
+pub struct PersonaRegistry {
+    personas: HashMap<String, Arc<Persona>>,
+    default: String,
+    records: SessionRecords<SessionPersona>,
+    sessions: Mutex<HashMap<Uuid, String>>,
+}
+
+impl PersonaRegistry {
+    pub fn load(config: &Config) -> io::Result<Self> {
+        let mut personas = HashMap::new();
+        let builtin = Persona::builtin().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        personas.insert(BUILTIN_PERSONA.to_string(), Arc::new(builtin));
+
+        let dir = &config.personas.dir;
+        if dir.is_dir() {
+            let mut files: Vec<PathBuf> = fs::read_dir(dir)?
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| path.extension().is_some_and(|extension| extension == "toml"))
+                .collect();
+            files.sort();
+            for path in files {
+                let persona = load_persona(&path)?;
+                personas.insert(persona.name.clone(), Arc::new(persona));
+            }
+        } else {
+            info!("Personas directory {} not found; only the built-in persona is available", dir.display());
+        }
+
+        let default = config.personas.default.clone();
+        if !personas.contains_key(&default) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Default persona '{}' is not defined", default)));
+        }
+
+        // Sessions used to be rewritten into session_personas.json, which is still read
+        let legacy_path = config.data_file("session_personas.json");
+        let mut sessions: HashMap<Uuid, String> = if legacy_path.exists() {
+            let contents = fs::read_to_string(&legacy_path)?;
+            serde_json::from_str(&contents)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", legacy_path.display(), e)))?
+        } else {
+            HashMap::new()
+        };
+        let (records, recorded) = SessionRecords::<SessionPersona>::open(config.data_file("session_personas.jsonl"))?;
+        sessions.extend(recorded.into_iter().map(|(session_id, record)| (session_id, record.persona)));
+
+        let mut names: Vec<&String> = personas.keys().collect();
+        names.sort();
+        info!("Loaded personas {:?} (default: {})", names, default);
+        Ok(PersonaRegistry { personas, default, records, sessions: Mutex::new(sessions) })
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<Persona>> {
+        self.personas.get(name).cloned()
+    }
+
+    pub fn default_persona(&self) -> Arc<Persona> {
+        self.personas[&self.default].clone()
+    }
+
+    // The session's persona. A new session takes the requested persona, else the key's,
+    // else the tenant's default, and keeps it from then on. Personas the tenant does not
+    // allow are treated as unknown.
+    pub fn for_session(
+        &self,
+        session_id: Uuid,
+        requested: Option<&str>,
+        key: &AuthenticatedKey,
+        tenant: &Tenant,
+    ) -> Result<Arc<Persona>, PersonaError> {
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some(name) = sessions.get(&session_id) {
+            if let Some(requested) = requested.filter(|requested| *requested != name) {
+                return Err(PersonaError::Conflict { session: name.clone(), requested: requested.to_string() });
+            }
+            return Ok(self.get(name).unwrap_or_else(|| {
+                warn!("Persona '{}' of session {} no longer exists; using '{}'", name, session_id, self.default);
+                self.default_persona()
+            }));
+        }
+
+        let name = requested.or(key.persona.as_deref()).unwrap_or(&tenant.persona);
+        let persona = self
+            .get(name)
+            .filter(|_| tenant.allows_persona(name))
+            .ok_or_else(|| PersonaError::Unknown(name.to_string()))?;
+        sessions.insert(session_id, persona.name.clone());
+        self.records.append(session_id, SessionPersona { persona: persona.name.clone() });
+        Ok(persona)
+    }
+}
+
+fn load_persona(path: &Path) -> io::Result<Persona> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), message));
+    let contents = fs::read_to_string(path)?;
+    let mut file: PersonaFile = toml::from_str(&contents).map_err(|e| invalid(e.to_string()))?;
+    if file.name.is_empty() {
+        file.name = path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+    }
+    file.into_persona().map_err(invalid)
+}
+
+#[derive(Serialize)]
+struct PersonaView<'a> {
+    name: &'a str,
+    description: &'a str,
+    model: Option<&'a str>,
+    capabilities: &'a [Route],
+    default: bool,
+}
+
+// The personas the caller's tenant can pick from; prompts stay on the server.
+pub async fn list_personas(registry: web::Data<PersonaRegistry>, tenant: web::ReqData<Arc<Tenant>>) -> impl Responder {
+    let mut personas: Vec<PersonaView> = registry
+        .personas
+        .values()
+        .filter(|persona| tenant.allows_persona(&persona.name))
+        .map(|persona| PersonaView {
+            name: &persona.name,
+            description: &persona.description,
+            model: persona.model.as_deref(),
+            capabilities: &persona.capabilities,
+            default: persona.name == tenant.persona,
+        })
+        .collect();
+    personas.sort_by(|a, b| a.name.cmp(b.name));
+    HttpResponse::Ok().json(serde_json::json!({ "personas": personas }))
+}
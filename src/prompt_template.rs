@@ -0,0 +1,187 @@
+// prompt_template.rs
+use crate::api_keys::{AuthenticatedKey, Scope};
+use crate::config;
+use crate::interaction::Route;
+use crate::tenants::Tenant;
+use crate::tools;
+
+use chrono::Utc;
+use minijinja::{Environment, UndefinedBehavior};
+use serde::Serialize;
+use std::fmt;
+
+const TEMPLATE_NAME: &str = "system_prompt";
+
+// A system prompt written as a minijinja template, e.g. `Share {{ contact_email }}`.
+// Variables: now, date, time, weekday, user_name, locale, tenant_name, contact_email and
+// tools (a list of {name, description}); using anything else is an error at load time.
+pub struct PromptTemplate {
+    env: Environment<'static>,
+}
+
+impl fmt::Debug for PromptTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PromptTemplate")
+    }
+}
+
+#[derive(Debug)]
+pub struct TemplateError(String);
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "System prompt template error: {}", self.0)
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+impl From<minijinja::Error> for TemplateError {
+    fn from(e: minijinja::Error) -> Self {
+        // The default Display leaves out the line number
+        match e.line() {
+            Some(line) => TemplateError(format!("line {}: {}", line, e)),
+            None => TemplateError(e.to_string()),
+        }
+    }
+}
+
+impl PromptTemplate {
+    // Parse the template and render it once with sample values, so syntax errors and
+    // unknown variables are reported when the persona is loaded rather than mid-conversation.
+    pub fn compile(source: &str) -> Result<Self, TemplateError> {
+        let mut env = Environment::new();
+        env.set_undefined_behavior(UndefinedBehavior::Strict);
+        env.set_trim_blocks(true);
+        env.set_lstrip_blocks(true);
+        env.add_template_owned(TEMPLATE_NAME, source.to_string())?;
+        let template = PromptTemplate { env };
+
+        let sample = PromptVariables {
+            user_name: Some("Ada".to_string()),
+            locale: "en".to_string(),
+            tenant_name: "Example".to_string(),
+            contact_email: "hello@example.com".to_string(),
+            tools: ROUTE_TOOLS.to_vec(),
+            routes: Vec::new(),
+        };
+        template.render(&sample)?;
+        template.render(&PromptVariables { user_name: None, tools: Vec::new(), ..sample })?;
+        Ok(template)
+    }
+
+    pub fn render(&self, variables: &PromptVariables) -> Result<String, TemplateError> {
+        let now = Utc::now();
+        let context = RenderContext {
+            now: now.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            date: now.format("%Y-%m-%d").to_string(),
+            time: now.format("%H:%M").to_string(),
+            weekday: now.format("%A").to_string(),
+            variables,
+        };
+        Ok(self.env.get_template(TEMPLATE_NAME)?.render(&context)?)
+    }
+}
+
+// Something the assistant can do besides chatting, as described to the model.
+#[derive(Serialize, Debug, Clone, Copy)]
+pub struct ToolInfo {
+    pub name: &'static str,
+    pub description: &'static str,
+    #[serde(skip)]
+    pub route: Route,
+}
+
+// Without function calling, images are still analysed and drawn when the message asks for it.
+const ROUTE_TOOLS: &[ToolInfo] = &[
+    ToolInfo { name: "image_analysis", description: "describe and analyse images from links the user shares", route: Route::Vision },
+    ToolInfo { name: "image_generation", description: "create an image from a description", route: Route::Generate },
+];
+
+// Per-request values for a system prompt; the date and time are filled in at render time.
+#[derive(Serialize, Debug, Clone)]
+pub struct PromptVariables {
+    pub user_name: Option<String>,
+    pub locale: String,
+    pub tenant_name: String,
+    pub contact_email: String,
+    pub tools: Vec<ToolInfo>,
+    // The routes behind those tools, which decide the functions offered to chat models
+    #[serde(skip)]
+    pub routes: Vec<Route>,
+}
+
+impl PromptVariables {
+    // The tenant's details, with the tools this caller is actually offered: enabled on the
+    // server, shared with the tenant, allowed for the persona and, for API callers, within
+    // the key's scopes.
+    pub fn new(capabilities: &[Route], tenant: &Tenant, key: Option<&AuthenticatedKey>) -> Self {
+        let config = config::get();
+        let features = &config.features;
+        let routes: Vec<Route> = [Route::Vision, Route::Generate]
+            .into_iter()
+            .filter(|route| match route {
+                Route::Vision => features.vision,
+                Route::Generate => features.image_generation,
+                Route::Chat => true,
+            })
+            .filter(|route| capabilities.contains(route))
+            .filter(|route| key.is_none_or(|key| key.has_scope(Scope::for_route(*route))))
+            .collect();
+        let tools = if features.tools {
+            tools::for_request(tenant, &routes)
+                .into_iter()
+                .map(|tool| ToolInfo { name: tool.name(), description: tool.description(), route: tool.route() })
+                .collect()
+        } else {
+            ROUTE_TOOLS.iter().filter(|tool| routes.contains(&tool.route)).copied().collect()
+        };
+        PromptVariables {
+            user_name: None,
+            locale: config.prompt.locale.clone(),
+            tenant_name: tenant.name.clone(),
+            contact_email: tenant.contact_email.clone(),
+            tools,
+            routes,
+        }
+    }
+
+    // Take a capability away for this request, e.g. when its quota is used up.
+    pub fn without(mut self, route: Route) -> Self {
+        self.routes.retain(|allowed| *allowed != route);
+        self.tools.retain(|tool| tool.route != route);
+        self
+    }
+
+    // Caller-supplied details; blank values are ignored.
+    pub fn with_user(mut self, user_name: Option<&str>, locale: Option<&str>) -> Self {
+        if let Some(user_name) = user_name.map(str::trim).filter(|name| !name.is_empty()) {
+            self.user_name = Some(user_name.chars().take(64).collect());
+        }
+        if let Some(locale) = locale.map(str::trim).filter(|locale| is_locale(locale)) {
+            self.locale = locale.to_string();
+        }
+        self
+    }
+}
+
+// BCP 47-ish tags such as `en`, `pt-BR` or `zh-Hant-TW`; anything else is not worth a prompt line.
+fn is_locale(tag: &str) -> bool {
+    !tag.is_empty() && tag.len() <= 35 && tag.split('-').all(|part| !part.is_empty() && part.len() <= 8 && part.chars().all(|c| c.is_ascii_alphanumeric()))
+}
+
+// First language of an Accept-Language header, e.g. `pt-BR` from `pt-BR,pt;q=0.9,en;q=0.8`.
+pub fn accept_language(header: Option<&str>) -> Option<&str> {
+    let first = header?.split(',').next()?.split(';').next()?.trim();
+    (first != "*" && is_locale(first)).then_some(first)
+}
+
+#[derive(Serialize)]
+struct RenderContext<'a> {
+    now: String,
+    date: String,
+    time: String,
+    weekday: String,
+    #[serde(flatten)]
+    variables: &'a PromptVariables,
+}
@@ -0,0 +1,288 @@
+// provider_chain.rs
+use crate::chat_error::ChatError;
+use crate::config;
+use crate::generation_params::model_limits;
+use crate::http_client::ProviderError;
+use crate::tenants::ProviderCredentials;
+
+use lazy_static::lazy_static;
+use log::{info, warn};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// OpenAI-compatible chat completion endpoints we can route to.
+pub struct Provider {
+    pub name: &'static str,
+    pub default_chat_url: &'static str,
+}
+
+pub const PROVIDERS: &[Provider] = &[
+    Provider { name: "groq", default_chat_url: "https://api.groq.com/openai/v1/chat/completions" },
+    Provider { name: "openai", default_chat_url: "https://api.openai.com/v1/chat/completions" },
+];
+
+impl Provider {
+    fn settings(&self) -> &'static config::ProviderConfig {
+        let providers = &config::get().providers;
+        match self.name {
+            "groq" => &providers.groq,
+            _ => &providers.openai,
+        }
+    }
+
+    pub fn chat_url(&self) -> &'static str {
+        self.settings().chat_url.as_deref().unwrap_or(self.default_chat_url)
+    }
+}
+
+pub fn provider(name: &str) -> Option<&'static Provider> {
+    PROVIDERS.iter().find(|provider| provider.name == name)
+}
+
+// One step of a fallback chain: a model on a provider, with the tenant's key to call it.
+pub struct ChainEntry {
+    pub provider: &'static Provider,
+    pub model: String,
+    pub max_tokens: u32,
+    pub api_key: String,
+}
+
+// The requested chat model first, then the configured fallbacks (models.chat_fallbacks).
+pub fn chat_chain(primary_model: &str, credentials: &ProviderCredentials) -> Vec<ChainEntry> {
+    let models = std::iter::once(primary_model.to_string())
+        .chain(config::get().models.chat_fallbacks.iter().cloned());
+    build_chain(models, credentials)
+}
+
+// Vision-capable models in order of preference (models.vision).
+pub fn vision_chain(credentials: &ProviderCredentials) -> Vec<ChainEntry> {
+    build_chain(config::get().models.vision.iter().cloned(), credentials)
+}
+
+fn build_chain(models: impl Iterator<Item = String>, credentials: &ProviderCredentials) -> Vec<ChainEntry> {
+    let mut chain: Vec<ChainEntry> = Vec::new();
+    for model in models {
+        if chain.iter().any(|entry| entry.model == model) {
+            continue;
+        }
+        let Some(limits) = model_limits(&model) else {
+            warn!("Skipping unknown model '{}' in provider chain", model);
+            continue;
+        };
+        let Some(provider) = provider(&limits.provider) else {
+            warn!("Skipping model '{}': unknown provider '{}'", model, limits.provider);
+            continue;
+        };
+        let Some(api_key) = credentials.api_key(provider.name) else {
+            warn!("Skipping model '{}': no API key configured for {}", model, provider.name);
+            continue;
+        };
+        chain.push(ChainEntry { provider, model, max_tokens: limits.max_tokens, api_key: api_key.to_string() });
+    }
+    chain
+}
+
+// Try each entry in order until one succeeds. Only provider failures fall through to
+// the next entry; anything else (e.g. a malformed response mid-stream) is returned as is,
+// and so is a rejected request, which every other provider would reject too.
+pub async fn call_with_fallback<'a, T, F, Fut>(
+    chain: &'a [ChainEntry],
+    mut call: F,
+) -> Result<(T, &'a ChainEntry), Box<dyn std::error::Error>>
+where
+    F: FnMut(&'a ChainEntry) -> Fut,
+    Fut: Future<Output = Result<T, Box<dyn std::error::Error>>>,
+{
+    if chain.is_empty() {
+        return Err(Box::new(ChatError::NotConfigured { provider: "A provider for the requested models".to_string() }));
+    }
+    let mut last_error: Option<Box<dyn std::error::Error>> = None;
+
+    for entry in chain {
+        let Some(_permit) = CircuitBreaker::allow(entry.provider.name) else {
+            info!("Circuit open for {}, skipping {}", entry.provider.name, entry.model);
+            continue;
+        };
+        match call(entry).await {
+            Ok(value) => {
+                CircuitBreaker::record_success(entry.provider.name);
+                return Ok((value, entry));
+            }
+            Err(e) => match e.downcast_ref::<ProviderError>() {
+                Some(ProviderError::BadRequest { detail, .. }) if !detail.is_model_unavailable() => {
+                    CircuitBreaker::record_success(entry.provider.name);
+                    return Err(e);
+                }
+                Some(provider_error) => {
+                    if trips_breaker(provider_error) {
+                        CircuitBreaker::record_failure(entry.provider.name);
+                    } else {
+                        CircuitBreaker::record_success(entry.provider.name);
+                    }
+                    warn!("{} ({}) failed, trying next provider: {}", entry.provider.name, entry.model, provider_error);
+                    last_error = Some(e);
+                }
+                None => {
+                    // The provider answered, so its circuit should not stay half-open
+                    CircuitBreaker::record_success(entry.provider.name);
+                    return Err(e);
+                }
+            },
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| {
+        Box::new(ProviderError::CircuitOpen {
+            providers: chain.iter().map(|entry| entry.provider.name).collect::<Vec<_>>().join(", "),
+        })
+    }))
+}
+
+// A rejected request says nothing about the provider's health.
+fn trips_breaker(error: &ProviderError) -> bool {
+    !matches!(error, ProviderError::BadRequest { .. })
+}
+
+lazy_static! {
+    static ref BREAKERS: Mutex<HashMap<&'static str, BreakerState>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Default)]
+struct BreakerState {
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+    // After the cool-down a single trial request is let through
+    trial_in_flight: bool,
+}
+
+pub struct CircuitBreaker;
+
+// Held while a call is in flight. A trial whose call is dropped before recording an outcome
+// (the client disconnected or cancelled) frees its slot for the next request.
+pub struct Permit {
+    provider: &'static str,
+    trial: bool,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if self.trial {
+            if let Some(state) = BREAKERS.lock().unwrap().get_mut(self.provider) {
+                state.trial_in_flight = false;
+            }
+        }
+    }
+}
+
+impl BreakerState {
+    // Whether a call may go ahead and, if so, whether it is the trial after the cool-down.
+    fn admit(&mut self, now: Instant) -> Option<bool> {
+        match self.open_until {
+            None => Some(false),
+            Some(until) if now < until => None,
+            Some(_) if self.trial_in_flight => None,
+            Some(_) => {
+                self.trial_in_flight = true;
+                Some(true)
+            }
+        }
+    }
+
+    // Count a failure, opening the circuit after too many in a row or a failed trial.
+    fn fail(&mut self, settings: &config::CircuitBreakerConfig, now: Instant) -> bool {
+        self.consecutive_failures += 1;
+        if !self.trial_in_flight && self.consecutive_failures < settings.failure_threshold {
+            return false;
+        }
+        self.open_until = Some(now + Duration::from_secs(settings.cooldown_secs));
+        self.trial_in_flight = false;
+        true
+    }
+}
+
+impl CircuitBreaker {
+    pub fn allow(provider: &'static str) -> Option<Permit> {
+        let mut breakers = BREAKERS.lock().unwrap();
+        let trial = breakers.entry(provider).or_default().admit(Instant::now())?;
+        Some(Permit { provider, trial })
+    }
+
+    pub fn record_success(provider: &'static str) {
+        let mut breakers = BREAKERS.lock().unwrap();
+        if let Some(state) = breakers.get_mut(provider) {
+            if state.open_until.is_some() {
+                info!("Circuit for {} closed", provider);
+            }
+            *state = BreakerState::default();
+        }
+    }
+
+    pub fn record_failure(provider: &'static str) {
+        let mut breakers = BREAKERS.lock().unwrap();
+        let state = breakers.entry(provider).or_default();
+        let settings = &config::get().circuit_breaker;
+        if state.fail(settings, Instant::now()) {
+            warn!("Circuit for {} opened for {}s after {} failure(s)", provider, settings.cooldown_secs, state.consecutive_failures);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::CircuitBreakerConfig;
+
+    const SETTINGS: CircuitBreakerConfig = CircuitBreakerConfig { failure_threshold: 3, cooldown_secs: 30 };
+
+    #[test]
+    fn opens_after_consecutive_failures() {
+        let now = Instant::now();
+        let mut state = BreakerState::default();
+        assert!(!state.fail(&SETTINGS, now));
+        assert!(!state.fail(&SETTINGS, now));
+        assert_eq!(state.admit(now), Some(false));
+        assert!(state.fail(&SETTINGS, now));
+        assert_eq!(state.admit(now), None);
+        assert_eq!(state.admit(now + Duration::from_secs(29)), None);
+    }
+
+    #[test]
+    fn lets_one_trial_through_after_the_cooldown() {
+        let now = Instant::now();
+        let mut state = BreakerState { consecutive_failures: 3, open_until: Some(now), trial_in_flight: false };
+        let later = now + Duration::from_secs(1);
+        assert_eq!(state.admit(later), Some(true));
+        assert_eq!(state.admit(later), None);
+
+        // A failed trial opens the circuit again straight away
+        assert!(state.fail(&SETTINGS, later));
+        assert_eq!(state.open_until, Some(later + Duration::from_secs(30)));
+        assert_eq!(state.admit(later), None);
+        assert_eq!(state.admit(later + Duration::from_secs(30)), Some(true));
+    }
+
+    #[test]
+    fn success_closes_the_circuit() {
+        let provider = "test-success";
+        BREAKERS.lock().unwrap().insert(provider, BreakerState { consecutive_failures: 3, open_until: Some(Instant::now()), trial_in_flight: false });
+        let permit = CircuitBreaker::allow(provider).unwrap();
+        assert!(permit.trial);
+        CircuitBreaker::record_success(provider);
+        drop(permit);
+        let permit = CircuitBreaker::allow(provider).unwrap();
+        assert!(!permit.trial);
+        assert_eq!(BREAKERS.lock().unwrap()[provider].consecutive_failures, 0);
+    }
+
+    #[test]
+    fn dropped_trial_frees_its_slot() {
+        let provider = "test-dropped";
+        BREAKERS.lock().unwrap().insert(provider, BreakerState { consecutive_failures: 3, open_until: Some(Instant::now()), trial_in_flight: false });
+        let permit = CircuitBreaker::allow(provider).unwrap();
+        assert!(CircuitBreaker::allow(provider).is_none());
+        drop(permit);
+        assert!(CircuitBreaker::allow(provider).is_some_and(|permit| permit.trial));
+    }
+}
@@ -0,0 +1,478 @@
+// rate_limit.rs
+use crate::api_keys::{now, AuthenticatedKey};
+use crate::config::{Config, LimitsConfig};
+use crate::interaction::{Interaction, ProviderUsage, Route};
+use crate::tenants::Tenant;
+
+use chrono::{Datelike, Duration as ChronoDuration, NaiveDate, Utc};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Past this many buckets the least recently used go first; an idle bucket refills anyway,
+// so dropping it only forgets a limit that no longer bites.
+const MAX_BUCKETS: usize = 10_000;
+
+struct TokenBucket {
+    tokens: f64,
+    capacity: f64,
+    refill_per_sec: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(capacity: u32, per_minute: u32) -> Self {
+        TokenBucket {
+            tokens: capacity as f64,
+            capacity: capacity as f64,
+            refill_per_sec: per_minute as f64 / 60.0,
+            updated: Instant::now(),
+        }
+    }
+
+    // Add what has refilled since the last look, then say how long until a token is
+    // available, if none is now.
+    fn wait(&mut self) -> Option<Duration> {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            None
+        } else if self.refill_per_sec > 0.0 {
+            Some(Duration::from_secs_f64((1.0 - self.tokens) / self.refill_per_sec))
+        } else {
+            Some(Duration::from_secs(60))
+        }
+    }
+
+    // Take one token; call after `wait` found one.
+    fn take(&mut self) -> u32 {
+        self.tokens -= 1.0;
+        self.tokens as u32
+    }
+}
+
+// Token buckets by id, in order of last use.
+#[derive(Default)]
+struct Buckets {
+    buckets: HashMap<String, (TokenBucket, u64)>,
+    by_use: BTreeMap<u64, String>,
+    uses: u64,
+}
+
+impl Buckets {
+    fn get(&mut self, id: &str) -> Option<&mut TokenBucket> {
+        self.uses += 1;
+        let (bucket, used) = self.buckets.get_mut(id)?;
+        self.by_use.remove(used);
+        self.by_use.insert(self.uses, id.to_string());
+        *used = self.uses;
+        Some(bucket)
+    }
+
+    fn get_or_insert(&mut self, id: &str, new: impl FnOnce() -> TokenBucket) -> &mut TokenBucket {
+        if !self.buckets.contains_key(id) {
+            while self.buckets.len() >= MAX_BUCKETS {
+                let Some((_, oldest)) = self.by_use.pop_first() else { break };
+                self.buckets.remove(&oldest);
+            }
+            self.buckets.insert(id.to_string(), (new(), 0));
+        }
+        self.get(id).expect("the bucket was just inserted")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitScope {
+    Key,
+    Session,
+    // Quotas shared by all keys of a tenant
+    Tenant,
+}
+
+impl LimitScope {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LimitScope::Key => "key",
+            LimitScope::Session => "session",
+            LimitScope::Tenant => "tenant",
+        }
+    }
+}
+
+// Outcome of a rate limit check, reported to clients through X-RateLimit-* headers.
+pub struct RateStatus {
+    pub limit: u32,
+    pub remaining: u32,
+}
+
+pub struct RateLimited {
+    pub scope: LimitScope,
+    pub limit: u32,
+    pub retry_after: Duration,
+}
+
+// Token buckets per API key and per end-user session of that key.
+pub struct RateLimiter {
+    key_per_minute: u32,
+    key_burst: u32,
+    session_per_minute: u32,
+    session_burst: u32,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    // The key limits apply to each key unless it sets its own;
+    // the session limits apply to each session within a key.
+    pub fn new(limits: &LimitsConfig) -> Self {
+        RateLimiter {
+            key_per_minute: limits.key_requests_per_minute,
+            key_burst: limits.key_burst,
+            session_per_minute: limits.session_requests_per_minute,
+            session_burst: limits.session_burst,
+            buckets: Mutex::new(Buckets::default()),
+        }
+    }
+
+    pub fn check(&self, key: &AuthenticatedKey, session: &str) -> Result<RateStatus, RateLimited> {
+        let key_per_minute = key.limits.requests_per_minute.unwrap_or(self.key_per_minute);
+        let key_burst = key.limits.burst.unwrap_or(self.key_burst).max(1);
+
+        let session_burst = self.session_burst.max(1);
+
+        let mut buckets = self.buckets.lock().unwrap();
+        let key_id = format!("key:{}", key.id);
+        let key_bucket = buckets.get_or_insert(&key_id, || TokenBucket::new(key_burst, key_per_minute));
+        // Pick up limit changes made through the admin API
+        key_bucket.capacity = key_burst as f64;
+        key_bucket.refill_per_sec = key_per_minute as f64 / 60.0;
+        if let Some(retry_after) = key_bucket.wait() {
+            return Err(RateLimited { scope: LimitScope::Key, limit: key_burst, retry_after });
+        }
+
+        // Sessions are named by the client, so a bucket is only made for one the key admits;
+        // until then the session has a full bucket anyway
+        let session_id = format!("session:{}:{}", key.id, session);
+        if let Some(retry_after) = buckets.get(&session_id).and_then(TokenBucket::wait) {
+            return Err(RateLimited { scope: LimitScope::Session, limit: session_burst, retry_after });
+        }
+
+        // Both buckets had a token before either is debited, so a rejected request costs nothing
+        let remaining = buckets.get(&key_id).map(TokenBucket::take).unwrap_or_default();
+        buckets.get_or_insert(&session_id, || TokenBucket::new(session_burst, self.session_per_minute)).take();
+        Ok(RateStatus { limit: key_burst, remaining })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaKind {
+    Tokens,
+    Images,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaPeriod {
+    Daily,
+    Monthly,
+}
+
+impl QuotaKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            QuotaKind::Tokens => "tokens",
+            QuotaKind::Images => "images",
+        }
+    }
+}
+
+impl QuotaPeriod {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            QuotaPeriod::Daily => "daily",
+            QuotaPeriod::Monthly => "monthly",
+        }
+    }
+}
+
+pub struct QuotaExceeded {
+    pub scope: LimitScope,
+    pub kind: QuotaKind,
+    pub period: QuotaPeriod,
+    pub limit: u64,
+    // Unix timestamp (seconds) at which the period rolls over
+    pub reset_at: u64,
+}
+
+impl QuotaExceeded {
+    pub fn retry_after(&self) -> Duration {
+        Duration::from_secs(self.reset_at.saturating_sub(now()).max(1))
+    }
+
+    pub fn message(&self) -> String {
+        format!("{} {} quota of {} exhausted for this {}", self.period.as_str(), self.kind.as_str(), self.limit, self.scope.as_str())
+    }
+}
+
+// What one key has used in the current day and month (UTC).
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct KeyUsage {
+    day: String,
+    day_tokens: u64,
+    day_images: u64,
+    month: String,
+    month_tokens: u64,
+    month_images: u64,
+}
+
+impl KeyUsage {
+    // Zero the counters of any period that has ended.
+    fn roll_over(&mut self, day: &str, month: &str) {
+        if self.day != day {
+            self.day = day.to_string();
+            self.day_tokens = 0;
+            self.day_images = 0;
+        }
+        if self.month != month {
+            self.month = month.to_string();
+            self.month_tokens = 0;
+            self.month_images = 0;
+        }
+    }
+}
+
+struct QuotaLimits {
+    daily_tokens: Option<u64>,
+    monthly_tokens: Option<u64>,
+    daily_images: Option<u64>,
+    monthly_images: Option<u64>,
+}
+
+// Daily and monthly budgets for chat tokens and generated images, per API key and pooled
+// per tenant (under a `tenant:<id>` entry). Usage is persisted (quota_usage.json in the data directory) so restarts don't reset it;
+// `flush` writes it out, on an interval and at shutdown, rather than on every request.
+pub struct QuotaTracker {
+    path: PathBuf,
+    defaults: QuotaLimits,
+    usage: Mutex<HashMap<String, KeyUsage>>,
+    // Usage changed since the last flush
+    dirty: AtomicBool,
+}
+
+impl QuotaTracker {
+    pub fn load(config: &Config) -> io::Result<Self> {
+        let path = config.data_file("quota_usage.json");
+        let usage = if path.exists() {
+            let contents = fs::read_to_string(&path)?;
+            serde_json::from_str(&contents)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))?
+        } else {
+            HashMap::new()
+        };
+        Ok(QuotaTracker {
+            path,
+            defaults: QuotaLimits {
+                daily_tokens: config.limits.daily_tokens,
+                monthly_tokens: config.limits.monthly_tokens,
+                daily_images: config.limits.daily_images,
+                monthly_images: config.limits.monthly_images,
+            },
+            usage: Mutex::new(usage),
+            dirty: AtomicBool::new(false),
+        })
+    }
+
+    fn limits_for(&self, key: &AuthenticatedKey) -> QuotaLimits {
+        QuotaLimits {
+            daily_tokens: key.limits.daily_tokens.or(self.defaults.daily_tokens),
+            monthly_tokens: key.limits.monthly_tokens.or(self.defaults.monthly_tokens),
+            daily_images: key.limits.daily_images.or(self.defaults.daily_images),
+            monthly_images: key.limits.monthly_images.or(self.defaults.monthly_images),
+        }
+    }
+
+    // Refuse work once a budget is used up, the key's or its tenant's. Token usage is only
+    // known afterwards, so the last request of a period may overshoot the token quota.
+    pub fn check(&self, key: &AuthenticatedKey, tenant: &Tenant, route: Route) -> Result<(), QuotaExceeded> {
+        let tenant_limits = QuotaLimits {
+            daily_tokens: tenant.quotas.daily_tokens,
+            monthly_tokens: tenant.quotas.monthly_tokens,
+            daily_images: tenant.quotas.daily_images,
+            monthly_images: tenant.quotas.monthly_images,
+        };
+        let (day, month) = current_periods();
+        let mut usage = self.usage.lock().unwrap();
+        for (scope, id, limits) in [
+            (LimitScope::Key, key.id.clone(), self.limits_for(key)),
+            (LimitScope::Tenant, tenant_usage_id(&tenant.id), tenant_limits),
+        ] {
+            let usage = usage.entry(id.clone()).or_default();
+            usage.roll_over(&day, &month);
+
+            let checks = match route {
+                Route::Generate => [
+                    (QuotaKind::Images, QuotaPeriod::Daily, usage.day_images, limits.daily_images),
+                    (QuotaKind::Images, QuotaPeriod::Monthly, usage.month_images, limits.monthly_images),
+                ],
+                Route::Chat | Route::Vision => [
+                    (QuotaKind::Tokens, QuotaPeriod::Daily, usage.day_tokens, limits.daily_tokens),
+                    (QuotaKind::Tokens, QuotaPeriod::Monthly, usage.month_tokens, limits.monthly_tokens),
+                ],
+            };
+            for (kind, period, used, limit) in checks {
+                if let Some(limit) = limit {
+                    if used >= limit {
+                        info!("{} exhausted its {} {} quota ({})", id, period.as_str(), kind.as_str(), limit);
+                        return Err(QuotaExceeded { scope, kind, period, limit, reset_at: reset_at(period) });
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Count a completed interaction against its key's and tenant's budgets.
+    pub fn record(&self, key: &AuthenticatedKey, interaction: &Interaction) {
+        self.add(key, interaction.total_tokens(), interaction.generated_images());
+    }
+
+    // Count a provider call made outside an interaction, e.g. in the background.
+    pub fn record_call(&self, key: &AuthenticatedKey, usage: &ProviderUsage) {
+        self.add(key, usage.tokens.map(|tokens| tokens.total_tokens).unwrap_or(0), usage.images);
+    }
+
+    fn add(&self, key: &AuthenticatedKey, tokens: u64, images: u64) {
+        if tokens == 0 && images == 0 {
+            return;
+        }
+
+        let (day, month) = current_periods();
+        let mut usage = self.usage.lock().unwrap();
+        for id in [key.id.clone(), tenant_usage_id(&key.tenant)] {
+            let entry = usage.entry(id).or_default();
+            entry.roll_over(&day, &month);
+            entry.day_tokens += tokens;
+            entry.month_tokens += tokens;
+            entry.day_images += images;
+            entry.month_images += images;
+        }
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    // Persist usage recorded since the last flush. Only the flushing thread writes the file.
+    pub fn flush(&self) {
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return;
+        }
+        let json = match serde_json::to_string_pretty(&*self.usage.lock().unwrap()) {
+            Ok(json) => json,
+            Err(e) => {
+                error!("Failed to serialise quota usage: {}", e);
+                return;
+            }
+        };
+        if let Err(e) = self.save(json) {
+            self.dirty.store(true, Ordering::Relaxed);
+            error!("Failed to persist quota usage to {}: {}", self.path.display(), e);
+        }
+    }
+
+    fn save(&self, json: String) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp_path = self.path.with_extension("json.tmp");
+        fs::write(&tmp_path, json)?;
+        fs::rename(&tmp_path, &self.path)
+    }
+}
+
+fn tenant_usage_id(tenant: &str) -> String {
+    format!("tenant:{}", tenant)
+}
+
+fn current_periods() -> (String, String) {
+    let today = Utc::now().date_naive();
+    (today.format("%Y-%m-%d").to_string(), today.format("%Y-%m").to_string())
+}
+
+// Start of the next day or month (UTC) as a unix timestamp.
+fn reset_at(period: QuotaPeriod) -> u64 {
+    let today = Utc::now().date_naive();
+    let next = match period {
+        QuotaPeriod::Daily => today + ChronoDuration::days(1),
+        QuotaPeriod::Monthly => {
+            let (year, month) = if today.month() == 12 { (today.year() + 1, 1) } else { (today.year(), today.month() + 1) };
+            NaiveDate::from_ymd_opt(year, month, 1).unwrap_or(today)
+        }
+    };
+    next.and_hms_opt(0, 0, 0).map(|start| start.and_utc().timestamp() as u64).unwrap_or_else(now)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api_keys::KeyLimits;
+
+    fn limiter(key_burst: u32, session_burst: u32) -> RateLimiter {
+        RateLimiter { key_per_minute: 0, key_burst, session_per_minute: 0, session_burst, buckets: Mutex::new(Buckets::default()) }
+    }
+
+    fn key() -> AuthenticatedKey {
+        AuthenticatedKey {
+            id: "k1".to_string(),
+            name: "test".to_string(),
+            scopes: Vec::new(),
+            limits: KeyLimits::default(),
+            session: None,
+            persona: None,
+            tenant: "default".to_string(),
+            subject: None,
+        }
+    }
+
+    #[test]
+    fn rejected_requests_make_no_session_buckets() {
+        let limiter = limiter(2, 5);
+        assert_eq!(limiter.check(&key(), "a").ok().unwrap().remaining, 1);
+        assert_eq!(limiter.check(&key(), "b").ok().unwrap().remaining, 0);
+        for session in ["c", "d", "e"] {
+            let limited = limiter.check(&key(), session).err().unwrap();
+            assert_eq!((limited.scope, limited.limit), (LimitScope::Key, 2));
+        }
+        assert_eq!(limiter.buckets.lock().unwrap().buckets.len(), 3);
+    }
+
+    #[test]
+    fn sessions_are_limited_within_the_key() {
+        let limiter = limiter(10, 0);
+        assert!(limiter.check(&key(), "a").is_ok());
+        let limited = limiter.check(&key(), "a").err().unwrap();
+        // Reported as the burst the bucket was built with
+        assert_eq!((limited.scope, limited.limit), (LimitScope::Session, 1));
+        assert!(limiter.check(&key(), "b").is_ok());
+        // The rejected request was not taken from the key
+        assert_eq!(limiter.check(&key(), "c").ok().unwrap().remaining, 7);
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used_bucket() {
+        let mut buckets = Buckets::default();
+        for i in 0..MAX_BUCKETS {
+            buckets.get_or_insert(&i.to_string(), || TokenBucket::new(1, 0));
+        }
+        // Bucket 0 is used again, so 1 is now the oldest
+        buckets.get("0").unwrap().take();
+        buckets.get_or_insert("new", || TokenBucket::new(1, 0));
+        assert_eq!(buckets.buckets.len(), MAX_BUCKETS);
+        assert_eq!(buckets.by_use.len(), MAX_BUCKETS);
+        assert!(buckets.get("1").is_none());
+        assert_eq!(buckets.get("0").unwrap().tokens, 0.0);
+    }
+}
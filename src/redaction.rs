@@ -0,0 +1,190 @@
+// redaction.rs
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::io::{self, Write};
+use tracing_subscriber::fmt::MakeWriter;
+
+lazy_static! {
+    // Applied in order; secrets first so their digits are not half-matched as phone numbers
+    static ref SECRET_PATTERNS: Vec<(Regex, &'static str)> = vec![
+        (Regex::new(r"\beyJ[A-Za-z0-9_-]+\.[A-Za-z0-9_-]+\.[A-Za-z0-9_-]+").unwrap(), "[REDACTED_JWT]"),
+        (Regex::new(r"(?i)\bbearer\s+[A-Za-z0-9._~+/=-]+").unwrap(), "Bearer [REDACTED]"),
+        (Regex::new(r"\b(?:sk|gsk|fana|pk|rk)[-_][A-Za-z0-9_-]{16,}").unwrap(), "[REDACTED_KEY]"),
+    ];
+    // `api_key=...`, `"password": "..."` and friends, keeping the name
+    static ref KEY_VALUE: Regex = Regex::new(
+        r#"(?i)\b(api[_-]?key|access[_-]?token|secret|password|authorization)(\\?"?\s*[:=]\s*\\?"?)[^\s"'\\,}&]+"#
+    )
+    .unwrap();
+    static ref EMAIL: Regex = Regex::new(r"\b[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}\b").unwrap();
+    static ref CARD: Regex = Regex::new(r"\b\d(?:[ -]?\d){12,18}\b").unwrap();
+    static ref PHONE: Regex = Regex::new(
+        r"(?:\+\d{1,3}[\s.-]?(?:\(\d{1,4}\)|\d{1,4})(?:[\s.-]?\d{2,4}){2,4}\b|\(?\b\d{3}\)?[\s.-]\d{3}[\s.-]\d{4}\b)"
+    )
+    .unwrap();
+    static ref CONTENT_MODE: ContentMode = crate::config::get().logging.content_mode;
+}
+
+// Mask credentials and personal data in a log line.
+pub fn redact(text: &str) -> Cow<'_, str> {
+    let mut text = Cow::Borrowed(text);
+    for (pattern, replacement) in SECRET_PATTERNS.iter() {
+        replace(&mut text, pattern, |_| replacement.to_string());
+    }
+    replace(&mut text, &KEY_VALUE, |caps| format!("{}{}[REDACTED]", &caps[1], &caps[2]));
+    replace(&mut text, &EMAIL, |_| "[EMAIL]".to_string());
+    replace(&mut text, &CARD, |caps| {
+        if luhn_valid(&caps[0]) { "[CARD]".to_string() } else { caps[0].to_string() }
+    });
+    replace(&mut text, &PHONE, |_| "[PHONE]".to_string());
+    text
+}
+
+fn replace(text: &mut Cow<'_, str>, pattern: &Regex, replacement: impl Fn(&Captures) -> String) {
+    if let Cow::Owned(replaced) = pattern.replace_all(text, |caps: &Captures| replacement(caps)) {
+        *text = Cow::Owned(replaced);
+    }
+}
+
+fn luhn_valid(number: &str) -> bool {
+    let digits: Vec<u32> = number.chars().filter_map(|c| c.to_digit(10)).collect();
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, &digit)| match i % 2 {
+            0 => digit,
+            _ if digit * 2 > 9 => digit * 2 - 9,
+            _ => digit * 2,
+        })
+        .sum();
+    sum.is_multiple_of(10)
+}
+
+// How message content (user input, model output, payloads) appears in logs (logging.content_mode).
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentMode {
+    // The content itself, still passed through redaction
+    #[default]
+    Full,
+    // A short SHA-256 prefix, enough to correlate identical messages
+    Hash,
+    Length,
+}
+
+// Render message content for a log line according to the configured content mode.
+pub fn content(text: &str) -> String {
+    match *CONTENT_MODE {
+        ContentMode::Full => text.to_string(),
+        ContentMode::Hash => {
+            let digest: String = Sha256::digest(text.as_bytes()).iter().take(6).map(|byte| format!("{:02x}", byte)).collect();
+            format!("[sha256:{} len:{}]", digest, text.chars().count())
+        }
+        ContentMode::Length => format!("[len:{}]", text.chars().count()),
+    }
+}
+
+// Wraps the log writer so every formatted event is redacted before it reaches the file.
+pub struct Redacting<M>(pub M);
+
+impl<'a, M: MakeWriter<'a>> MakeWriter<'a> for Redacting<M> {
+    type Writer = RedactingWriter<M::Writer>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        RedactingWriter(self.0.make_writer())
+    }
+}
+
+pub struct RedactingWriter<W>(W);
+
+impl<W: Write> Write for RedactingWriter<W> {
+    // The fmt layer writes each event in one call, so patterns are never split across writes.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let text = String::from_utf8_lossy(buf);
+        self.0.write_all(redact(&text).as_bytes())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_card_numbers() {
+        assert!(luhn_valid("4111 1111 1111 1111"));
+        assert!(luhn_valid("5500-0000-0000-0004"));
+        assert!(luhn_valid("378282246310005"));
+        assert!(!luhn_valid("4111 1111 1111 1112"));
+        assert!(!luhn_valid("1234567890123"));
+    }
+
+    #[test]
+    fn masks_secrets_keeping_names() {
+        assert_eq!(redact("sent Bearer abc.def-123"), "sent Bearer [REDACTED]");
+        assert!(!redact("Authorization: Bearer abc.def-123").contains("abc"));
+        assert_eq!(redact("token eyJhbGciOiJIUzI1NiJ9.eyJzdWIiOiJhIn0.c2ln end"), "token [REDACTED_JWT] end");
+        assert_eq!(redact("key fana_0123456789abcdef0123 used"), "key [REDACTED_KEY] used");
+        assert_eq!(redact("url?api_key=s3cr3t&x=1"), "url?api_key=[REDACTED]&x=1");
+        assert_eq!(redact(r#"{"password": "hunter2", "user": "ada"}"#), r#"{"password": "[REDACTED]", "user": "ada"}"#);
+    }
+
+    #[test]
+    fn masks_secrets_in_json_log_lines() {
+        use std::sync::{Arc, Mutex};
+
+        #[derive(Clone, Default)]
+        struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+        impl Write for Buffer {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        impl<'a> MakeWriter<'a> for Buffer {
+            type Writer = Buffer;
+
+            fn make_writer(&'a self) -> Self::Writer {
+                self.clone()
+            }
+        }
+
+        let buffer = Buffer::default();
+        let subscriber = tracing_subscriber::fmt().json().with_writer(Redacting(buffer.clone())).finish();
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!("Payload: {}", r#"{"password": "hunter2", "user": "ada"}"#);
+        });
+        let line = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        assert!(line.contains(r#"\"password\": \"[REDACTED]\""#), "{}", line);
+        assert!(!line.contains("hunter2"));
+    }
+
+    #[test]
+    fn masks_personal_data() {
+        assert_eq!(redact("mail ada@example.com now"), "mail [EMAIL] now");
+        assert_eq!(redact("card 4111 1111 1111 1111 ok"), "card [CARD] ok");
+        assert_eq!(redact("call +351 912 345 678 or (555) 123-4567"), "call [PHONE] or [PHONE]");
+    }
+
+    #[test]
+    fn leaves_other_text_alone() {
+        assert!(matches!(redact("nothing to hide here"), Cow::Borrowed(_)));
+        // Long numbers that fail the checksum are not cards
+        assert_eq!(redact("order 4111111111111112"), "order 4111111111111112");
+        assert_eq!(redact("took 1234 ms"), "took 1234 ms");
+    }
+}
@@ -36,6 +36,13 @@ impl SessionManager {
         session_id
     }
 
+    // Point an address at an existing session id, e.g. one a client asked to resume.
+    pub fn bind_session(&mut self, ip_addr: IpAddr, session_id: Uuid) -> Uuid {
+        self.sessions.insert(ip_addr, session_id);
+        self.session_data.entry(session_id).or_default();
+        session_id
+    }
+
     pub fn get_session(&mut self, session_id: &Uuid) -> Option<&mut Vec<Value>> {
         self.session_data.get_mut(session_id)
     }
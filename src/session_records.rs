@@ -0,0 +1,118 @@
+// session_records.rs
+use log::{error, info};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use uuid::Uuid;
+
+// One line of a record file: what was recorded for a session.
+#[derive(Serialize, Deserialize)]
+struct Line<T> {
+    session: Uuid,
+    #[serde(flatten)]
+    record: T,
+}
+
+// Something recorded once per session (its persona, its tenant), appended as a JSON line
+// by a thread of its own so starting a session never waits on the disk. The file only
+// grows by the new session's line.
+pub struct SessionRecords<T> {
+    sender: mpsc::Sender<Line<T>>,
+}
+
+impl<T: Serialize + DeserializeOwned + Send + 'static> SessionRecords<T> {
+    // Read what was recorded before, and start the writer.
+    pub fn open(path: PathBuf) -> io::Result<(Self, HashMap<Uuid, T>)> {
+        let records = read(&path)?;
+        let (sender, receiver) = mpsc::channel::<Line<T>>();
+        std::thread::spawn(move || {
+            while let Ok(line) = receiver.recv() {
+                // Whatever else arrived meanwhile goes out with it
+                let lines: Vec<Line<T>> = std::iter::once(line).chain(receiver.try_iter()).collect();
+                if let Err(e) = append(&path, &lines) {
+                    error!("Failed to record {} session(s) in {}: {}", lines.len(), path.display(), e);
+                }
+            }
+        });
+        Ok((SessionRecords { sender }, records))
+    }
+
+    pub fn append(&self, session: Uuid, record: T) {
+        if self.sender.send(Line { session, record }).is_err() {
+            error!("The writer of session {} is gone; the record is lost", session);
+        }
+    }
+}
+
+fn read<T: DeserializeOwned>(path: &Path) -> io::Result<HashMap<Uuid, T>> {
+    let mut records = HashMap::new();
+    if !path.exists() {
+        return Ok(records);
+    }
+    let reader = BufReader::new(fs::File::open(path)?);
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<Line<T>>(&line) {
+            Ok(line) => {
+                records.insert(line.session, line.record);
+            }
+            Err(e) => error!("Skipping malformed session record at {}:{}: {}", path.display(), number + 1, e),
+        }
+    }
+    info!("Loaded {} session records from {}", records.len(), path.display());
+    Ok(records)
+}
+
+fn append<T: Serialize>(path: &Path, lines: &[Line<T>]) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut contents = String::new();
+    for line in lines {
+        contents.push_str(&serde_json::to_string(line)?);
+        contents.push('\n');
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(contents.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Record {
+        persona: String,
+    }
+
+    fn line(session: Uuid, persona: &str) -> Line<Record> {
+        Line { session, record: Record { persona: persona.to_string() } }
+    }
+
+    #[test]
+    fn reads_back_appended_lines() {
+        let path = std::env::temp_dir().join(format!("session_records_{}", Uuid::new_v4())).join("records.jsonl");
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        append(&path, &[line(first, "fana")]).unwrap();
+        append(&path, &[line(second, "support"), line(first, "sales")]).unwrap();
+
+        let records: HashMap<Uuid, Record> = read(&path).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[&first].persona, "sales");
+        assert_eq!(records[&second].persona, "support");
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn missing_file_is_empty() {
+        let path = std::env::temp_dir().join(format!("session_records_{}.jsonl", Uuid::new_v4()));
+        assert!(read::<Record>(&path).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,159 @@
+// telemetry.rs
+use crate::config::{LogFormat, LoggingConfig};
+use crate::redaction::Redacting;
+
+use actix_web::dev::ServiceRequest;
+use std::fs;
+use std::io;
+use tracing::Span;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, EnvFilter, Layer};
+use uuid::Uuid;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+// Keeps the log writer (and the OTLP exporter, if any) flushing until shutdown.
+pub struct TelemetryGuard {
+    _writer: WorkerGuard,
+    #[cfg(feature = "otlp")]
+    tracer_provider: Option<opentelemetry_sdk::trace::SdkTracerProvider>,
+}
+
+#[cfg(feature = "otlp")]
+impl Drop for TelemetryGuard {
+    fn drop(&mut self) {
+        if let Some(provider) = self.tracer_provider.take() {
+            let _ = provider.shutdown();
+        }
+    }
+}
+
+// Log to logs/app.log through `tracing`, so every line carries the active request and
+// interaction spans. `log` macros are bridged, and every line is redacted on its way out.
+// logging.filter (or RUST_LOG) sets the filter and the json format writes one object per line.
+// With the `otlp` feature, spans are also exported when OTEL_EXPORTER_OTLP_ENDPOINT is set.
+pub fn init(logging: &LoggingConfig) -> io::Result<TelemetryGuard> {
+    fs::create_dir_all("logs")?;
+    let (writer, guard) = tracing_appender::non_blocking(tracing_appender::rolling::never("logs", "app.log"));
+
+    let filter = EnvFilter::try_new(&logging.filter).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let fmt_layer = if logging.format == LogFormat::Json {
+        fmt::layer().json().with_current_span(true).with_span_list(true).with_writer(Redacting(writer)).boxed()
+    } else {
+        fmt::layer().with_ansi(false).with_writer(Redacting(writer)).boxed()
+    };
+    let registry = tracing_subscriber::registry().with(filter).with(fmt_layer);
+
+    #[cfg(feature = "otlp")]
+    {
+        let tracer_provider = otlp::tracer_provider()?;
+        let otel_layer = tracer_provider.as_ref().map(otlp::layer);
+        registry.with(otel_layer).try_init().map_err(io::Error::other)?;
+        Ok(TelemetryGuard { _writer: guard, tracer_provider })
+    }
+    #[cfg(not(feature = "otlp"))]
+    {
+        registry.try_init().map_err(io::Error::other)?;
+        Ok(TelemetryGuard { _writer: guard })
+    }
+}
+
+// Honour a caller-supplied X-Request-Id if it is reasonable, otherwise mint one.
+pub fn request_id(req: &ServiceRequest) -> String {
+    req.headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|id| {
+            !id.is_empty()
+                && id.len() <= 128
+                && id.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'))
+        })
+        .map(String::from)
+        .unwrap_or_else(|| Uuid::new_v4().to_string())
+}
+
+// Root span of one HTTP request; handlers fill in the session and route once known.
+pub fn request_span(request_id: &str, req: &ServiceRequest) -> Span {
+    tracing::info_span!(
+        "request",
+        request_id = %request_id,
+        method = %req.method(),
+        path = %req.path(),
+        session_id = tracing::field::Empty,
+        route = tracing::field::Empty,
+    )
+}
+
+#[cfg(feature = "otlp")]
+mod otlp {
+    use opentelemetry::trace::TracerProvider;
+    use opentelemetry_otlp::WithExportConfig;
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use opentelemetry_sdk::Resource;
+    use std::env;
+    use std::io;
+    use tracing_subscriber::filter::filter_fn;
+    use tracing_subscriber::registry::LookupSpan;
+    use tracing_subscriber::Layer;
+
+    pub fn tracer_provider() -> io::Result<Option<SdkTracerProvider>> {
+        let Ok(endpoint) = env::var("OTEL_EXPORTER_OTLP_ENDPOINT") else {
+            return Ok(None);
+        };
+        let exporter = opentelemetry_otlp::SpanExporter::builder()
+            .with_http()
+            .with_endpoint(format!("{}/v1/traces", endpoint.trim_end_matches('/')))
+            .build()
+            .map_err(io::Error::other)?;
+        let service_name = env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| "fanallmrust".to_string());
+        Ok(Some(
+            SdkTracerProvider::builder()
+                .with_batch_exporter(exporter)
+                .with_resource(Resource::builder().with_service_name(service_name).build())
+                .build(),
+        ))
+    }
+
+    // Spans only: log events carry message text that has not been through redaction.
+    pub fn layer<S>(provider: &SdkTracerProvider) -> impl Layer<S>
+    where
+        S: tracing::Subscriber + for<'span> LookupSpan<'span>,
+    {
+        tracing_opentelemetry::layer()
+            .with_tracer(provider.tracer("fanallmrust"))
+            .with_filter(filter_fn(|metadata| metadata.is_span()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn id_for(header: Option<&str>) -> String {
+        let mut req = TestRequest::default();
+        if let Some(header) = header {
+            req = req.insert_header((REQUEST_ID_HEADER, header));
+        }
+        request_id(&req.to_srv_request())
+    }
+
+    #[test]
+    fn keeps_reasonable_caller_ids() {
+        assert_eq!(id_for(Some("abc-123_X.y:z")), "abc-123_X.y:z");
+        assert_eq!(id_for(Some("  padded  ")), "padded");
+        assert_eq!(id_for(Some(&"a".repeat(128))), "a".repeat(128));
+    }
+
+    #[test]
+    fn mints_an_id_otherwise() {
+        for header in [None, Some(""), Some("has space"), Some("semi;colon"), Some(&*"a".repeat(129))] {
+            let id = id_for(header);
+            assert!(Uuid::parse_str(&id).is_ok(), "{:?} gave {}", header, id);
+        }
+        assert_ne!(id_for(None), id_for(None));
+    }
+}
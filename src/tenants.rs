@@ -0,0 +1,267 @@
+// tenants.rs
+use crate::api_keys::KeyLimits;
+use crate::config::{Config, TenantConfig, TenantQuotas};
+use crate::knowledge_base::KnowledgeStore;
+use crate::memory::MemoryStore;
+use crate::personas::PersonaRegistry;
+use crate::session_records::SessionRecords;
+use crate::triggers_generate;
+
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+use whatlang::Lang;
+
+// Keys and tokens that don't name a tenant belong here; it is configured by the
+// server-wide settings and keeps the data directory layout of single-tenant deployments.
+pub const DEFAULT_TENANT: &str = "default";
+
+// Provider keys a tenant's requests are billed to.
+#[derive(Debug, Clone, Default)]
+pub struct ProviderCredentials {
+    groq: Option<String>,
+    openai: Option<String>,
+}
+
+impl ProviderCredentials {
+    pub fn api_key(&self, provider: &str) -> Option<&str> {
+        match provider {
+            "groq" => self.groq.as_deref(),
+            "openai" => self.openai.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+// One brand: who it presents itself as, what it may use and where its data lives.
+#[derive(Debug)]
+pub struct Tenant {
+    pub id: String,
+    pub name: String,
+    pub contact_email: String,
+    pub persona: String,
+    personas: Vec<String>,
+    pub credentials: ProviderCredentials,
+    pub key_limits: KeyLimits,
+    pub quotas: TenantQuotas,
+    triggers: Option<Vec<String>>,
+    language_triggers: HashMap<Lang, Vec<String>>,
+    storage_dir: PathBuf,
+    pub knowledge: KnowledgeStore,
+    pub memories: MemoryStore,
+}
+
+impl Tenant {
+    fn default_tenant(config: &Config) -> io::Result<Self> {
+        let storage_dir = config.storage.data_dir.clone();
+        Ok(Tenant {
+            id: DEFAULT_TENANT.to_string(),
+            name: config.prompt.tenant_name.clone(),
+            contact_email: config.prompt.contact_email.clone(),
+            persona: config.personas.default.clone(),
+            personas: Vec::new(),
+            credentials: ProviderCredentials {
+                groq: config.providers.groq.api_key.clone(),
+                openai: config.providers.openai.api_key.clone(),
+            },
+            key_limits: KeyLimits::default(),
+            quotas: TenantQuotas::default(),
+            triggers: None,
+            language_triggers: HashMap::new(),
+            knowledge: KnowledgeStore::open(&storage_dir.join("knowledge"))?,
+            memories: MemoryStore::open(&storage_dir)?,
+            storage_dir,
+        })
+    }
+
+    fn from_config(id: &str, tenant: &TenantConfig, config: &Config, defaults: &Tenant) -> io::Result<Self> {
+        let storage_dir = config.data_file("tenants").join(id);
+        Ok(Tenant {
+            id: id.to_string(),
+            name: tenant.name.clone().unwrap_or_else(|| id.to_string()),
+            contact_email: tenant.contact_email.clone().unwrap_or_else(|| defaults.contact_email.clone()),
+            persona: tenant.persona.clone().unwrap_or_else(|| defaults.persona.clone()),
+            personas: tenant.personas.clone(),
+            // Without keys of its own a tenant is billed to the server's accounts
+            credentials: ProviderCredentials {
+                groq: tenant.groq_api_key.clone().or_else(|| defaults.credentials.groq.clone()),
+                openai: tenant.openai_api_key.clone().or_else(|| defaults.credentials.openai.clone()),
+            },
+            key_limits: tenant.limits.clone(),
+            quotas: tenant.quotas.clone(),
+            triggers: tenant.triggers.as_ref().map(|words| words.iter().map(|word| word.to_lowercase()).collect()),
+            // Codes were checked with the rest of the configuration
+            language_triggers: tenant
+                .language_triggers
+                .iter()
+                .filter_map(|(code, words)| Some((Lang::from_code(code.as_str())?, words.iter().map(|word| word.to_lowercase()).collect())))
+                .collect(),
+            knowledge: KnowledgeStore::open(&storage_dir.join("knowledge"))?,
+            memories: MemoryStore::open(&storage_dir)?,
+            storage_dir,
+        })
+    }
+
+    // Conversation histories, one directory per session.
+    pub fn sessions_dir(&self) -> PathBuf {
+        self.storage_dir.join("user_sessions")
+    }
+
+    pub fn allows_persona(&self, name: &str) -> bool {
+        self.personas.is_empty() || self.personas.iter().any(|persona| persona == name)
+    }
+
+    // Whether a message asks for an image, by this tenant's trigger words if it has any.
+    // Words of the message's language apply on top of the general list.
+    pub fn is_trigger(&self, input: &str, language: Option<Lang>) -> bool {
+        let lowercase = input.to_lowercase();
+        let matches = |triggers: &[String]| triggers.iter().any(|trigger| lowercase.contains(trigger.as_str()));
+        let general = match &self.triggers {
+            Some(triggers) => matches(triggers),
+            None => triggers_generate::contains_trigger_word(input),
+        };
+        general
+            || language.is_some_and(|language| match self.language_triggers.get(&language) {
+                Some(triggers) => matches(triggers),
+                None => triggers_generate::contains_language_trigger_word(input, language),
+            })
+    }
+
+    // A key's own limits, with this tenant's defaults for whatever it leaves unset.
+    pub fn key_limits(&self, limits: &KeyLimits) -> KeyLimits {
+        KeyLimits {
+            requests_per_minute: limits.requests_per_minute.or(self.key_limits.requests_per_minute),
+            burst: limits.burst.or(self.key_limits.burst),
+            daily_tokens: limits.daily_tokens.or(self.key_limits.daily_tokens),
+            monthly_tokens: limits.monthly_tokens.or(self.key_limits.monthly_tokens),
+            daily_images: limits.daily_images.or(self.key_limits.daily_images),
+            monthly_images: limits.monthly_images.or(self.key_limits.monthly_images),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SessionError {
+    // The session belongs to another tenant; reported as not found so ids can't be probed
+    NotFound(Uuid),
+    // The session belongs to another end user of the tenant
+    Forbidden(Uuid),
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SessionError::NotFound(session_id) => write!(f, "Session {} not found", session_id),
+            SessionError::Forbidden(session_id) => write!(f, "Session {} belongs to another user", session_id),
+        }
+    }
+}
+
+impl std::error::Error for SessionError {}
+
+// Who a session belongs to: the tenant that first used it and, when that was an end user
+// with a JWT, the user (the token's `sub`).
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct SessionClaim {
+    tenant: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    owner: Option<String>,
+}
+
+// Configured tenants, and who each session belongs to (session_tenants.jsonl in the data
+// directory). A session is claimed by the first tenant, and end user, that uses it.
+pub struct TenantRegistry {
+    tenants: HashMap<String, Arc<Tenant>>,
+    records: SessionRecords<SessionClaim>,
+    sessions: Mutex<HashMap<Uuid, SessionClaim>>,
+}
+
+impl TenantRegistry {
+    pub fn load(config: &Config, personas: &PersonaRegistry) -> io::Result<Self> {
+        let default_tenant = Arc::new(Tenant::default_tenant(config)?);
+        let mut tenants = HashMap::new();
+        tenants.insert(DEFAULT_TENANT.to_string(), default_tenant.clone());
+        for (id, tenant_config) in &config.tenants {
+            let tenant = Tenant::from_config(id, tenant_config, config, &default_tenant)?;
+            for persona in std::iter::once(&tenant.persona).chain(&tenant.personas) {
+                if personas.get(persona).is_none() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("Tenant '{}' uses unknown persona '{}'", id, persona),
+                    ));
+                }
+            }
+            if !tenant.allows_persona(&tenant.persona) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Tenant '{}' does not allow its own default persona '{}'", id, tenant.persona),
+                ));
+            }
+            tenants.insert(id.clone(), Arc::new(tenant));
+        }
+
+        let (records, sessions) = SessionRecords::<SessionClaim>::open(config.data_file("session_tenants.jsonl"))?;
+
+        let mut ids: Vec<&String> = tenants.keys().collect();
+        ids.sort();
+        info!("Loaded tenants {:?}", ids);
+        Ok(TenantRegistry { tenants, records, sessions: Mutex::new(sessions) })
+    }
+
+    pub fn get(&self, id: &str) -> Option<Arc<Tenant>> {
+        self.tenants.get(id).cloned()
+    }
+
+    pub fn default_tenant(&self) -> Arc<Tenant> {
+        self.tenants[DEFAULT_TENANT].clone()
+    }
+
+    // Make sure the caller may use the session, without claiming it, so a request that
+    // turns out to be invalid leaves nothing behind.
+    pub fn check_session(&self, session_id: Uuid, tenant: &Tenant, subject: Option<&str>) -> Result<(), SessionError> {
+        let sessions = self.sessions.lock().unwrap();
+        self.authorize(&sessions, session_id, tenant, subject).map(|_| ())
+    }
+
+    // Claim the session for the caller if nobody has used it yet; for requests that passed
+    // validation. Checked again, as another request may have claimed it meanwhile.
+    pub fn claim_session(&self, session_id: Uuid, tenant: &Tenant, subject: Option<&str>) -> Result<(), SessionError> {
+        let mut sessions = self.sessions.lock().unwrap();
+        if self.authorize(&sessions, session_id, tenant, subject)? {
+            let claim = SessionClaim { tenant: tenant.id.clone(), owner: subject.map(String::from) };
+            sessions.insert(session_id, claim.clone());
+            self.records.append(session_id, claim);
+        }
+        Ok(())
+    }
+
+    // Whether the caller may use the session, and whether it is still unclaimed. End users
+    // (callers with a JWT `sub`) only get sessions they started; API keys may use any of
+    // their tenant's. Sessions from before tenants existed live in the default tenant's
+    // directory.
+    fn authorize(&self, sessions: &HashMap<Uuid, SessionClaim>, session_id: Uuid, tenant: &Tenant, subject: Option<&str>) -> Result<bool, SessionError> {
+        let claim = match sessions.get(&session_id) {
+            Some(claim) => claim.clone(),
+            None if self.default_tenant().sessions_dir().join(session_id.to_string()).exists() => {
+                SessionClaim { tenant: DEFAULT_TENANT.to_string(), owner: None }
+            }
+            None => return Ok(true),
+        };
+        if claim.tenant != tenant.id {
+            info!("Tenant {} was refused session {} of tenant {}", tenant.id, session_id, claim.tenant);
+            return Err(SessionError::NotFound(session_id));
+        }
+        match subject {
+            Some(subject) if claim.owner.as_deref() != Some(subject) => {
+                info!("User {} of tenant {} was refused session {}", subject, tenant.id, session_id);
+                Err(SessionError::Forbidden(session_id))
+            }
+            _ => Ok(false),
+        }
+    }
+}
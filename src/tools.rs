@@ -0,0 +1,466 @@
+// tools.rs
+use crate::api_keys::now;
+use crate::config::{self, Config};
+use crate::image_diffusion::GenerateImageTool;
+use crate::image_vision::AnalyzeImageTool;
+use crate::interaction::{ProviderUsage, Route};
+use crate::redaction;
+use crate::tenants::Tenant;
+
+use futures::future::LocalBoxFuture;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::error::Error;
+use std::io;
+use std::path::Path;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use tokio::fs::OpenOptions;
+use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
+
+pub type ToolError = Box<dyn Error>;
+
+static REGISTRY: OnceLock<ToolRegistry> = OnceLock::new();
+
+// The conversation a tool was called from.
+pub struct ToolContext<'a> {
+    pub tenant: &'a Tenant,
+    pub session_id: Uuid,
+    // What the caller may use besides chat (see PromptVariables::routes)
+    pub routes: &'a [Route],
+}
+
+// What a tool produced. The content is handed back to the model; images are shown to
+// the user with the answer.
+#[derive(Default)]
+pub struct ToolOutput {
+    pub content: String,
+    pub image_urls: Vec<String>,
+    pub usage: Vec<ProviderUsage>,
+}
+
+impl From<String> for ToolOutput {
+    fn from(content: String) -> Self {
+        ToolOutput { content, ..Default::default() }
+    }
+}
+
+// A function the chat model may call. Parameters are described by a JSON schema object,
+// as in the OpenAI `tools` field, and the model's arguments arrive as that object.
+pub trait Tool: Send + Sync {
+    fn name(&self) -> &str;
+    fn description(&self) -> &str;
+    fn parameters(&self) -> Value;
+    // The capability the tool exercises; it is only offered to callers who may use it
+    fn route(&self) -> Route {
+        Route::Chat
+    }
+    // Whether the tenant's chats are offered the tool
+    fn offered_to(&self, _tenant: &str) -> bool {
+        true
+    }
+    fn call<'a>(&'a self, arguments: Value, context: &'a ToolContext<'a>) -> LocalBoxFuture<'a, Result<ToolOutput, ToolError>>;
+}
+
+// A call the model asked for, as it appears in the assistant message.
+#[derive(Debug, Clone)]
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    // JSON text, exactly as the model wrote it
+    pub arguments: String,
+}
+
+impl ToolCall {
+    pub fn to_message(&self) -> Value {
+        json!({
+            "id": self.id,
+            "type": "function",
+            "function": { "name": self.name, "arguments": self.arguments },
+        })
+    }
+}
+
+// One executed call, recorded in the session and returned to API callers.
+#[derive(Serialize, Debug, Clone)]
+pub struct ToolCallRecord {
+    pub name: String,
+    #[serde(skip)]
+    pub route: Route,
+    pub arguments: Value,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub image_urls: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub duration_ms: u128,
+    #[serde(skip)]
+    pub usage: Vec<ProviderUsage>,
+}
+
+// The tools enabled by configuration.
+pub struct ToolRegistry {
+    tools: Vec<Box<dyn Tool>>,
+}
+
+impl ToolRegistry {
+    fn builtin() -> Vec<Box<dyn Tool>> {
+        vec![Box::new(CurrentTime), Box::new(Calculator), Box::new(GenerateImageTool), Box::new(AnalyzeImageTool)]
+    }
+
+    // Whether a tool gives the model this capability.
+    pub fn handles(&self, route: Route) -> bool {
+        self.tools.iter().any(|tool| tool.route() == route)
+    }
+}
+
+// The tools offered to one request: those shared with its tenant that the caller's
+// routes allow, or none when tools are off.
+pub fn for_request(tenant: &Tenant, routes: &[Route]) -> Vec<&'static dyn Tool> {
+    if !config::get().features.tools {
+        return Vec::new();
+    }
+    get()
+        .tools
+        .iter()
+        .map(|tool| tool.as_ref())
+        .filter(|tool| tool.offered_to(&tenant.id))
+        .filter(|tool| tool.route() == Route::Chat || routes.contains(&tool.route()))
+        .collect()
+}
+
+// The `tools` field of a chat request.
+pub fn definitions(tools: &[&dyn Tool]) -> Vec<Value> {
+    tools
+        .iter()
+        .map(|tool| {
+            json!({
+                "type": "function",
+                "function": {
+                    "name": tool.name(),
+                    "description": tool.description(),
+                    "parameters": tool.parameters(),
+                },
+            })
+        })
+        .collect()
+}
+
+// Build the registry from the built-in tools and those listed by MCP servers, filtered by
+// `tools.enabled`. An unknown name is a startup error, unless it belongs to an MCP server,
+// which may just be down.
+pub fn init(config: &Config, external: Vec<Box<dyn Tool>>) -> io::Result<&'static ToolRegistry> {
+    let mut available = ToolRegistry::builtin();
+    for tool in external {
+        if available.iter().any(|existing| existing.name() == tool.name()) {
+            warn!("Ignoring a second tool named {}", tool.name());
+            continue;
+        }
+        available.push(tool);
+    }
+    let enabled = &config.tools.enabled;
+    let from_mcp = |name: &str| config.tools.mcp.keys().any(|server| name.starts_with(&format!("{}__", server)));
+    for name in enabled.iter().filter(|name| !available.iter().any(|tool| tool.name() == name.as_str())) {
+        if !from_mcp(name) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("tools.enabled: unknown tool '{}'", name)));
+        }
+        warn!("tools.enabled: {} is unavailable", name);
+    }
+    if !enabled.is_empty() {
+        available.retain(|tool| enabled.iter().any(|name| name == tool.name()));
+    }
+    let names: Vec<&str> = available.iter().map(|tool| tool.name()).collect();
+    if config.features.tools {
+        info!("Chat tools: {:?}", names);
+    }
+    Ok(REGISTRY.get_or_init(|| ToolRegistry { tools: available }))
+}
+
+pub fn get() -> &'static ToolRegistry {
+    REGISTRY.get().expect("tools are initialised at startup")
+}
+
+// Run one call. Failures are reported to the model as the result, so it can correct its
+// arguments or answer without the tool, rather than failing the whole message.
+pub async fn execute(call: &ToolCall, context: &ToolContext<'_>) -> (String, ToolCallRecord) {
+    let tool = for_request(context.tenant, context.routes).into_iter().find(|tool| tool.name() == call.name);
+    let config = config::get();
+    let started = Instant::now();
+    let arguments: Result<Value, ToolError> = if call.arguments.trim().is_empty() {
+        Ok(json!({}))
+    } else {
+        serde_json::from_str(&call.arguments).map_err(|e| format!("Arguments are not valid JSON: {}", e).into())
+    };
+    let recorded_arguments = arguments.as_ref().cloned().unwrap_or_else(|_| Value::from(call.arguments.clone()));
+
+    let result = match (tool, arguments) {
+        (None, _) => Err(format!("There is no tool named '{}'", call.name).into()),
+        (Some(_), Err(e)) => Err(e),
+        (Some(tool), Ok(arguments)) => {
+            match tokio::time::timeout(Duration::from_secs(config.tools.timeout_secs), tool.call(arguments, context)).await {
+                Ok(result) => result,
+                Err(_) => Err(format!("The tool did not finish within {} seconds", config.tools.timeout_secs).into()),
+            }
+        }
+    };
+
+    let (output, error) = match result {
+        Ok(output) => (output, None),
+        Err(e) => {
+            warn!("Tool {} failed in session {}: {}", call.name, context.session_id, e);
+            (ToolOutput::from(json!({ "error": e.to_string() }).to_string()), Some(e.to_string()))
+        }
+    };
+    let content = truncate(output.content, config.tools.max_output_chars);
+    crate::metrics::record_tool_call(&call.name, error.is_none());
+    info!("Tool {} returned: {}", call.name, redaction::content(&content));
+
+    let record = ToolCallRecord {
+        name: call.name.clone(),
+        route: tool.map(|tool| tool.route()).unwrap_or(Route::Chat),
+        arguments: recorded_arguments,
+        image_urls: output.image_urls,
+        error,
+        duration_ms: started.elapsed().as_millis(),
+        usage: output.usage,
+    };
+    if let Err(e) = append_to_session(&context.tenant.sessions_dir(), &context.session_id, &record, &content).await {
+        warn!("Failed to record a tool call in session {}: {}", context.session_id, e);
+    }
+    (content, record)
+}
+
+fn truncate(mut output: String, max_chars: usize) -> String {
+    if let Some((cut, _)) = output.char_indices().nth(max_chars) {
+        output.truncate(cut);
+        output.push_str("\n[truncated]");
+    }
+    output
+}
+
+#[derive(Serialize)]
+struct SessionToolCall<'a> {
+    at: u64,
+    #[serde(flatten)]
+    record: &'a ToolCallRecord,
+    output: &'a str,
+}
+
+// Every call of a session, one JSON object per line (tool_calls.jsonl next to its context).
+async fn append_to_session(sessions_dir: &Path, session_id: &Uuid, record: &ToolCallRecord, output: &str) -> io::Result<()> {
+    let dir = sessions_dir.join(session_id.to_string());
+    tokio::fs::create_dir_all(&dir).await?;
+    let mut line = serde_json::to_string(&SessionToolCall { at: now(), record, output })?;
+    line.push('\n');
+    let mut file = OpenOptions::new().create(true).append(true).open(dir.join("tool_calls.jsonl")).await?;
+    file.write_all(line.as_bytes()).await
+}
+
+// The date and time, which the model otherwise only knows from its system prompt.
+struct CurrentTime;
+
+impl Tool for CurrentTime {
+    fn name(&self) -> &str {
+        "current_time"
+    }
+
+    fn description(&self) -> &str {
+        "Get the current date and time in UTC."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({ "type": "object", "properties": {} })
+    }
+
+    fn call<'a>(&'a self, _arguments: Value, _context: &'a ToolContext<'a>) -> LocalBoxFuture<'a, Result<ToolOutput, ToolError>> {
+        Box::pin(async { Ok(chrono::Utc::now().format("%A, %Y-%m-%d %H:%M:%S UTC").to_string().into()) })
+    }
+}
+
+// Exact arithmetic, which language models are unreliable at.
+struct Calculator;
+
+#[derive(Deserialize)]
+struct CalculatorArguments {
+    expression: String,
+}
+
+impl Tool for Calculator {
+    fn name(&self) -> &str {
+        "calculator"
+    }
+
+    fn description(&self) -> &str {
+        "Evaluate an arithmetic expression with + - * / % ^ and parentheses, e.g. (12.5 * 4) ^ 2 / 3."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "expression": { "type": "string", "description": "The expression to evaluate" }
+            },
+            "required": ["expression"],
+        })
+    }
+
+    fn call<'a>(&'a self, arguments: Value, _context: &'a ToolContext<'a>) -> LocalBoxFuture<'a, Result<ToolOutput, ToolError>> {
+        Box::pin(async move {
+            let arguments: CalculatorArguments = serde_json::from_value(arguments)?;
+            if arguments.expression.len() > MAX_EXPRESSION_LEN {
+                return Err(format!("The expression is longer than {} characters", MAX_EXPRESSION_LEN).into());
+            }
+            let value = Expression::new(&arguments.expression).evaluate()?;
+            Ok(value.to_string().into())
+        })
+    }
+}
+
+// Limits that keep a hostile expression from exhausting the worker's stack
+const MAX_EXPRESSION_LEN: usize = 1000;
+const MAX_EXPRESSION_DEPTH: usize = 64;
+
+// Recursive descent over: sum = product (('+'|'-') product)*, product = power (('*'|'/'|'%') power)*,
+// power = unary ('^' power)?, unary = '-' unary | number | '(' sum ')'.
+struct Expression<'a> {
+    chars: std::iter::Peekable<std::str::CharIndices<'a>>,
+    text: &'a str,
+    // Nested parentheses, powers and signs entered so far
+    depth: usize,
+}
+
+impl<'a> Expression<'a> {
+    fn new(text: &'a str) -> Self {
+        Expression { chars: text.char_indices().peekable(), text, depth: 0 }
+    }
+
+    // Run a rule one level deeper, failing past MAX_EXPRESSION_DEPTH.
+    fn nested(&mut self, rule: fn(&mut Self) -> Result<f64, String>) -> Result<f64, String> {
+        if self.depth >= MAX_EXPRESSION_DEPTH {
+            return Err("Expression too deeply nested".to_string());
+        }
+        self.depth += 1;
+        let value = rule(self);
+        self.depth -= 1;
+        value
+    }
+
+    fn evaluate(mut self) -> Result<f64, String> {
+        let value = self.sum()?;
+        match self.peek() {
+            None if value.is_finite() => Ok(value),
+            None => Err("The result is not a finite number".to_string()),
+            Some(c) => Err(format!("Unexpected '{}'", c)),
+        }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        while self.chars.next_if(|(_, c)| c.is_whitespace()).is_some() {}
+        self.chars.peek().map(|(_, c)| *c)
+    }
+
+    fn sum(&mut self) -> Result<f64, String> {
+        let mut value = self.product()?;
+        while let Some(op @ ('+' | '-')) = self.peek() {
+            self.chars.next();
+            let rhs = self.product()?;
+            value = if op == '+' { value + rhs } else { value - rhs };
+        }
+        Ok(value)
+    }
+
+    fn product(&mut self) -> Result<f64, String> {
+        let mut value = self.power()?;
+        while let Some(op @ ('*' | '/' | '%')) = self.peek() {
+            self.chars.next();
+            let rhs = self.power()?;
+            value = match op {
+                '*' => value * rhs,
+                _ if rhs == 0.0 => return Err("Division by zero".to_string()),
+                '/' => value / rhs,
+                _ => value % rhs,
+            };
+        }
+        Ok(value)
+    }
+
+    fn power(&mut self) -> Result<f64, String> {
+        let base = self.unary()?;
+        if self.peek() == Some('^') {
+            self.chars.next();
+            return Ok(base.powf(self.nested(Self::power)?));
+        }
+        Ok(base)
+    }
+
+    fn unary(&mut self) -> Result<f64, String> {
+        match self.peek() {
+            Some('-') => {
+                self.chars.next();
+                Ok(-self.nested(Self::unary)?)
+            }
+            Some('(') => {
+                self.chars.next();
+                let value = self.nested(Self::sum)?;
+                match self.peek() {
+                    Some(')') => {
+                        self.chars.next();
+                        Ok(value)
+                    }
+                    _ => Err("Missing ')'".to_string()),
+                }
+            }
+            Some(c) if c.is_ascii_digit() || c == '.' => {
+                let start = self.chars.peek().map(|(i, _)| *i).unwrap_or(0);
+                let mut end = start;
+                while let Some((i, c)) = self.chars.next_if(|(_, c)| c.is_ascii_digit() || *c == '.') {
+                    end = i + c.len_utf8();
+                }
+                self.text[start..end].parse().map_err(|_| format!("'{}' is not a number", &self.text[start..end]))
+            }
+            Some(c) => Err(format!("Unexpected '{}'", c)),
+            None => Err("The expression ended early".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn evaluate(text: &str) -> Result<f64, String> {
+        Expression::new(text).evaluate()
+    }
+
+    #[test]
+    fn follows_precedence_and_associativity() {
+        assert_eq!(evaluate("1 + 2 * 3"), Ok(7.0));
+        assert_eq!(evaluate("(1 + 2) * 3"), Ok(9.0));
+        assert_eq!(evaluate("2 ^ 3 ^ 2"), Ok(512.0));
+        assert_eq!(evaluate("10 - 4 - 3"), Ok(3.0));
+        assert_eq!(evaluate("-2 ^ 2"), Ok(4.0));
+        assert_eq!(evaluate("7 % 4 + .5"), Ok(3.5));
+    }
+
+    #[test]
+    fn rejects_malformed_expressions() {
+        assert!(evaluate("1 +").is_err());
+        assert!(evaluate("(1 + 2").is_err());
+        assert!(evaluate("2 x 3").is_err());
+        assert!(evaluate("1..2").is_err());
+        assert_eq!(evaluate("1 / 0"), Err("Division by zero".to_string()));
+        assert_eq!(evaluate("5 % 0"), Err("Division by zero".to_string()));
+        assert!(evaluate("10 ^ 400").is_err());
+    }
+
+    #[test]
+    fn limits_nesting() {
+        let deep = MAX_EXPRESSION_DEPTH + 1;
+        let too_deep = Err("Expression too deeply nested".to_string());
+        assert_eq!(evaluate(&format!("{}1{}", "(".repeat(deep), ")".repeat(deep))), too_deep);
+        assert_eq!(evaluate(&format!("{}1", "-".repeat(deep))), too_deep);
+        assert_eq!(evaluate(&vec!["2"; deep + 1].join("^")), too_deep);
+        assert_eq!(evaluate(&format!("{}1{}", "(".repeat(10), ")".repeat(10))), Ok(1.0));
+    }
+}
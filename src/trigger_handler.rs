@@ -1,35 +1,53 @@
 // trigger_handler.rs
-use crate::image_diffusion::generate_image;
+use crate::image_diffusion::{generate_image, IMAGE_SIZES};
 use log::{info, error};
 use serde_json::json;
 use crate::context_manager::manage_context::ContextManager;
+use crate::interaction::{EventSender, Interaction, Route, StreamEvent};
+use crate::tenants::ProviderCredentials;
 use uuid::Uuid;
 use std::net::IpAddr;
+use whatlang::Lang;
 
 
 
-pub async fn handle_trigger(user_input: &str, context_manager: &mut ContextManager, ip_addr: IpAddr, session_id: &Uuid) -> Result<String, Box<dyn std::error::Error>> {
+pub async fn handle_trigger(user_input: &str, context_manager: &mut ContextManager, ip_addr: IpAddr, session_id: &Uuid, credentials: &ProviderCredentials, language: Option<Lang>, events: Option<&EventSender>) -> Result<Interaction, Box<dyn std::error::Error>> {
     info!("Trigger word detected in user input. Generating image.");
 
-    match generate_image(user_input).await {
-        Ok(image_url) => {
-            let message = format!("\nFANA:\nI've generated an image based on your request.\nYou can view it here: {}", image_url);
-            println!("{}", message);
-            info!("Image generated. URL: {}", image_url);
+    if let Some(events) = events {
+        let _ = events.send(StreamEvent::ImageProgress("started"));
+    }
+    match generate_image(user_input, IMAGE_SIZES[0], language, credentials).await {
+        Ok(image) => {
+            if let Some(events) = events {
+                let _ = events.send(StreamEvent::ImageProgress("completed"));
+            }
+            let message = format!("I've generated an image based on your request.\nYou can view it here: {}", image.url);
+            info!("Image generated. URL: {}", image.url);
         
             // Add the image information to the conversation
             context_manager.add_message(ip_addr, json!({
                 "role": "assistant",
-                "content": format!("{}", image_url)
+                "content": image.url.clone()
             })).await;
             info!("Added analysis result to context for session {}", session_id);
-            Ok(message)
+            Ok(Interaction {
+                session_id: *session_id,
+                route: Route::Generate,
+                provider: "openai".to_string(),
+                model: image.model,
+                content: message,
+                usage: None,
+                image_urls: vec![image.url],
+                sources: Vec::new(),
+                language: None,
+                tool_calls: Vec::new(),
+                auxiliary: image.translation.into_iter().collect(),
+            })
         },
         Err(e) => {
-            let message = format!("\nFANA:\nFailed to generate image: {}", e);
-            println!("{}", message);
             error!("Image generation failed: {}", e);
-            Err(e.into())
+            Err(e)
         }
     }
 }
@@ -1,4 +1,6 @@
 // triggers.rs
+use whatlang::Lang;
+
 pub fn trigger_words() -> Vec<&'static str> {
     vec![
         "generate", "create", "make", "produce", "design", "draw", "build", "elaborate",
@@ -35,3 +37,45 @@ pub fn contains_trigger_word(input: &str) -> bool {
     let triggers = trigger_words();
     triggers.iter().any(|&trigger| input.to_lowercase().contains(trigger))
 }
+
+// Trigger words of messages detected in another language; the English list always applies too.
+pub fn language_trigger_words(language: Lang) -> &'static [&'static str] {
+    match language {
+        Lang::Por => &[
+            "gera", "gerar", "cria", "criar", "desenha", "desenhar", "pinta", "pintar", "ilustra",
+            "ilustrar", "faz uma imagem", "fazer uma imagem", "mostra-me", "quero ver", "imagem de",
+            "foto de", "fotografia de", "retrato de", "desenho de", "ilustração", "paisagem",
+            "no estilo de", "inspirado em", "mais uma", "outra imagem", "troca o", "muda o",
+        ],
+        Lang::Spa => &[
+            "genera", "generar", "crea", "crear", "dibuja", "dibujar", "pinta", "pintar", "ilustra",
+            "ilustrar", "haz una imagen", "muéstrame", "quiero ver", "imagen de", "foto de",
+            "fotografía de", "retrato de", "dibujo de", "ilustración", "paisaje", "al estilo de",
+            "inspirado en", "otra imagen", "cambia el",
+        ],
+        Lang::Fra => &[
+            "génère", "générer", "crée", "créer", "dessine", "dessiner", "peins", "peindre",
+            "illustre", "illustrer", "fais une image", "montre-moi", "je veux voir", "image de",
+            "photo de", "portrait de", "dessin de", "illustration", "paysage", "dans le style de",
+            "inspiré de", "une autre image", "change le",
+        ],
+        Lang::Deu => &[
+            "generiere", "erstelle", "erzeuge", "zeichne", "male", "illustriere", "zeig mir",
+            "ich möchte sehen", "bild von", "foto von", "porträt von", "zeichnung von",
+            "illustration", "landschaft", "im stil von", "inspiriert von", "noch ein bild",
+            "ändere den",
+        ],
+        Lang::Ita => &[
+            "genera", "generare", "crea", "creare", "disegna", "disegnare", "dipingi", "dipingere",
+            "illustra", "illustrare", "fammi vedere", "voglio vedere", "immagine di", "foto di",
+            "ritratto di", "disegno di", "illustrazione", "paesaggio", "nello stile di",
+            "ispirato a", "un'altra immagine", "cambia il",
+        ],
+        _ => &[],
+    }
+}
+
+pub fn contains_language_trigger_word(input: &str, language: Lang) -> bool {
+    let input = input.to_lowercase();
+    language_trigger_words(language).iter().any(|&trigger| input.contains(trigger))
+}
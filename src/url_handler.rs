@@ -1,33 +1,46 @@
 // url_handler.rs
 use crate::image_vision::analyze_image;
 use crate::context_manager::manage_context::ContextManager;
+use crate::interaction::{Interaction, Route};
+use crate::redaction;
+use crate::tenants::ProviderCredentials;
 use log::{info, error};
 use regex::Regex;
 use serde_json::json;
 use uuid::Uuid;
 use std::net::IpAddr;
 
-pub async fn handle_url(url: &str, context_manager: &mut ContextManager, ip_addr: IpAddr, session_id: &Uuid) -> Result<String, Box<dyn std::error::Error>> {
+pub async fn handle_url(url: &str, context_manager: &mut ContextManager, ip_addr: IpAddr, session_id: &Uuid, credentials: &ProviderCredentials) -> Result<Interaction, Box<dyn std::error::Error>> {
     info!("URL detected in user input: {}", url);
 
-    match analyze_image(url).await {
+    match analyze_image(url, None, credentials).await {
         Ok(analysis) => {
-            println!("\nFANA:\nImage analysis: {}", analysis);
-            info!("Image analysis: {}", analysis);
+            info!("Image analysis: {}", redaction::content(&analysis.content));
                 
             // Add the analysis result to the conversation
             context_manager.add_message(ip_addr, json!({
                 "role": "assistant",
-                "content": analysis
+                "content": analysis.content
             })).await;
             
             info!("Added analysis result to context for session {}", session_id);
-            Ok(analysis)
+            Ok(Interaction {
+                session_id: *session_id,
+                route: Route::Vision,
+                provider: analysis.provider,
+                model: analysis.model,
+                content: analysis.content,
+                usage: analysis.usage,
+                image_urls: vec![url.to_string()],
+                sources: Vec::new(),
+                language: None,
+                tool_calls: Vec::new(),
+                auxiliary: Vec::new(),
+            })
         },
         Err(e) => {
-            println!("\nFANA:\n{}", e);
             error!("Image analysis failed: {}", e);
-            Err(e.into())
+            Err(e)
         }
     }
 }
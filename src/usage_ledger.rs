@@ -0,0 +1,474 @@
+// usage_ledger.rs
+use crate::api_keys::{now, AuthenticatedKey};
+use crate::config::Config;
+use crate::interaction::{Interaction, ProviderUsage, Route, TokenUsage};
+use crate::tenants::DEFAULT_TENANT;
+
+use chrono::{DateTime, Utc};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::{mpsc, Mutex, RwLock};
+use std::thread::JoinHandle;
+use uuid::Uuid;
+
+// List prices in USD: per million prompt/completion tokens, or per generated image.
+pub struct ModelPrice {
+    pub model: &'static str,
+    pub prompt_per_million: f64,
+    pub completion_per_million: f64,
+    pub per_image: f64,
+}
+
+pub const PRICES: &[ModelPrice] = &[
+    ModelPrice { model: "mixtral-8x7b-32768", prompt_per_million: 0.24, completion_per_million: 0.24, per_image: 0.0 },
+    ModelPrice { model: "llama3-8b-8192", prompt_per_million: 0.05, completion_per_million: 0.08, per_image: 0.0 },
+    ModelPrice { model: "llama3-70b-8192", prompt_per_million: 0.59, completion_per_million: 0.79, per_image: 0.0 },
+    ModelPrice { model: "gemma-7b-it", prompt_per_million: 0.07, completion_per_million: 0.07, per_image: 0.0 },
+    ModelPrice { model: "gpt-4o", prompt_per_million: 2.50, completion_per_million: 10.00, per_image: 0.0 },
+    ModelPrice { model: "gpt-4o-mini", prompt_per_million: 0.15, completion_per_million: 0.60, per_image: 0.0 },
+    ModelPrice { model: "dall-e-3", prompt_per_million: 0.0, completion_per_million: 0.0, per_image: 0.04 },
+    ModelPrice { model: "text-embedding-3-small", prompt_per_million: 0.02, completion_per_million: 0.0, per_image: 0.0 },
+    ModelPrice { model: "text-embedding-3-large", prompt_per_million: 0.13, completion_per_million: 0.0, per_image: 0.0 },
+    ModelPrice { model: "text-embedding-ada-002", prompt_per_million: 0.10, completion_per_million: 0.0, per_image: 0.0 },
+];
+
+fn price(model: &str) -> Option<&'static ModelPrice> {
+    PRICES.iter().find(|price| price.model == model)
+}
+
+// One billed provider call.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UsageEntry {
+    pub timestamp: u64,
+    // Entries from before tenants existed belong to the default tenant
+    #[serde(default = "default_tenant")]
+    pub tenant: String,
+    pub key_id: String,
+    pub session_id: Uuid,
+    pub route: Route,
+    pub provider: String,
+    pub model: String,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub images: u64,
+    pub cost_usd: f64,
+}
+
+fn default_tenant() -> String {
+    DEFAULT_TENANT.to_string()
+}
+
+impl UsageEntry {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        key: &AuthenticatedKey,
+        session_id: Uuid,
+        route: Route,
+        provider: &str,
+        model: &str,
+        usage: Option<&TokenUsage>,
+        images: u64,
+    ) -> Self {
+        let (prompt_tokens, completion_tokens) = usage.map(|usage| (usage.prompt_tokens, usage.completion_tokens)).unwrap_or((0, 0));
+        let cost_usd = match price(model) {
+            Some(price) => {
+                prompt_tokens as f64 * price.prompt_per_million / 1_000_000.0
+                    + completion_tokens as f64 * price.completion_per_million / 1_000_000.0
+                    + images as f64 * price.per_image
+            }
+            None => {
+                info!("No price for model {}; recording zero cost", model);
+                0.0
+            }
+        };
+        UsageEntry {
+            timestamp: now(),
+            tenant: key.tenant.clone(),
+            key_id: key.id.clone(),
+            session_id,
+            route,
+            provider: provider.to_string(),
+            model: model.to_string(),
+            prompt_tokens,
+            completion_tokens,
+            images,
+            cost_usd,
+        }
+    }
+
+    // The interaction's own call, then one per provider call made by its tools or on its
+    // behalf, each priced by its model.
+    fn from_interaction(key: &AuthenticatedKey, interaction: &Interaction) -> Vec<Self> {
+        let images = match interaction.route {
+            Route::Generate => interaction.image_urls.len() as u64,
+            Route::Chat | Route::Vision => 0,
+        };
+        let mut entries = vec![UsageEntry::new(
+            key,
+            interaction.session_id,
+            interaction.route,
+            &interaction.provider,
+            &interaction.model,
+            interaction.usage.as_ref(),
+            images,
+        )];
+        let calls = interaction.tool_calls.iter().flat_map(|call| call.usage.iter().map(move |usage| (call.route, usage)));
+        let auxiliary = interaction.auxiliary.iter().map(|usage| (interaction.route, usage));
+        for (route, usage) in calls.chain(auxiliary) {
+            entries.push(UsageEntry::new(key, interaction.session_id, route, &usage.provider, &usage.model, usage.tokens.as_ref(), usage.images));
+        }
+        entries
+    }
+
+    // UTC day of the call, e.g. 2024-06-30
+    pub fn day(&self) -> String {
+        DateTime::<Utc>::from_timestamp(self.timestamp as i64, 0)
+            .map(|time| time.format("%Y-%m-%d").to_string())
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GroupBy {
+    Tenant,
+    Key,
+    Session,
+    Day,
+}
+
+impl GroupBy {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.trim() {
+            "tenant" => Some(GroupBy::Tenant),
+            "key" => Some(GroupBy::Key),
+            "session" => Some(GroupBy::Session),
+            "day" => Some(GroupBy::Day),
+            _ => None,
+        }
+    }
+}
+
+// Which entries to aggregate; days are inclusive `YYYY-MM-DD` bounds.
+#[derive(Default)]
+pub struct UsageFilter {
+    pub tenant: Option<String>,
+    pub key_id: Option<String>,
+    pub session_id: Option<Uuid>,
+    pub from: Option<String>,
+    pub to: Option<String>,
+}
+
+impl UsageFilter {
+    fn matches(&self, rollup: &RollupKey) -> bool {
+        self.tenant.as_ref().is_none_or(|tenant| *tenant == rollup.tenant)
+            && self.key_id.as_ref().is_none_or(|key_id| *key_id == rollup.key_id)
+            && self.session_id.is_none_or(|session_id| session_id == rollup.session_id)
+            && self.from.as_ref().is_none_or(|from| rollup.day >= *from)
+            && self.to.as_ref().is_none_or(|to| rollup.day <= *to)
+    }
+}
+
+// What a day's usage is summed by: every dimension reports can filter or group on.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct RollupKey {
+    day: String,
+    tenant: String,
+    key_id: String,
+    session_id: Uuid,
+}
+
+impl RollupKey {
+    fn of(entry: &UsageEntry) -> Self {
+        RollupKey { day: entry.day(), tenant: entry.tenant.clone(), key_id: entry.key_id.clone(), session_id: entry.session_id }
+    }
+}
+
+#[derive(Serialize, Default, Debug, Clone)]
+pub struct UsageTotals {
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+    pub images: u64,
+    pub cost_usd: f64,
+}
+
+impl UsageTotals {
+    fn add(&mut self, entry: &UsageEntry) {
+        self.requests += 1;
+        self.prompt_tokens += entry.prompt_tokens;
+        self.completion_tokens += entry.completion_tokens;
+        self.total_tokens += entry.prompt_tokens + entry.completion_tokens;
+        self.images += entry.images;
+        self.cost_usd += entry.cost_usd;
+    }
+
+    fn merge(&mut self, other: &UsageTotals) {
+        self.requests += other.requests;
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.total_tokens += other.total_tokens;
+        self.images += other.images;
+        self.cost_usd += other.cost_usd;
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct UsageGroup {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<Uuid>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub day: Option<String>,
+    #[serde(flatten)]
+    pub totals: UsageTotals,
+}
+
+// Tenant, key, session and day of a group; dimensions not grouped by are None.
+type GroupKey = (Option<String>, Option<String>, Option<Uuid>, Option<String>);
+
+// Append-only ledger of provider usage (usage_ledger.jsonl in the data directory). Reports
+// read daily rollups kept in memory rather than the entries, and entries are appended by a
+// thread of its own so requests never wait on the disk.
+pub struct UsageLedger {
+    rollups: RwLock<BTreeMap<RollupKey, UsageTotals>>,
+    sender: Mutex<Option<mpsc::Sender<UsageEntry>>>,
+    writer: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl UsageLedger {
+    pub fn open(config: &Config) -> io::Result<Self> {
+        let path = config.data_file("usage_ledger.jsonl");
+        let mut rollups: BTreeMap<RollupKey, UsageTotals> = BTreeMap::new();
+        let mut count = 0;
+        if path.exists() {
+            let reader = BufReader::new(fs::File::open(&path)?);
+            for (number, line) in reader.lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match serde_json::from_str::<UsageEntry>(&line) {
+                    Ok(entry) => {
+                        rollups.entry(RollupKey::of(&entry)).or_default().add(&entry);
+                        count += 1;
+                    }
+                    Err(e) => error!("Skipping malformed usage entry at {}:{}: {}", path.display(), number + 1, e),
+                }
+            }
+        }
+        info!("Loaded {} usage entries ({} daily rollups) from {}", count, rollups.len(), path.display());
+
+        let (sender, receiver) = mpsc::channel::<UsageEntry>();
+        let writer = std::thread::spawn(move || {
+            while let Ok(entry) = receiver.recv() {
+                // Whatever else arrived meanwhile goes out with it
+                let entries: Vec<UsageEntry> = std::iter::once(entry).chain(receiver.try_iter()).collect();
+                if let Err(e) = append(&path, &entries) {
+                    error!("Failed to write {} usage entries to {}: {}", entries.len(), path.display(), e);
+                }
+            }
+        });
+        Ok(UsageLedger { rollups: RwLock::new(rollups), sender: Mutex::new(Some(sender)), writer: Mutex::new(Some(writer)) })
+    }
+
+    pub fn record(&self, key: &AuthenticatedKey, interaction: &Interaction) {
+        self.push(key, UsageEntry::from_interaction(key, interaction));
+    }
+
+    // Record a provider call made outside an interaction, e.g. embedding a document.
+    pub fn record_call(&self, key: &AuthenticatedKey, session_id: Uuid, route: Route, usage: &ProviderUsage) {
+        let entry = UsageEntry::new(key, session_id, route, &usage.provider, &usage.model, usage.tokens.as_ref(), usage.images);
+        self.push(key, vec![entry]);
+    }
+
+    fn push(&self, key: &AuthenticatedKey, entries: Vec<UsageEntry>) {
+        {
+            let mut rollups = self.rollups.write().unwrap();
+            for entry in &entries {
+                rollups.entry(RollupKey::of(entry)).or_default().add(entry);
+            }
+        }
+        let sender = self.sender.lock().unwrap();
+        for entry in entries {
+            if sender.as_ref().is_none_or(|sender| sender.send(entry).is_err()) {
+                error!("The usage writer is gone; an entry for key {} is lost", key.id);
+            }
+        }
+    }
+
+    // Write out what is still queued and stop the writer; called at shutdown.
+    pub fn close(&self) {
+        drop(self.sender.lock().unwrap().take());
+        if let Some(writer) = self.writer.lock().unwrap().take() {
+            if writer.join().is_err() {
+                error!("The usage writer panicked");
+            }
+        }
+    }
+
+    // Aggregate the matching entries by the requested dimensions, in tenant/key/session/day order.
+    pub fn aggregate(&self, filter: &UsageFilter, group_by: &[GroupBy]) -> (Vec<UsageGroup>, UsageTotals) {
+        let mut groups: BTreeMap<GroupKey, UsageTotals> = BTreeMap::new();
+        let mut totals = UsageTotals::default();
+
+        let rollups = self.rollups.read().unwrap();
+        // Rollups are ordered by day first, so only the requested days are visited
+        let first = RollupKey { day: filter.from.clone().unwrap_or_default(), tenant: String::new(), key_id: String::new(), session_id: Uuid::nil() };
+        for (rollup, day_totals) in rollups.range(first..) {
+            if filter.to.as_ref().is_some_and(|to| rollup.day > *to) {
+                break;
+            }
+            if !filter.matches(rollup) {
+                continue;
+            }
+            totals.merge(day_totals);
+            let group = (
+                group_by.contains(&GroupBy::Tenant).then(|| rollup.tenant.clone()),
+                group_by.contains(&GroupBy::Key).then(|| rollup.key_id.clone()),
+                group_by.contains(&GroupBy::Session).then_some(rollup.session_id),
+                group_by.contains(&GroupBy::Day).then(|| rollup.day.clone()),
+            );
+            groups.entry(group).or_default().merge(day_totals);
+        }
+
+        let groups = groups
+            .into_iter()
+            .map(|((tenant, key_id, session_id, day), totals)| UsageGroup { tenant, key_id, session_id, day, totals })
+            .collect();
+        (groups, totals)
+    }
+}
+
+fn append(path: &Path, entries: &[UsageEntry]) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut contents = String::new();
+    for entry in entries {
+        contents.push_str(&serde_json::to_string(entry)?);
+        contents.push('\n');
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(contents.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2024-06-30 00:00:00 UTC
+    const JUNE_30: u64 = 1_719_705_600;
+    const DAY: u64 = 86_400;
+
+    fn entry(tenant: &str, key_id: &str, session_id: Uuid, timestamp: u64, prompt_tokens: u64) -> UsageEntry {
+        UsageEntry {
+            timestamp,
+            tenant: tenant.to_string(),
+            key_id: key_id.to_string(),
+            session_id,
+            route: Route::Chat,
+            provider: "groq".to_string(),
+            model: "llama3-8b-8192".to_string(),
+            prompt_tokens,
+            completion_tokens: 1,
+            images: 0,
+            cost_usd: 0.5,
+        }
+    }
+
+    #[test]
+    fn parses_group_names() {
+        assert_eq!(GroupBy::parse("tenant"), Some(GroupBy::Tenant));
+        assert_eq!(GroupBy::parse(" day "), Some(GroupBy::Day));
+        assert_eq!(GroupBy::parse("Key"), None);
+        assert_eq!(GroupBy::parse("model"), None);
+    }
+
+    #[test]
+    fn filters_by_every_field_with_inclusive_days() {
+        let session = Uuid::new_v4();
+        let rollup = RollupKey::of(&entry("acme", "k1", session, JUNE_30 + 3600, 10));
+        assert_eq!(rollup.day, "2024-06-30");
+        assert!(UsageFilter::default().matches(&rollup));
+
+        let filter = UsageFilter {
+            tenant: Some("acme".to_string()),
+            key_id: Some("k1".to_string()),
+            session_id: Some(session),
+            from: Some("2024-06-30".to_string()),
+            to: Some("2024-06-30".to_string()),
+        };
+        assert!(filter.matches(&rollup));
+        assert!(!UsageFilter { tenant: Some("default".to_string()), ..Default::default() }.matches(&rollup));
+        assert!(!UsageFilter { key_id: Some("k2".to_string()), ..Default::default() }.matches(&rollup));
+        assert!(!UsageFilter { session_id: Some(Uuid::new_v4()), ..Default::default() }.matches(&rollup));
+        assert!(!UsageFilter { from: Some("2024-07-01".to_string()), ..Default::default() }.matches(&rollup));
+        assert!(!UsageFilter { to: Some("2024-06-29".to_string()), ..Default::default() }.matches(&rollup));
+    }
+
+    #[test]
+    fn aggregates_by_the_requested_dimensions() {
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut rollups: BTreeMap<RollupKey, UsageTotals> = BTreeMap::new();
+        for entry in [
+            entry("acme", "k1", first, JUNE_30, 10),
+            entry("acme", "k2", second, JUNE_30 + DAY, 20),
+            entry("acme", "k1", first, JUNE_30 + DAY, 30),
+            entry("default", "k3", second, JUNE_30, 40),
+            entry("acme", "k1", first, JUNE_30 + 2 * DAY, 50),
+        ] {
+            rollups.entry(RollupKey::of(&entry)).or_default().add(&entry);
+        }
+        let ledger = UsageLedger { rollups: RwLock::new(rollups), sender: Mutex::new(None), writer: Mutex::new(None) };
+
+        let (groups, totals) = ledger.aggregate(&UsageFilter::default(), &[]);
+        assert_eq!(groups.len(), 1);
+        assert_eq!((totals.requests, totals.prompt_tokens, totals.total_tokens), (5, 150, 155));
+        assert_eq!(totals.cost_usd, 2.5);
+
+        let acme = UsageFilter { tenant: Some("acme".to_string()), to: Some("2024-07-01".to_string()), ..Default::default() };
+        let (groups, totals) = ledger.aggregate(&acme, &[GroupBy::Day, GroupBy::Key]);
+        let keys: Vec<(&str, &str, u64)> = groups
+            .iter()
+            .map(|group| (group.key_id.as_deref().unwrap(), group.day.as_deref().unwrap(), group.totals.prompt_tokens))
+            .collect();
+        assert_eq!(keys, vec![("k1", "2024-06-30", 10), ("k1", "2024-07-01", 30), ("k2", "2024-07-01", 20)]);
+        assert!(groups.iter().all(|group| group.tenant.is_none() && group.session_id.is_none()));
+        assert_eq!(totals.requests, 3);
+    }
+
+    #[test]
+    fn entries_written_in_the_background_are_read_back() {
+        let mut config = Config::default();
+        config.storage.data_dir = std::env::temp_dir().join(format!("usage_ledger_{}", Uuid::new_v4()));
+        let key = AuthenticatedKey {
+            id: "k1".to_string(),
+            name: "test".to_string(),
+            scopes: Vec::new(),
+            limits: Default::default(),
+            session: None,
+            persona: None,
+            tenant: "acme".to_string(),
+            subject: None,
+        };
+        let usage = ProviderUsage { provider: "openai".to_string(), model: "dall-e-3".to_string(), tokens: None, images: 2 };
+
+        let ledger = UsageLedger::open(&config).unwrap();
+        ledger.record_call(&key, Uuid::nil(), Route::Generate, &usage);
+        ledger.record_call(&key, Uuid::nil(), Route::Generate, &usage);
+        ledger.close();
+
+        let (_, totals) = UsageLedger::open(&config).unwrap().aggregate(&UsageFilter::default(), &[]);
+        assert_eq!((totals.requests, totals.images), (2, 4));
+        assert!((totals.cost_usd - 0.16).abs() < 1e-9);
+        fs::remove_dir_all(&config.storage.data_dir).unwrap();
+    }
+}
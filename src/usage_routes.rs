@@ -0,0 +1,82 @@
+// usage_routes.rs
+use crate::api_keys::{AuthenticatedKey, Scope};
+use crate::usage_ledger::{GroupBy, UsageFilter, UsageLedger};
+
+use actix_web::{web, HttpResponse, Responder};
+use chrono::NaiveDate;
+use serde::Deserialize;
+use serde_json::json;
+use uuid::Uuid;
+
+#[derive(Deserialize)]
+struct UsageQuery {
+    // Comma separated: tenant, key, session, day
+    group_by: Option<String>,
+    tenant: Option<String>,
+    key_id: Option<String>,
+    session_id: Option<Uuid>,
+    from: Option<String>,
+    to: Option<String>,
+}
+
+// Set Usage Routes (nested under /api)
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route("/usage", web::get().to(usage_report));
+}
+
+fn bad_request(message: String) -> HttpResponse {
+    HttpResponse::BadRequest().json(json!({ "error": { "code": "invalid_parameter", "message": message } }))
+}
+
+// Aggregated usage and cost. Operators may query any tenant and tenant admins any key of
+// their tenant; everyone else only sees their own key.
+async fn usage_report(
+    query: web::Query<UsageQuery>,
+    ledger: web::Data<UsageLedger>,
+    auth: web::ReqData<AuthenticatedKey>,
+) -> impl Responder {
+    let query = query.into_inner();
+
+    let mut group_by = Vec::new();
+    for name in query.group_by.as_deref().unwrap_or("key").split(',').filter(|name| !name.trim().is_empty()) {
+        match GroupBy::parse(name) {
+            Some(dimension) if !group_by.contains(&dimension) => group_by.push(dimension),
+            Some(_) => {}
+            None => return bad_request(format!("cannot group by '{}'; use tenant, key, session or day", name.trim())),
+        }
+    }
+    for day in [&query.from, &query.to].into_iter().flatten() {
+        if NaiveDate::parse_from_str(day, "%Y-%m-%d").is_err() {
+            return bad_request(format!("'{}' is not a YYYY-MM-DD date", day));
+        }
+    }
+
+    let tenant = if auth.is_operator() {
+        query.tenant
+    } else {
+        match query.tenant {
+            Some(tenant) if tenant != auth.tenant => {
+                return HttpResponse::Forbidden().json(json!({
+                    "error": { "code": "insufficient_scope", "message": "Only operators can query other tenants' usage" }
+                }));
+            }
+            _ => Some(auth.tenant.clone()),
+        }
+    };
+    let key_id = if auth.has_scope(Scope::Admin) {
+        query.key_id
+    } else {
+        match query.key_id {
+            Some(key_id) if key_id != auth.id => {
+                return HttpResponse::Forbidden().json(json!({
+                    "error": { "code": "insufficient_scope", "message": "Only admins can query other keys' usage" }
+                }));
+            }
+            _ => Some(auth.id.clone()),
+        }
+    };
+
+    let filter = UsageFilter { tenant, key_id, session_id: query.session_id, from: query.from, to: query.to };
+    let (groups, totals) = ledger.aggregate(&filter, &group_by);
+    HttpResponse::Ok().json(json!({ "groups": groups, "totals": totals }))
+}
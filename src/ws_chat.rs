@@ -0,0 +1,384 @@
+// ws_chat.rs
+use crate::api_routes::{persona_error, session_error, user_id_error, InteractResponse};
+use crate::chat_error;
+use crate::generation_params::{GenerationDefaults, GenerationOverrides, GenerationParams};
+use crate::input_process::{process_user_input, route_for};
+use crate::api_keys::{AuthenticatedKey, Scope};
+use crate::interaction::{Interaction, Route, StreamEvent};
+use crate::memory;
+use crate::personas::{Persona, PersonaRegistry};
+use crate::prompt_template::{accept_language, PromptVariables};
+use crate::rate_limit::{QuotaTracker, RateLimiter};
+use crate::tenants::{Tenant, TenantRegistry};
+use crate::usage_ledger::UsageLedger;
+
+use actix_web::{rt, web, Error, HttpRequest, HttpResponse};
+use actix_ws::{Message, Session};
+use futures::StreamExt;
+use log::{error, info, warn};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::Instrument;
+use uuid::Uuid;
+
+// How often we ping the client, and how long we wait for any sign of life before dropping it.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(45);
+
+#[derive(Deserialize)]
+pub struct WsQuery {
+    session_id: Option<Uuid>,
+    persona: Option<String>,
+    user_name: Option<String>,
+    locale: Option<String>,
+    user_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    Message {
+        content: String,
+        #[serde(flatten)]
+        generation: GenerationOverrides,
+    },
+    Cancel,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerMessage<'a> {
+    Session { session_id: Uuid, persona: &'a str },
+    Token { content: &'a str },
+    ImageProgress { stage: &'a str },
+    ToolCall { name: &'a str },
+    Done(Box<InteractResponse>),
+    Cancelled,
+    Error { code: &'a str, message: String },
+}
+
+// A generation records its own usage, so a cancelled one is still counted. Closing its
+// events channel is how it is cancelled: streaming and tool rounds stop once nobody is
+// listening, but a call already under way (an image, a tool) runs to the end.
+type Outcome = Result<Interaction, (&'static str, String)>;
+
+struct Generation {
+    handle: JoinHandle<Outcome>,
+    events: mpsc::UnboundedReceiver<StreamEvent>,
+    started: Instant,
+    // Cancelled but still finishing; the session stays busy until it has saved its context
+    cancelled: bool,
+}
+
+enum Progress {
+    Event(StreamEvent),
+    Finished(Box<Result<Outcome, tokio::task::JoinError>>),
+}
+
+impl Generation {
+    // The next event, or the outcome once the task is done.
+    async fn progress(&mut self) -> Progress {
+        tokio::select! {
+            biased;
+            Some(event) = self.events.recv() => Progress::Event(event),
+            result = &mut self.handle => Progress::Finished(Box::new(result)),
+        }
+    }
+}
+
+struct Connection {
+    session_id: Uuid,
+    persona: Arc<Persona>,
+    prompt: PromptVariables,
+    tenant: Arc<Tenant>,
+    client: Client,
+    generation_defaults: GenerationDefaults,
+    quotas: web::Data<QuotaTracker>,
+    usage_ledger: web::Data<UsageLedger>,
+    // Every message is rate limited, not just the upgrade request
+    limiter: Arc<RateLimiter>,
+    auth: AuthenticatedKey,
+    user_id: Option<String>,
+}
+
+// Upgrade to a WebSocket bound to the requested (or a freshly created) session.
+#[allow(clippy::too_many_arguments)]
+pub async fn ws_route(
+    req: HttpRequest,
+    body: web::Payload,
+    query: web::Query<WsQuery>,
+    client: web::Data<Client>,
+    generation_defaults: web::Data<GenerationDefaults>,
+    personas: web::Data<PersonaRegistry>,
+    tenants: web::Data<Arc<TenantRegistry>>,
+    quotas: web::Data<QuotaTracker>,
+    usage_ledger: web::Data<UsageLedger>,
+    limiter: web::Data<Arc<RateLimiter>>,
+    auth: web::ReqData<AuthenticatedKey>,
+    tenant: web::ReqData<Arc<Tenant>>,
+) -> Result<HttpResponse, Error> {
+    if !auth.has_scope(Scope::Chat) {
+        return Ok(HttpResponse::Forbidden().json(serde_json::json!({
+            "error": { "code": "insufficient_scope", "message": "This API key is not allowed to use the chat route" }
+        })));
+    }
+
+    // A session named by the caller's token wins over the query string
+    let session_id = auth.session.or(query.session_id).unwrap_or_else(Uuid::new_v4);
+    if let Err(e) = tenants.check_session(session_id, &tenant, auth.subject.as_deref()) {
+        let (status, code) = session_error(&e);
+        return Ok(HttpResponse::build(status).json(serde_json::json!({
+            "error": { "code": code, "message": e.to_string() }
+        })));
+    }
+    let user_id = match memory::user_id(&auth, query.user_id.as_deref()) {
+        Ok(user_id) => user_id,
+        Err(e) => {
+            let (status, code) = user_id_error(&e);
+            return Ok(HttpResponse::build(status).json(serde_json::json!({
+                "error": { "code": code, "message": e.to_string() }
+            })));
+        }
+    };
+    let persona = match personas.for_session(session_id, query.persona.as_deref(), &auth, &tenant) {
+        Ok(persona) => persona,
+        Err(e) => {
+            let (status, code) = persona_error(&e);
+            return Ok(HttpResponse::build(status).json(serde_json::json!({
+                "error": { "code": code, "message": e.to_string() }
+            })));
+        }
+    };
+    let locale = query.locale.as_deref().or_else(|| {
+        accept_language(req.headers().get(actix_web::http::header::ACCEPT_LANGUAGE).and_then(|value| value.to_str().ok()))
+    });
+    let prompt = persona.prompt_variables(&tenant, Some(&auth)).with_user(query.user_name.as_deref(), locale);
+    if let Err(e) = tenants.claim_session(session_id, &tenant, auth.subject.as_deref()) {
+        let (status, code) = session_error(&e);
+        return Ok(HttpResponse::build(status).json(serde_json::json!({
+            "error": { "code": code, "message": e.to_string() }
+        })));
+    }
+    let (response, session, msg_stream) = actix_ws::handle(&req, body)?;
+    info!("WebSocket connected for session {} ({}) with key {} ({}) of tenant {}", session_id, persona.name, auth.id, auth.name, tenant.id);
+
+    let connection = Connection {
+        session_id,
+        persona,
+        prompt,
+        tenant: tenant.into_inner(),
+        client: client.get_ref().clone(),
+        generation_defaults: generation_defaults.get_ref().clone(),
+        quotas,
+        usage_ledger,
+        limiter: limiter.get_ref().clone(),
+        auth: auth.into_inner(),
+        user_id,
+    };
+    tracing::Span::current().record("session_id", tracing::field::display(session_id));
+    // The connection outlives the upgrade request, so it gets its own span under it
+    let span = tracing::info_span!("ws");
+    rt::spawn(run_connection(connection, session, msg_stream).instrument(span));
+
+    Ok(response)
+}
+
+async fn run_connection(connection: Connection, mut session: Session, mut msg_stream: actix_ws::MessageStream) {
+    let mut last_heartbeat = Instant::now();
+    let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+    let mut in_flight: Option<Generation> = None;
+
+    if !send(&mut session, &ServerMessage::Session { session_id: connection.session_id, persona: &connection.persona.name }).await {
+        return;
+    }
+
+    let close_reason = loop {
+        tokio::select! {
+            _ = heartbeat.tick() => {
+                if last_heartbeat.elapsed() > CLIENT_TIMEOUT {
+                    info!("WebSocket for session {} timed out", connection.session_id);
+                    break None;
+                }
+                if session.ping(b"").await.is_err() {
+                    break None;
+                }
+            }
+
+            progress = async { in_flight.as_mut().unwrap().progress().await }, if in_flight.is_some() => {
+                let result = match progress {
+                    Progress::Event(_) if in_flight.as_ref().is_some_and(|generation| generation.cancelled) => continue,
+                    Progress::Event(event) => {
+                        if !forward_event(&mut session, &event).await {
+                            break None;
+                        }
+                        continue;
+                    }
+                    Progress::Finished(result) => *result,
+                };
+                let Generation { mut events, started, cancelled, .. } = in_flight.take().unwrap();
+                if cancelled {
+                    info!("Cancelled generation for session {} finished", connection.session_id);
+                    continue;
+                }
+                // Flush anything the pipeline emitted right before finishing
+                while let Ok(event) = events.try_recv() {
+                    forward_event(&mut session, &event).await;
+                }
+                let message = match result {
+                    Ok(Ok(interaction)) => ServerMessage::Done(Box::new(InteractResponse::new(interaction, &connection.persona.name, started))),
+                    Ok(Err((code, message))) => ServerMessage::Error { code, message },
+                    Err(e) => {
+                        error!("Generation task failed: {}", e);
+                        ServerMessage::Error { code: "internal_error", message: "Generation task failed".to_string() }
+                    }
+                };
+                if !send(&mut session, &message).await {
+                    break None;
+                }
+            }
+
+            msg = msg_stream.next() => {
+                match msg {
+                    Some(Ok(Message::Text(text))) => {
+                        last_heartbeat = Instant::now();
+                        let reply = handle_client_message(&connection, &text, &mut in_flight);
+                        if let Some(reply) = reply {
+                            if !send(&mut session, &reply).await {
+                                break None;
+                            }
+                        }
+                    }
+                    Some(Ok(Message::Ping(bytes))) => {
+                        last_heartbeat = Instant::now();
+                        if session.pong(&bytes).await.is_err() {
+                            break None;
+                        }
+                    }
+                    Some(Ok(Message::Pong(_))) => {
+                        last_heartbeat = Instant::now();
+                    }
+                    Some(Ok(Message::Close(reason))) => break reason,
+                    Some(Ok(_)) => {}
+                    Some(Err(e)) => {
+                        warn!("WebSocket protocol error for session {}: {}", connection.session_id, e);
+                        break None;
+                    }
+                    None => break None,
+                }
+            }
+        }
+    };
+
+    // Dropping an unfinished generation cancels it; it still records its usage
+    drop(in_flight);
+    info!("WebSocket closed for session {}", connection.session_id);
+    let _ = session.close(close_reason).await;
+}
+
+// Start or cancel a generation; returns an immediate reply for the client, if any.
+fn handle_client_message<'a>(
+    connection: &Connection,
+    text: &str,
+    in_flight: &mut Option<Generation>,
+) -> Option<ServerMessage<'a>> {
+    let client_message = match serde_json::from_str::<ClientMessage>(text) {
+        Ok(client_message) => client_message,
+        Err(e) => return Some(ServerMessage::Error { code: "invalid_message", message: e.to_string() }),
+    };
+
+    match client_message {
+        ClientMessage::Message { content, generation } => {
+            if let Some(generation) = in_flight {
+                let message = if generation.cancelled {
+                    "The cancelled generation is still finishing; retry shortly"
+                } else {
+                    "Cancel the current generation or wait for it to finish"
+                };
+                return Some(ServerMessage::Error { code: "generation_in_progress", message: message.to_string() });
+            }
+            if let Err(limited) = connection.limiter.check(&connection.auth, &connection.session_id.to_string()) {
+                info!("Rate limited key {} ({} bucket) on its WebSocket", connection.auth.id, limited.scope.as_str());
+                return Some(ServerMessage::Error {
+                    code: "rate_limited",
+                    message: format!("Too many messages; retry in {} seconds", limited.retry_after.as_secs().max(1)),
+                });
+            }
+            let routing = route_for(&content, &connection.persona, &connection.tenant);
+            let route = routing.route;
+            if !connection.auth.has_scope(Scope::for_route(route)) {
+                return Some(ServerMessage::Error {
+                    code: "insufficient_scope",
+                    message: format!("This API key is not allowed to use the {} route", route.as_str()),
+                });
+            }
+            if let Err(exceeded) = connection.quotas.check(&connection.auth, &connection.tenant, route) {
+                return Some(ServerMessage::Error { code: "quota_exceeded", message: exceeded.message() });
+            }
+            let params = match GenerationParams::resolve(&generation, &connection.persona.generation_defaults(&connection.generation_defaults)) {
+                Ok(params) => params,
+                Err(e) => return Some(ServerMessage::Error { code: "invalid_parameter", message: e.to_string() }),
+            };
+
+            let session_id = connection.session_id;
+            let client = connection.client.clone();
+            let tenant = connection.tenant.clone();
+            let persona = connection.persona.clone();
+            let mut prompt = connection.prompt.clone();
+            // The chat model may generate images through tools, which needs image quota left
+            if connection.quotas.check(&connection.auth, &connection.tenant, Route::Generate).is_err() {
+                prompt = prompt.without(Route::Generate);
+            }
+            let user_id = connection.user_id.clone();
+            let (quotas, usage_ledger, auth) = (connection.quotas.clone(), connection.usage_ledger.clone(), connection.auth.clone());
+            let (events, events_rx) = mpsc::unbounded_channel();
+            let handle = rt::spawn(async move {
+                let ip_addr = IpAddr::V4("95.94.61.253".parse().unwrap());
+                let interaction = process_user_input(content.clone(), routing, session_id, &client, &tenant, ip_addr, &persona, &prompt, &params, user_id.as_deref(), Some(&events))
+                    .await
+                    .map_err(|e| (chat_error::classify(e.as_ref()).code, e.to_string()))?;
+                quotas.record(&auth, &interaction);
+                usage_ledger.record(&auth, &interaction);
+                memory::remember_later(&auth, user_id.as_deref(), &content, &interaction);
+                Ok(interaction)
+            }.instrument(tracing::Span::current()));
+            *in_flight = Some(Generation { handle, events: events_rx, started: Instant::now(), cancelled: false });
+            None
+        }
+        ClientMessage::Cancel => match in_flight.as_mut().filter(|generation| !generation.cancelled) {
+            Some(generation) => {
+                // The task is kept until it finishes, so its side effects never overlap the next message's
+                generation.events.close();
+                generation.cancelled = true;
+                info!("Cancelled in-flight generation for session {}", connection.session_id);
+                Some(ServerMessage::Cancelled)
+            }
+            None => Some(ServerMessage::Error {
+                code: "nothing_to_cancel",
+                message: "No generation is in progress".to_string(),
+            }),
+        },
+    }
+}
+
+async fn forward_event(session: &mut Session, event: &StreamEvent) -> bool {
+    match event {
+        StreamEvent::Token(content) => send(session, &ServerMessage::Token { content }).await,
+        StreamEvent::ImageProgress(stage) => send(session, &ServerMessage::ImageProgress { stage }).await,
+        StreamEvent::ToolCall(name) => send(session, &ServerMessage::ToolCall { name }).await,
+    }
+}
+
+async fn send(session: &mut Session, message: &ServerMessage<'_>) -> bool {
+    match serde_json::to_string(message) {
+        Ok(text) => session.text(text).await.is_ok(),
+        Err(e) => {
+            error!("Failed to serialize WebSocket message: {}", e);
+            true
+        }
+    }
+}